@@ -0,0 +1,226 @@
+//! Device selection from a spec like `cpu`, `cuda:N`, `metal:N` or `auto`.
+//!
+//! Which backends exist is decided at build time by the `cuda` and `metal` features. Asking for
+//! a backend that isn't compiled in is an error, not a silent fall back to the CPU.
+
+use anyhow::{Context as _, Result};
+use mistralrs::{Device, MemoryGpuConfig};
+use std::{fmt, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceSpec {
+    /// The first CUDA device, else the first Metal device, else the CPU, among the backends
+    /// compiled in.
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl FromStr for DeviceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim().to_lowercase();
+        let ordinal = |o: &str| {
+            o.parse::<usize>()
+                .with_context(|| format!("Invalid device ordinal `{o}` in device `{s}`."))
+        };
+        match s.split_once(':') {
+            None if s == "auto" => Ok(Self::Auto),
+            None if s == "cpu" => Ok(Self::Cpu),
+            None if s == "cuda" => Ok(Self::Cuda(0)),
+            None if s == "metal" => Ok(Self::Metal(0)),
+            Some(("cuda", o)) => Ok(Self::Cuda(ordinal(o)?)),
+            Some(("metal", o)) => Ok(Self::Metal(ordinal(o)?)),
+            _ => anyhow::bail!(
+                "Unknown device `{s}`. Expected `auto`, `cpu`, `cuda:N` or `metal:N`."
+            ),
+        }
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(o) => write!(f, "cuda:{o}"),
+            Self::Metal(o) => write!(f, "metal:{o}"),
+        }
+    }
+}
+
+/// A device picked by [`select`].
+pub struct SelectedDevice {
+    pub device: Device,
+    /// What was picked, never `Auto`.
+    pub spec: DeviceSpec,
+}
+
+/// Opens the device `spec` asks for and logs it. `cpu_threads` is only there to warn that it
+/// does nothing off the CPU; [`init_cpu_threads`] applies it.
+pub fn select(spec: DeviceSpec, cpu_threads: Option<usize>) -> Result<SelectedDevice> {
+    let (device, spec) = match spec {
+        DeviceSpec::Auto => auto(),
+        DeviceSpec::Cpu => (Device::Cpu, spec),
+        DeviceSpec::Cuda(ordinal) => {
+            anyhow::ensure!(
+                cfg!(feature = "cuda"),
+                "Device `{spec}` was requested, but this binary was built without CUDA support. \
+                 Rebuild it with `--features cuda`."
+            );
+            let device = Device::new_cuda(ordinal)
+                .with_context(|| format!("Could not open device `{spec}`."))?;
+            (device, spec)
+        }
+        DeviceSpec::Metal(ordinal) => {
+            anyhow::ensure!(
+                cfg!(feature = "metal"),
+                "Device `{spec}` was requested, but this binary was built without Metal support. \
+                 Rebuild it with `--features metal`."
+            );
+            let device = Device::new_metal(ordinal)
+                .with_context(|| format!("Could not open device `{spec}`."))?;
+            (device, spec)
+        }
+    };
+
+    if spec == DeviceSpec::Cpu {
+        let threads = rayon_core::current_num_threads();
+        match memory_info() {
+            Some((available, total)) => println!(
+                "Using device `cpu` with {threads} threads, {:.1} GiB of {:.1} GiB memory available.",
+                gib(available),
+                gib(total)
+            ),
+            None => println!("Using device `cpu` with {threads} threads."),
+        }
+    } else {
+        if cpu_threads.is_some() {
+            eprintln!("Warning: the CPU thread count only applies to the `cpu` device, ignoring it on `{spec}`.");
+        }
+        println!("Using device `{spec}`.");
+    }
+    Ok(SelectedDevice { device, spec })
+}
+
+fn auto() -> (Device, DeviceSpec) {
+    if cfg!(feature = "cuda") {
+        if let Ok(device) = Device::new_cuda(0) {
+            return (device, DeviceSpec::Cuda(0));
+        }
+    }
+    if cfg!(feature = "metal") {
+        if let Ok(device) = Device::new_metal(0) {
+            return (device, DeviceSpec::Metal(0));
+        }
+    }
+    (Device::Cpu, DeviceSpec::Cpu)
+}
+
+impl SelectedDevice {
+    /// Whether PagedAttention can run here. The kernels are CUDA only, so on any other device
+    /// this warns and says no, and the caller should run without it.
+    pub fn supports_paged_attn(&self, gpu_mem: MemoryGpuConfig) -> bool {
+        if let DeviceSpec::Cuda(_) = self.spec {
+            return true;
+        }
+        let asked = match gpu_mem {
+            MemoryGpuConfig::Utilization(utilization) => {
+                format!("{:.0}% of GPU memory", utilization * 100.0)
+            }
+            MemoryGpuConfig::Amount(mb) => format!("{mb} MB of GPU memory"),
+        };
+        eprintln!(
+            "Warning: PagedAttention ({asked}) needs a CUDA device, but `{}` was selected. \
+             Running without PagedAttention.",
+            self.spec
+        );
+        false
+    }
+}
+
+/// Builds the global rayon pool the CPU kernels run on with `threads` threads. `None` leaves it
+/// to rayon: `RAYON_NUM_THREADS`, else one per core. The pool can only be built once, so call
+/// this from `main` before the async runtime starts, ahead of anything that uses rayon.
+pub fn init_cpu_threads(threads: Option<usize>) -> Result<()> {
+    let Some(threads) = threads else {
+        return Ok(());
+    };
+    anyhow::ensure!(threads > 0, "The CPU thread count must be at least 1.");
+    rayon_core::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("cpu-kernel-{i}"))
+        .build_global()
+        .context("Could not start the CPU threads.")
+}
+
+/// Available and total system memory in bytes, from `/proc/meminfo`. Linux only.
+fn memory_info() -> Option<(u64, u64)> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        meminfo
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .and_then(|value| value.trim().strip_suffix("kB"))
+            .and_then(|kb| kb.trim().parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some((field("MemAvailable")?, field("MemTotal")?))
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0 * 1024.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_specs() {
+        for (s, spec) in [
+            ("auto", DeviceSpec::Auto),
+            ("cpu", DeviceSpec::Cpu),
+            (" CPU ", DeviceSpec::Cpu),
+            ("cuda", DeviceSpec::Cuda(0)),
+            ("cuda:3", DeviceSpec::Cuda(3)),
+            ("Metal:1", DeviceSpec::Metal(1)),
+            ("metal", DeviceSpec::Metal(0)),
+        ] {
+            assert_eq!(s.parse::<DeviceSpec>().unwrap(), spec, "{s}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_devices_and_bad_ordinals() {
+        for (s, error) in [
+            ("gpu", "Unknown device `gpu`"),
+            ("cpu:0", "Unknown device `cpu:0`"),
+            ("auto:1", "Unknown device"),
+            ("", "Unknown device"),
+            ("cuda:", "Invalid device ordinal ``"),
+            ("cuda:-1", "Invalid device ordinal `-1`"),
+            (
+                "metal:one",
+                "Invalid device ordinal `one` in device `metal:one`",
+            ),
+        ] {
+            let e = s.parse::<DeviceSpec>().unwrap_err();
+            assert!(e.to_string().contains(error), "{s}: {e}");
+        }
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for spec in [
+            DeviceSpec::Auto,
+            DeviceSpec::Cpu,
+            DeviceSpec::Cuda(2),
+            DeviceSpec::Metal(0),
+        ] {
+            assert_eq!(spec.to_string().parse::<DeviceSpec>().unwrap(), spec);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+//! Helpers shared by the `isq` and `gguf_locally` binaries.
+
+pub mod device;
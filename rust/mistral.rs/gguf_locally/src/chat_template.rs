@@ -0,0 +1,349 @@
+//! Finds the chat template for a local GGUF model.
+//!
+//! In order: the `tokenizer.chat_template` GGUF metadata, the `chat_template` of a
+//! `tokenizer_config.json` next to the GGUF file, and a built-in template from
+//! `chat_templates/`. mistral.rs takes a chat template as a JSON file with a `chat_template`
+//! field, so a template found anywhere but in a usable `tokenizer_config.json` is written to a
+//! temporary file, along with the BOS and EOS tokens named in the GGUF metadata. The file is
+//! removed when the [`ChatTemplate`] is dropped.
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::OpenOptions,
+    io::{ErrorKind, Write as _},
+    path::{Path, PathBuf},
+};
+
+use crate::gguf::{self, MetadataValue};
+
+const GGUF_KEY: &str = "tokenizer.chat_template";
+const GGUF_TOKENS: &str = "tokenizer.ggml.tokens";
+const GGUF_BOS: &str = "tokenizer.ggml.bos_token_id";
+const GGUF_EOS: &str = "tokenizer.ggml.eos_token_id";
+const TOKENIZER_CONFIG: &str = "tokenizer_config.json";
+
+/// Templates in `chat_templates/`, built into the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BuiltinTemplate {
+    Chatml,
+    Default,
+    Llama2,
+    Mistral,
+}
+
+impl BuiltinTemplate {
+    fn json(self) -> &'static str {
+        match self {
+            Self::Chatml => include_str!("../chat_templates/chatml.json"),
+            Self::Default => include_str!("../chat_templates/default.json"),
+            Self::Llama2 => include_str!("../chat_templates/llama2.json"),
+            Self::Mistral => include_str!("../chat_templates/mistral.json"),
+        }
+    }
+}
+
+impl fmt::Display for BuiltinTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.to_possible_value().expect("no variant is skipped");
+        write!(f, "{}", name.get_name())
+    }
+}
+
+/// Where the chat template came from.
+pub enum TemplateSource {
+    Gguf(PathBuf),
+    TokenizerConfig(PathBuf),
+    /// No template was found elsewhere.
+    Builtin(BuiltinTemplate),
+}
+
+impl fmt::Display for TemplateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Gguf(path) => write!(f, "`{GGUF_KEY}` in the metadata of `{}`", path.display()),
+            Self::TokenizerConfig(path) => write!(f, "`chat_template` in `{}`", path.display()),
+            Self::Builtin(template) => write!(
+                f,
+                "built-in template `{template}`, as neither the GGUF metadata nor a \
+                 {TOKENIZER_CONFIG} has one"
+            ),
+        }
+    }
+}
+
+pub struct ChatTemplate {
+    /// Chat template file to hand to the loader.
+    pub file: PathBuf,
+    pub source: TemplateSource,
+    /// Whether `file` was written by [`find`], and so is removed on drop.
+    written: bool,
+}
+
+impl Drop for ChatTemplate {
+    fn drop(&mut self) {
+        if self.written {
+            let _ = std::fs::remove_file(&self.file);
+        }
+    }
+}
+
+/// Looks for the chat template of `gguf_file`, falling back to `builtin`.
+pub fn find(gguf_file: &Path, model_dir: &Path, builtin: BuiltinTemplate) -> Result<ChatTemplate> {
+    let mut metadata = gguf::metadata(gguf_file, &[GGUF_KEY, GGUF_TOKENS, GGUF_BOS, GGUF_EOS])?;
+    let tokens = special_tokens(&metadata);
+
+    if let Some(MetadataValue::String(template)) = metadata.remove(GGUF_KEY) {
+        return written(
+            &template_json(Map::new(), template, &tokens),
+            TemplateSource::Gguf(gguf_file.to_path_buf()),
+        );
+    }
+
+    let config_path = model_dir.join(TOKENIZER_CONFIG);
+    if config_path.exists() {
+        let config = std::fs::read_to_string(&config_path)
+            .with_context(|| format!("Could not read `{}`.", config_path.display()))?;
+        let config = serde_json::from_str::<Map<String, Value>>(&config)
+            .with_context(|| format!("Invalid JSON in `{}`.", config_path.display()))?;
+        let source = TemplateSource::TokenizerConfig(config_path.clone());
+        match config.get("chat_template") {
+            // The file itself can be used, and brings its special tokens along.
+            Some(Value::String(_)) => {
+                return Ok(ChatTemplate {
+                    file: config_path,
+                    source,
+                    written: false,
+                })
+            }
+            // Several named templates, as `[{"name", "template"}]`.
+            Some(Value::Array(templates)) => {
+                let named = |name: &str| {
+                    templates
+                        .iter()
+                        .find(|t| t["name"] == name)
+                        .and_then(|t| t["template"].as_str())
+                };
+                let template = named("default")
+                    .or_else(|| templates.first()?["template"].as_str())
+                    .map(str::to_string);
+                if let Some(template) = template {
+                    return written(&template_json(config, template, &tokens), source);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let Ok(Value::Object(json)) = serde_json::from_str(builtin.json()) else {
+        unreachable!("built-in templates are JSON objects");
+    };
+    let template = json["chat_template"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    written(
+        &template_json(json, template, &tokens),
+        TemplateSource::Builtin(builtin),
+    )
+}
+
+/// `bos_token` and `eos_token`, as named by their ids in the GGUF metadata.
+fn special_tokens(metadata: &HashMap<String, MetadataValue>) -> Map<String, Value> {
+    let Some(MetadataValue::Strings(tokens)) = metadata.get(GGUF_TOKENS) else {
+        return Map::new();
+    };
+    [("bos_token", GGUF_BOS), ("eos_token", GGUF_EOS)]
+        .into_iter()
+        .filter_map(|(field, key)| {
+            let MetadataValue::Uint(id) = metadata.get(key)? else {
+                return None;
+            };
+            let token = tokens.get(usize::try_from(*id).ok()?)?;
+            Some((field.to_string(), Value::String(token.clone())))
+        })
+        .collect()
+}
+
+/// `json` with `template` as its chat template, and the special tokens it doesn't name itself.
+fn template_json(
+    mut json: Map<String, Value>,
+    template: String,
+    tokens: &Map<String, Value>,
+) -> String {
+    json.insert("chat_template".to_string(), Value::String(template));
+    for (field, token) in tokens {
+        json.entry(field.clone()).or_insert_with(|| token.clone());
+    }
+    Value::Object(json).to_string()
+}
+
+fn written(contents: &str, source: TemplateSource) -> Result<ChatTemplate> {
+    Ok(ChatTemplate {
+        file: write_file(contents)?,
+        source,
+        written: true,
+    })
+}
+
+/// Writes `contents` to a new file in the temporary directory. The file is created exclusively,
+/// so nothing already there, such as a link planted by another user, is written through.
+fn write_file(contents: &str) -> Result<PathBuf> {
+    let dir = std::env::temp_dir();
+    for n in 0.. {
+        let path = dir.join(format!(
+            "gguf_locally-{}-{n}-chat_template.json",
+            std::process::id()
+        ));
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Could not create chat template file `{}`.", path.display())
+                })
+            }
+        };
+        if let Err(e) = file.write_all(contents.as_bytes()) {
+            let _ = std::fs::remove_file(&path);
+            return Err(e).with_context(|| {
+                format!("Could not write the chat template to `{}`.", path.display())
+            });
+        }
+        return Ok(path);
+    }
+    unreachable!("the temporary directory can't hold every file name")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake::TempDir, gguf::fake::Gguf};
+
+    /// Writes a GGUF file with the given metadata into `dir` and returns its path.
+    fn write_gguf(dir: &TempDir, gguf: Gguf) -> PathBuf {
+        let path = dir.0.join("model.gguf");
+        std::fs::write(&path, gguf.bytes()).unwrap();
+        path
+    }
+
+    fn write_config(dir: &TempDir, config: Value) {
+        std::fs::write(dir.0.join(TOKENIZER_CONFIG), config.to_string()).unwrap();
+    }
+
+    fn with_tokens(gguf: Gguf) -> Gguf {
+        gguf.strings(GGUF_TOKENS, &["<unk>", "<s>", "</s>"])
+            .u32(GGUF_BOS, 1)
+            .u32(GGUF_EOS, 2)
+    }
+
+    fn contents(template: &ChatTemplate) -> Value {
+        serde_json::from_str(&std::fs::read_to_string(&template.file).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn gguf_metadata_comes_first_and_brings_its_special_tokens() {
+        let dir = TempDir::new("gguf");
+        let gguf = write_gguf(
+            &dir,
+            with_tokens(Gguf::default()).string(GGUF_KEY, "{{ gguf }}"),
+        );
+        write_config(&dir, serde_json::json!({ "chat_template": "{{ config }}" }));
+
+        let template = find(&gguf, &dir.0, BuiltinTemplate::Chatml).unwrap();
+        assert!(matches!(template.source, TemplateSource::Gguf(_)));
+        assert_eq!(
+            contents(&template),
+            serde_json::json!({
+                "chat_template": "{{ gguf }}",
+                "bos_token": "<s>",
+                "eos_token": "</s>",
+            })
+        );
+
+        let file = template.file.clone();
+        drop(template);
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn tokenizer_config_with_a_template_is_used_as_is() {
+        let dir = TempDir::new("config");
+        let gguf = write_gguf(&dir, with_tokens(Gguf::default()));
+        write_config(&dir, serde_json::json!({ "chat_template": "{{ config }}" }));
+
+        let template = find(&gguf, &dir.0, BuiltinTemplate::Chatml).unwrap();
+        assert!(matches!(
+            template.source,
+            TemplateSource::TokenizerConfig(_)
+        ));
+        assert_eq!(template.file, dir.0.join(TOKENIZER_CONFIG));
+        drop(template);
+        assert!(dir.0.join(TOKENIZER_CONFIG).exists());
+    }
+
+    #[test]
+    fn named_templates_pick_the_default_and_keep_the_config() {
+        let dir = TempDir::new("named");
+        let gguf = write_gguf(&dir, with_tokens(Gguf::default()));
+        write_config(
+            &dir,
+            serde_json::json!({
+                "bos_token": "<|begin|>",
+                "add_bos_token": true,
+                "chat_template": [
+                    { "name": "tool_use", "template": "{{ tools }}" },
+                    { "name": "default", "template": "{{ default }}" },
+                ],
+            }),
+        );
+
+        let template = find(&gguf, &dir.0, BuiltinTemplate::Chatml).unwrap();
+        assert!(matches!(
+            template.source,
+            TemplateSource::TokenizerConfig(_)
+        ));
+        assert_eq!(
+            contents(&template),
+            serde_json::json!({
+                "bos_token": "<|begin|>",
+                "add_bos_token": true,
+                "chat_template": "{{ default }}",
+                "eos_token": "</s>",
+            })
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_builtin_template() {
+        let dir = TempDir::new("builtin");
+        let gguf = write_gguf(&dir, with_tokens(Gguf::default()));
+        write_config(&dir, serde_json::json!({ "bos_token": "<s>" }));
+
+        let template = find(&gguf, &dir.0, BuiltinTemplate::Mistral).unwrap();
+        assert!(matches!(
+            template.source,
+            TemplateSource::Builtin(BuiltinTemplate::Mistral)
+        ));
+        let json = contents(&template);
+        let builtin: Value = serde_json::from_str(BuiltinTemplate::Mistral.json()).unwrap();
+        assert_eq!(json["chat_template"], builtin["chat_template"]);
+        assert_eq!(json["bos_token"], "<s>");
+        assert_eq!(json["eos_token"], "</s>");
+    }
+
+    #[test]
+    fn templates_get_files_of_their_own() {
+        let dir = TempDir::new("own");
+        let gguf = write_gguf(&dir, Gguf::default());
+
+        let a = find(&gguf, &dir.0, BuiltinTemplate::Chatml).unwrap();
+        let b = find(&gguf, &dir.0, BuiltinTemplate::Llama2).unwrap();
+        assert_ne!(a.file, b.file);
+        assert_eq!(contents(&a).as_object().unwrap().len(), 1);
+        assert_ne!(contents(&a), contents(&b));
+    }
+}
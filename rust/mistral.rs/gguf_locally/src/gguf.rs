@@ -0,0 +1,321 @@
+//! Just enough of the GGUF format to read metadata values without loading the tensors.
+//!
+//! A GGUF file starts with `GGUF`, a version, the tensor count and the metadata count, followed
+//! by the metadata as `(key, type, value)` entries, all little-endian.
+
+use anyhow::{Context as _, Result};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+const MAGIC: &[u8; 4] = b"GGUF";
+
+/// Value types, numbered as in the GGUF spec.
+const TYPE_U8: u32 = 0;
+const TYPE_U16: u32 = 2;
+const TYPE_U32: u32 = 4;
+const TYPE_STRING: u32 = 8;
+const TYPE_ARRAY: u32 = 9;
+const TYPE_U64: u32 = 10;
+
+/// The metadata values this crate has a use for. Other types are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataValue {
+    String(String),
+    /// Any unsigned integer type.
+    Uint(u64),
+    Strings(Vec<String>),
+}
+
+/// Reads the metadata values of `keys` from the GGUF file at `path`. Keys that aren't there, or
+/// whose values are of another type, are left out.
+pub fn metadata(path: &Path, keys: &[&str]) -> Result<HashMap<String, MetadataValue>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open GGUF file `{}`.", path.display()))?;
+    read_metadata(&mut BufReader::new(file), keys)
+        .with_context(|| format!("Could not read the metadata of `{}`.", path.display()))
+}
+
+fn read_metadata(reader: &mut impl Read, keys: &[&str]) -> Result<HashMap<String, MetadataValue>> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    anyhow::ensure!(&magic == MAGIC, "Not a GGUF file.");
+    let version = read_u32(reader)?;
+    anyhow::ensure!(
+        version >= 2,
+        "GGUF version {version} is not supported, only 2 and later."
+    );
+    let _tensor_count = read_u64(reader)?;
+    let metadata_count = read_u64(reader)?;
+
+    let mut values = HashMap::new();
+    for _ in 0..metadata_count {
+        if values.len() == keys.len() {
+            break;
+        }
+        let name = read_string(reader)?;
+        let tp = read_u32(reader)?;
+        if !keys.contains(&name.as_str()) {
+            skip_value(reader, tp)?;
+            continue;
+        }
+        if let Some(value) = read_value(reader, tp)? {
+            values.insert(name, value);
+        }
+    }
+    Ok(values)
+}
+
+/// Reads a value of a type in [`MetadataValue`], or skips it and returns `None`.
+fn read_value(reader: &mut impl Read, tp: u32) -> Result<Option<MetadataValue>> {
+    Ok(Some(match tp {
+        TYPE_STRING => MetadataValue::String(read_string(reader)?),
+        TYPE_U8 | TYPE_U16 | TYPE_U32 | TYPE_U64 => {
+            let size = scalar_size(tp).expect("integer types have a size");
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf[..size as usize])?;
+            MetadataValue::Uint(u64::from_le_bytes(buf))
+        }
+        TYPE_ARRAY => {
+            let item_tp = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            if item_tp != TYPE_STRING {
+                skip_items(reader, item_tp, len)?;
+                return Ok(None);
+            }
+            let strings = (0..len)
+                .map(|_| read_string(reader))
+                .collect::<Result<_>>()?;
+            MetadataValue::Strings(strings)
+        }
+        tp => {
+            skip_value(reader, tp)?;
+            return Ok(None);
+        }
+    }))
+}
+
+fn skip_value(reader: &mut impl Read, tp: u32) -> Result<()> {
+    match tp {
+        TYPE_STRING => {
+            let len = read_u64(reader)?;
+            skip(reader, len)
+        }
+        TYPE_ARRAY => {
+            let item_tp = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            skip_items(reader, item_tp, len)
+        }
+        tp => skip(
+            reader,
+            scalar_size(tp).with_context(|| format!("Unknown metadata value type {tp}."))?,
+        ),
+    }
+}
+
+fn skip_items(reader: &mut impl Read, item_tp: u32, len: u64) -> Result<()> {
+    match scalar_size(item_tp) {
+        Some(size) => skip(reader, size * len),
+        None => (0..len).try_for_each(|_| skip_value(reader, item_tp)),
+    }
+}
+
+/// Size in bytes of a fixed-size value type.
+fn scalar_size(tp: u32) -> Option<u64> {
+    match tp {
+        // u8, i8, bool
+        0 | 1 | 7 => Some(1),
+        // u16, i16
+        2 | 3 => Some(2),
+        // u32, i32, f32
+        4..=6 => Some(4),
+        // u64, i64, f64
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+fn skip(reader: &mut impl Read, len: u64) -> Result<()> {
+    let skipped = std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+    anyhow::ensure!(skipped == len, "Unexpected end of file.");
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    let len = read_u64(reader)?;
+    let mut buf = Vec::new();
+    reader.take(len).read_to_end(&mut buf)?;
+    anyhow::ensure!(buf.len() as u64 == len, "Unexpected end of file.");
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    //! Builds GGUF files in memory.
+
+    /// A GGUF v3 header and metadata, without tensors.
+    #[derive(Default)]
+    pub struct Gguf {
+        count: u64,
+        metadata: Vec<u8>,
+    }
+
+    impl Gguf {
+        fn entry(mut self, key: &str, tp: u32, value: &[u8]) -> Self {
+            self.count += 1;
+            put_string(&mut self.metadata, key);
+            self.metadata.extend(tp.to_le_bytes());
+            self.metadata.extend(value);
+            self
+        }
+
+        pub fn string(self, key: &str, value: &str) -> Self {
+            let mut bytes = Vec::new();
+            put_string(&mut bytes, value);
+            self.entry(key, super::TYPE_STRING, &bytes)
+        }
+
+        pub fn u32(self, key: &str, value: u32) -> Self {
+            self.entry(key, super::TYPE_U32, &value.to_le_bytes())
+        }
+
+        pub fn f32(self, key: &str, value: f32) -> Self {
+            self.entry(key, 6, &value.to_le_bytes())
+        }
+
+        pub fn strings(self, key: &str, values: &[&str]) -> Self {
+            let mut bytes = super::TYPE_STRING.to_le_bytes().to_vec();
+            bytes.extend((values.len() as u64).to_le_bytes());
+            for value in values {
+                put_string(&mut bytes, value);
+            }
+            self.entry(key, super::TYPE_ARRAY, &bytes)
+        }
+
+        pub fn i32s(self, key: &str, values: &[i32]) -> Self {
+            let mut bytes = 5u32.to_le_bytes().to_vec();
+            bytes.extend((values.len() as u64).to_le_bytes());
+            bytes.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            self.entry(key, super::TYPE_ARRAY, &bytes)
+        }
+
+        pub fn bytes(&self) -> Vec<u8> {
+            let mut bytes = super::MAGIC.to_vec();
+            bytes.extend(3u32.to_le_bytes());
+            bytes.extend(0u64.to_le_bytes());
+            bytes.extend(self.count.to_le_bytes());
+            bytes.extend(&self.metadata);
+            bytes
+        }
+    }
+
+    fn put_string(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend((s.len() as u64).to_le_bytes());
+        bytes.extend(s.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fake::Gguf, *};
+
+    fn read(bytes: &[u8], keys: &[&str]) -> Result<HashMap<String, MetadataValue>> {
+        read_metadata(&mut &bytes[..], keys)
+    }
+
+    #[test]
+    fn reads_the_requested_keys_and_skips_the_rest() {
+        let bytes = Gguf::default()
+            .string("general.name", "tiny")
+            .f32("rope.freq_base", 10000.0)
+            .i32s("tokenizer.ggml.token_type", &[1, 2, 3])
+            .strings("tokenizer.ggml.merges", &["a b", "c d"])
+            .strings("tokenizer.ggml.tokens", &["<unk>", "<s>", "</s>"])
+            .u32("tokenizer.ggml.bos_token_id", 1)
+            .string("tokenizer.chat_template", "{{ messages }}")
+            .bytes();
+        let values = read(
+            &bytes,
+            &[
+                "tokenizer.chat_template",
+                "tokenizer.ggml.tokens",
+                "tokenizer.ggml.bos_token_id",
+                "tokenizer.ggml.eos_token_id",
+            ],
+        )
+        .unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(
+            values["tokenizer.chat_template"],
+            MetadataValue::String("{{ messages }}".to_string())
+        );
+        assert_eq!(
+            values["tokenizer.ggml.tokens"],
+            MetadataValue::Strings(vec!["<unk>".into(), "<s>".into(), "</s>".into()])
+        );
+        assert_eq!(
+            values["tokenizer.ggml.bos_token_id"],
+            MetadataValue::Uint(1)
+        );
+    }
+
+    #[test]
+    fn leaves_out_values_of_other_types() {
+        let bytes = Gguf::default()
+            .f32("a", 1.0)
+            .i32s("b", &[1])
+            .string("c", "x")
+            .bytes();
+        let values = read(&bytes, &["a", "b", "c"]).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["c"], MetadataValue::String("x".to_string()));
+    }
+
+    #[test]
+    fn stops_reading_once_every_key_is_found() {
+        let mut bytes = Gguf::default().string("a", "x").bytes();
+        // Claim a second entry that isn't there; it is only read when a key is still missing.
+        bytes[16..24].copy_from_slice(&2u64.to_le_bytes());
+        assert_eq!(read(&bytes, &["a"]).unwrap().len(), 1);
+        assert!(read(&bytes, &["a", "b"]).is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let e = read(b"GGML\x03\0\0\0", &["a"]).unwrap_err();
+        assert!(e.to_string().contains("Not a GGUF file"), "{e}");
+
+        let mut bytes = Gguf::default().bytes();
+        bytes[4..8].copy_from_slice(&1u32.to_le_bytes());
+        let e = read(&bytes, &["a"]).unwrap_err();
+        assert!(e.to_string().contains("version 1 is not supported"), "{e}");
+
+        let bytes = Gguf::default().string("a", "long value").bytes();
+        let e = read(&bytes[..bytes.len() - 3], &["a"]).unwrap_err();
+        assert!(e.to_string().contains("Unexpected end of file"), "{e}");
+
+        let mut bytes = Gguf::default().u32("a", 1).bytes();
+        let tp = bytes.len() - 8;
+        bytes[tp..tp + 4].copy_from_slice(&42u32.to_le_bytes());
+        let e = read(&bytes, &["b"]).unwrap_err();
+        assert!(
+            e.to_string().contains("Unknown metadata value type 42"),
+            "{e}"
+        );
+    }
+}
@@ -1,65 +1,135 @@
+use anyhow::Context as _;
+use clap::Parser;
+use common::device::DeviceSpec;
 use either::Either;
 use indexmap::IndexMap;
-use std::{num::NonZero, sync::Arc};
+use std::{
+    num::NonZero,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::sync::mpsc::channel;
 
 use mistralrs::{
-    Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata, GGUFLoaderBuilder, GGUFSpecificConfig, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, NormalRequest, PagedAttentionConfig, Request, RequestMessage, ResponseOk, Result, SamplingParams, SchedulerConfig, TokenSource
+    Constraint, DefaultSchedulerMethod, DeviceMapMetadata, GGUFLoaderBuilder, GGUFSpecificConfig,
+    MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType, NormalRequest, PagedAttentionConfig,
+    Request, RequestMessage, ResponseOk, SamplingParams, SchedulerConfig, TokenSource,
 };
 
-/// Gets the best device, cpu, cuda if compiled with CUDA
-pub(crate) fn best_device() -> Result<Device> {
-    #[cfg(not(feature = "metal"))]
-    {
-        Device::cuda_if_available(0)
+mod chat_template;
+#[cfg(test)]
+mod fake;
+mod gguf;
+mod repl;
+
+use chat_template::BuiltinTemplate;
+
+#[derive(Debug, Parser)]
+#[command(about = "Run a local GGUF model with mistral.rs")]
+struct Args {
+    /// Directory with the GGUF file, and optionally `tokenizer.json` and
+    /// `tokenizer_config.json`. Nothing is downloaded.
+    #[arg(short, long, default_value = ".")]
+    model_dir: PathBuf,
+
+    /// GGUF file name inside `--model-dir`. Needed when there is more than one.
+    #[arg(short, long)]
+    gguf: Option<String>,
+
+    /// Built-in chat template, used when neither the GGUF metadata nor `tokenizer_config.json`
+    /// has one.
+    #[arg(long, value_enum, default_value_t = BuiltinTemplate::Mistral)]
+    template: BuiltinTemplate,
+
+    /// Answer this prompt and exit, instead of starting an interactive chat.
+    #[arg(short, long)]
+    prompt: Option<String>,
+
+    /// Device: `auto`, `cpu`, `cuda:N` or `metal:N`.
+    #[arg(short, long, default_value = "auto")]
+    device: DeviceSpec,
+
+    /// Threads used for inference on the CPU. Defaults to one per core.
+    #[arg(long)]
+    cpu_threads: Option<usize>,
+}
+
+/// Picks the GGUF file in `dir`: `name` if given, else the only `.gguf` file there.
+fn find_gguf(dir: &Path, name: Option<&str>) -> anyhow::Result<String> {
+    if let Some(name) = name {
+        anyhow::ensure!(
+            dir.join(name).is_file(),
+            "GGUF file `{name}` not found in `{}`.",
+            dir.display()
+        );
+        return Ok(name.to_string());
     }
-    #[cfg(feature = "metal")]
-    {
-        Device::new_metal(0)
+    let mut files = std::fs::read_dir(dir)
+        .with_context(|| format!("Could not read model directory `{}`.", dir.display()))?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.ends_with(".gguf").then_some(name)
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    match files.as_slice() {
+        [file] => Ok(file.clone()),
+        [] => anyhow::bail!("No `.gguf` file in `{}`.", dir.display()),
+        _ => anyhow::bail!(
+            "Several GGUF files in `{}`, pick one with `--gguf`: {}.",
+            dir.display(),
+            files.join(", ")
+        ),
     }
 }
 
-async fn setup() -> anyhow::Result<Arc<MistralRs>> {
-    // Select a Mistral model
-    // We do not use any files from HF servers here, and instead load the
-    // chat template from the specified file, and the tokenizer and model from a
-    // local GGUF file at the path `.`
+async fn setup(args: &Args) -> anyhow::Result<Arc<MistralRs>> {
+    // Everything comes from the model directory: mistral.rs reads local paths instead of
+    // downloading them, and `TokenSource::None` keeps it from looking for an HF token.
+    let model_dir = args.model_dir.to_string_lossy().to_string();
+    let gguf_file = find_gguf(&args.model_dir, args.gguf.as_deref())?;
+    let template = chat_template::find(
+        &args.model_dir.join(&gguf_file),
+        &args.model_dir,
+        args.template,
+    )?;
+    println!("Chat template: {}.", template.source);
+    // Without a `tokenizer.json` the tokenizer is built from the GGUF metadata.
+    let tok_model_id = if args.model_dir.join("tokenizer.json").is_file() {
+        println!("Tokenizer: `tokenizer.json` in `{model_dir}`.");
+        Some(model_dir.clone())
+    } else {
+        println!("Tokenizer: GGUF metadata.");
+        None
+    };
     let loader = GGUFLoaderBuilder::new(
-        // chat_templateに何を指定すればよいのか不明。
-        // tokenizer_config.jsonはget_tokenizers_json.pyによって取得できた
-        // tokenizer_config.jsonからchat_templateに変換する？必要があるのか、方法が不明。どちらも別々に必要そうだが、HFにはない
-        // https://huggingface.co/elyza/ELYZA-japanese-Llama-2-7b-instruct
-        // おそらくエラーが起こらないようなJIJAテンプレートを定義する必要がある、mistral.jsonは上手くいくパターン
-        Some("chat_templates/mistral.json".to_string()),
-        // Some("chat_templates/llama2.json".to_string()),
-        // Some("chat_templates/chatml.json".to_string()),
-        // Some("chat_templates/default.json".to_string()),
-        // Some("tokenizer_config.json".to_string()),
-        // None,
-        Some("elyza/ELYZA-japanese-Llama-2-7b-instruct".to_string()),
-        "mmnga/ELYZA-japanese-Llama-2-7b-instruct-gguf".to_string(),
-        vec!["ELYZA-japanese-Llama-2-7b-instruct-q4_K_M.gguf".to_string()],
+        Some(template.file.to_string_lossy().to_string()),
+        tok_model_id,
+        model_dir,
+        vec![gguf_file],
         GGUFSpecificConfig {
             prompt_batchsize: None,
             topology: None,
         },
     )
     .build();
-    let cache_config = Some(PagedAttentionConfig::new(
-        Some(32),
-        512,
-        MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-    )?);
+    let device = common::device::select(args.device, args.cpu_threads)?;
+    let gpu_mem = MemoryGpuConfig::Utilization(0.9); // NOTE(EricLBuehler): default is to use 90% of memory
+    let cache_config = if device.supports_paged_attn(gpu_mem) {
+        Some(PagedAttentionConfig::new(Some(32), 512, gpu_mem)?)
+    } else {
+        None
+    };
     // Load, into a Pipeline
     let pipeline = loader.load_model_from_hf(
         None,
-        TokenSource::CacheToken,
+        TokenSource::None,
         &ModelDType::Auto,
-        &best_device()?,
+        &device.device,
         false,
         DeviceMapMetadata::dummy(),
         None,
-        cache_config, // No PagedAttention.
+        cache_config,
     )?;
     let scheduler_config = if cache_config.is_some() {
         // Handle case where we may have device mapping
@@ -82,15 +152,24 @@ async fn setup() -> anyhow::Result<Arc<MistralRs>> {
     Ok(MistralRsBuilder::new(pipeline, scheduler_config).build())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let mistralrs = setup().await?;
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // While the process is still single threaded.
+    common::device::init_cpu_threads(args.cpu_threads)?;
+    tokio::runtime::Runtime::new()?.block_on(run(args))
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let mistralrs = setup(&args).await?;
+    let Some(prompt) = args.prompt else {
+        return repl::run(mistralrs).await;
+    };
 
     let (tx, mut rx) = channel(10_000);
     let request = Request::Normal(NormalRequest {
         messages: RequestMessage::Chat(vec![IndexMap::from([
             ("role".to_string(), Either::Left("user".to_string())),
-            ("content".to_string(), Either::Left("日本の伝統料理について教えて".to_string())),
+            ("content".to_string(), Either::Left(prompt)),
         ])]),
         sampling_params: SamplingParams::default(),
         response: tx,
@@ -0,0 +1,421 @@
+//! Interactive multi-turn chat. Each turn sends the whole conversation and streams the answer.
+//!
+//! Lines starting with `/` are commands, see [`HELP`]. Transcripts are saved as
+//! `{"messages": [{"role", "content"}, ...]}`, with the system prompt as the first message.
+
+use anyhow::Context as _;
+use either::Either;
+use indexmap::IndexMap;
+use mistralrs::{
+    Constraint, MistralRs, NormalRequest, Request, RequestMessage, Response, SamplingParams,
+};
+use serde_json::{json, Value};
+use std::{
+    io::Write as _,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncBufReadExt as _, BufReader},
+    sync::mpsc::channel,
+};
+
+const HELP: &str = "\
+Commands:
+  /system [TEXT]      set the system prompt, or clear it without TEXT
+  /reset              forget the conversation, keeping the system prompt
+  /save PATH          save the conversation as JSON
+  /load PATH          replace the conversation with a saved one
+  /set PARAM VALUE    set temperature, top_p, top_k or max_len, `none` for the default
+  /params             show the sampling parameters
+  /help               show this help
+  /quit               exit, as does Ctrl-D";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    role: String,
+    content: String,
+}
+
+struct Session {
+    mistralrs: Arc<MistralRs>,
+    system: Option<String>,
+    history: Vec<Message>,
+    sampling: SamplingParams,
+    next_id: usize,
+}
+
+pub async fn run(mistralrs: Arc<MistralRs>) -> anyhow::Result<()> {
+    let mut session = Session {
+        mistralrs,
+        system: None,
+        history: Vec::new(),
+        sampling: SamplingParams::default(),
+        next_id: 0,
+    };
+    println!("Chat with the model, `/help` for commands.");
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next_line().await? else {
+            println!();
+            return Ok(());
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let result = match line.strip_prefix('/') {
+            Some(command) => match session.command(command) {
+                Ok(Flow::Quit) => return Ok(()),
+                Ok(Flow::Continue) => Ok(()),
+                Err(e) => Err(e),
+            },
+            None => session.chat(line).await,
+        };
+        if let Err(e) = result {
+            eprintln!("Error: {e:#}");
+        }
+    }
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+impl Session {
+    fn command(&mut self, command: &str) -> anyhow::Result<Flow> {
+        let (name, arg) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, arg)| (name, arg.trim()));
+        match name {
+            "system" => {
+                self.system = (!arg.is_empty()).then(|| arg.to_string());
+                println!(
+                    "System prompt {}.",
+                    if arg.is_empty() { "cleared" } else { "set" }
+                );
+            }
+            "reset" => {
+                self.history.clear();
+                println!("Conversation reset.");
+            }
+            "save" => {
+                anyhow::ensure!(!arg.is_empty(), "Usage: /save PATH");
+                self.save(Path::new(arg))?;
+                println!("Saved {} messages to `{arg}`.", self.history.len());
+            }
+            "load" => {
+                anyhow::ensure!(!arg.is_empty(), "Usage: /load PATH");
+                self.load(Path::new(arg))?;
+                println!("Loaded {} messages from `{arg}`.", self.history.len());
+            }
+            "set" => {
+                let (param, value) = arg
+                    .split_once(char::is_whitespace)
+                    .context("Usage: /set PARAM VALUE")?;
+                self.set(param, value.trim())?;
+                self.print_params();
+            }
+            "params" => self.print_params(),
+            "help" => println!("{HELP}"),
+            "quit" | "exit" => return Ok(Flow::Quit),
+            _ => anyhow::bail!("Unknown command `/{name}`, see `/help`."),
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn set(&mut self, param: &str, value: &str) -> anyhow::Result<()> {
+        fn parse<T: std::str::FromStr>(value: &str) -> anyhow::Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            if value == "none" {
+                return Ok(None);
+            }
+            Ok(Some(value.parse()?))
+        }
+        let sampling = &mut self.sampling;
+        match param {
+            "temperature" => {
+                let value = parse::<f64>(value)?;
+                anyhow::ensure!(
+                    value.is_none_or(|v| v >= 0.0),
+                    "temperature must be at least 0."
+                );
+                sampling.temperature = value;
+            }
+            "top_p" => {
+                let value = parse::<f64>(value)?;
+                anyhow::ensure!(
+                    value.is_none_or(|v| v > 0.0 && v <= 1.0),
+                    "top_p must be in (0, 1]."
+                );
+                sampling.top_p = value;
+            }
+            "top_k" => {
+                let value = parse::<usize>(value)?;
+                anyhow::ensure!(value != Some(0), "top_k must be at least 1.");
+                sampling.top_k = value;
+            }
+            "max_len" => {
+                let value = parse::<usize>(value)?;
+                anyhow::ensure!(value != Some(0), "max_len must be at least 1.");
+                sampling.max_len = value;
+            }
+            _ => anyhow::bail!(
+                "Unknown parameter `{param}`, use temperature, top_p, top_k or max_len."
+            ),
+        }
+        Ok(())
+    }
+
+    fn print_params(&self) {
+        fn show<T: ToString>(value: Option<T>) -> String {
+            value.map_or_else(|| "none".to_string(), |v| v.to_string())
+        }
+        let sampling = &self.sampling;
+        println!(
+            "temperature: {}, top_p: {}, top_k: {}, max_len: {}",
+            show(sampling.temperature),
+            show(sampling.top_p),
+            show(sampling.top_k),
+            show(sampling.max_len)
+        );
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        save_transcript(path, self.system.as_deref(), &self.history)
+    }
+
+    fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        (self.system, self.history) = load_transcript(path)?;
+        Ok(())
+    }
+
+    /// Sends the conversation with `prompt` added and prints the answer as it streams in. The
+    /// turn is only kept in the history if the answer completes.
+    async fn chat(&mut self, prompt: &str) -> anyhow::Result<()> {
+        let messages = self
+            .system
+            .iter()
+            .map(|content| ("system", content.as_str()))
+            .chain(
+                self.history
+                    .iter()
+                    .map(|m| (m.role.as_str(), m.content.as_str())),
+            )
+            .chain([("user", prompt)])
+            .map(|(role, content)| {
+                IndexMap::from([
+                    ("role".to_string(), Either::Left(role.to_string())),
+                    ("content".to_string(), Either::Left(content.to_string())),
+                ])
+            })
+            .collect();
+
+        let (tx, mut rx) = channel(10_000);
+        let request = Request::Normal(NormalRequest {
+            messages: RequestMessage::Chat(messages),
+            sampling_params: self.sampling.clone(),
+            response: tx,
+            return_logprobs: false,
+            is_streaming: true,
+            id: self.next_id,
+            constraint: Constraint::None,
+            suffix: None,
+            adapters: None,
+            tools: None,
+            tool_choice: None,
+            logits_processors: None,
+        });
+        self.next_id += 1;
+        let start = Instant::now();
+        self.mistralrs.get_sender()?.send(request).await?;
+
+        let mut answer = String::new();
+        let (mut first_token, mut chunks) = (None::<Duration>, 0);
+        let finish_reason = loop {
+            let response = rx
+                .recv()
+                .await
+                .context("The model stopped without finishing the answer.")?;
+            let chunk = match response {
+                Response::Chunk(chunk) => chunk,
+                Response::ModelError(e, _) => anyhow::bail!("Model error: {e}"),
+                Response::ValidationError(e) => anyhow::bail!("Invalid request: {e}"),
+                Response::InternalError(e) => anyhow::bail!("Internal error: {e}"),
+                _ => anyhow::bail!("Unexpected response to a streaming chat request."),
+            };
+            let Some(choice) = chunk.choices.into_iter().next() else {
+                continue;
+            };
+            first_token.get_or_insert_with(|| start.elapsed());
+            chunks += 1;
+            print!("{}", choice.delta.content);
+            std::io::stdout().flush()?;
+            answer.push_str(&choice.delta.content);
+            if let Some(reason) = choice.finish_reason {
+                break reason;
+            }
+        };
+        println!();
+
+        let elapsed = start.elapsed();
+        let first_token = first_token.unwrap_or(elapsed);
+        let decoding = (elapsed - first_token).as_secs_f64();
+        let rate = if decoding > 0.0 {
+            format!("{:.1}", (chunks - 1) as f64 / decoding)
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "[{chunks} chunks, first after {:.2}s, {rate} chunks/s, finish reason: {finish_reason}]",
+            first_token.as_secs_f64()
+        );
+        if finish_reason == "length" {
+            println!("[The answer was cut off by max_len.]");
+        }
+
+        self.history.push(Message {
+            role: "user".to_string(),
+            content: prompt.to_string(),
+        });
+        self.history.push(Message {
+            role: "assistant".to_string(),
+            content: answer,
+        });
+        Ok(())
+    }
+}
+
+fn save_transcript(path: &Path, system: Option<&str>, history: &[Message]) -> anyhow::Result<()> {
+    let messages = system
+        .iter()
+        .map(|content| json!({ "role": "system", "content": content }))
+        .chain(
+            history
+                .iter()
+                .map(|m| json!({ "role": m.role, "content": m.content })),
+        )
+        .collect::<Vec<_>>();
+    let transcript = serde_json::to_string_pretty(&json!({ "messages": messages }))?;
+    std::fs::write(path, transcript)
+        .with_context(|| format!("Could not write `{}`.", path.display()))
+}
+
+/// The system prompt and history saved in `path`. Nothing is returned unless all of it is valid.
+fn load_transcript(path: &Path) -> anyhow::Result<(Option<String>, Vec<Message>)> {
+    let transcript = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read `{}`.", path.display()))?;
+    let transcript = serde_json::from_str::<Value>(&transcript)
+        .with_context(|| format!("`{}` is not JSON.", path.display()))?;
+    let messages = transcript["messages"]
+        .as_array()
+        .context("The transcript has no `messages` array.")?;
+    let mut system = None;
+    let mut history = Vec::new();
+    for (i, message) in messages.iter().enumerate() {
+        let (Some(role), Some(content)) = (message["role"].as_str(), message["content"].as_str())
+        else {
+            anyhow::bail!("Message {i} needs a string `role` and `content`.");
+        };
+        match role {
+            "system" if i == 0 => system = Some(content.to_string()),
+            "user" | "assistant" => history.push(Message {
+                role: role.to_string(),
+                content: content.to_string(),
+            }),
+            _ => anyhow::bail!("Message {i} has an unexpected role `{role}`."),
+        }
+    }
+    Ok((system, history))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::TempDir;
+
+    fn message(role: &str, content: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
+
+    /// Loads `transcript` written to a file in `dir`.
+    fn load_json(
+        dir: &TempDir,
+        transcript: Value,
+    ) -> anyhow::Result<(Option<String>, Vec<Message>)> {
+        let path = dir.0.join("transcript.json");
+        std::fs::write(&path, transcript.to_string()).unwrap();
+        load_transcript(&path)
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new("repl-round-trip");
+        let path = dir.0.join("chat.json");
+        let history = vec![
+            message("user", "Hi \"there\"\n"),
+            message("assistant", "こんにちは"),
+            message("user", ""),
+        ];
+        save_transcript(&path, Some("Be brief."), &history).unwrap();
+        let saved = serde_json::from_str::<Value>(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            saved.unwrap()["messages"][0],
+            json!({ "role": "system", "content": "Be brief." })
+        );
+        assert_eq!(
+            load_transcript(&path).unwrap(),
+            (Some("Be brief.".to_string()), history.clone())
+        );
+
+        save_transcript(&path, None, &history).unwrap();
+        assert_eq!(load_transcript(&path).unwrap(), (None, history));
+    }
+
+    #[test]
+    fn rejects_invalid_transcripts() {
+        let dir = TempDir::new("repl-invalid");
+        for (transcript, expected) in [
+            (json!([]), "no `messages` array"),
+            (json!({ "messages": {} }), "no `messages` array"),
+            (
+                json!({ "messages": [{ "role": "user" }] }),
+                "Message 0 needs a string `role` and `content`.",
+            ),
+            (
+                json!({ "messages": [{ "role": "user", "content": 1 }] }),
+                "Message 0 needs a string `role` and `content`.",
+            ),
+            (
+                json!({ "messages": [
+                    { "role": "user", "content": "hi" },
+                    { "role": "system", "content": "late" },
+                ] }),
+                "Message 1 has an unexpected role `system`.",
+            ),
+            (
+                json!({ "messages": [{ "role": "tool", "content": "{}" }] }),
+                "Message 0 has an unexpected role `tool`.",
+            ),
+        ] {
+            let e = load_json(&dir, transcript.clone()).unwrap_err().to_string();
+            assert!(e.contains(expected), "{transcript}: {e}");
+        }
+
+        let path = dir.0.join("broken.json");
+        std::fs::write(&path, "{\"messages\": [").unwrap();
+        let e = load_transcript(&path).unwrap_err().to_string();
+        assert!(e.contains("is not JSON"), "{e}");
+        let e = load_transcript(&dir.0.join("missing.json")).unwrap_err();
+        assert!(e.to_string().starts_with("Could not read"), "{e}");
+    }
+}
@@ -0,0 +1,455 @@
+//! LoRA and X-LoRA adapters of each model, and the `/v1/adapters` endpoints.
+//!
+//! mistral.rs 0.3 loads adapter weights together with the model and cannot add any later, so
+//! the set is fixed at startup by the model's ordering file: the adapters in its `order` plus
+//! its `preload_adapters`. `POST /v1/adapters/{name}/disable` takes an adapter out of service,
+//! though its weights stay in memory, and `POST /v1/adapters/{name}/enable` puts it back. Both
+//! need an admin API key.
+
+use axum::extract::{Json, Path, Query, State};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    config::{AdapterKind, AdaptersConfig, DEFAULT_MODEL_NAME},
+    engine::Engine,
+    error::ApiError,
+    openai::{AdapterList, AdapterObject},
+    state::AppState,
+};
+
+pub struct Adapters {
+    kind: AdapterKind,
+    adapters: Mutex<IndexMap<String, Adapter>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Adapter {
+    /// HF model id or local directory the weights were loaded from.
+    pub source: String,
+    /// Used by requests that pick no adapters.
+    pub default: bool,
+    /// Requests may use it.
+    pub enabled: bool,
+}
+
+impl Adapters {
+    pub fn new(kind: AdapterKind, adapters: impl IntoIterator<Item = (String, Adapter)>) -> Self {
+        Self {
+            kind,
+            adapters: Mutex::new(adapters.into_iter().collect()),
+        }
+    }
+
+    /// Everything in the ordering file, enabled. The `order` adapters are the defaults.
+    pub fn from_config(config: &AdaptersConfig) -> Self {
+        let ordered = config.ordered.iter().map(|name| {
+            let adapter = Adapter {
+                source: config.model_id.clone(),
+                default: true,
+                enabled: true,
+            };
+            (name.clone(), adapter)
+        });
+        let preloaded = config.preloaded.iter().map(|(name, source)| {
+            let adapter = Adapter {
+                source: source.clone(),
+                default: false,
+                enabled: true,
+            };
+            (name.clone(), adapter)
+        });
+        Self::new(config.kind, ordered.chain(preloaded))
+    }
+
+    /// The adapters a request runs with: the enabled ones it names, or else the enabled
+    /// defaults. Always explicit for LoRA, since mistral.rs keeps the last request's adapters
+    /// active for the next one.
+    fn select(&self, requested: Option<Vec<String>>) -> Result<Option<Vec<String>>, ApiError> {
+        if self.kind == AdapterKind::Xlora {
+            return match requested {
+                Some(_) => Err(ApiError::invalid_request(
+                    "This model mixes its X-LoRA adapters itself, they cannot be picked per request.",
+                )
+                .with_param("adapters")),
+                None => Ok(None),
+            };
+        }
+        let adapters = self.adapters.lock().unwrap();
+        let Some(requested) = requested else {
+            let defaults = adapters
+                .iter()
+                .filter(|(_, adapter)| adapter.default && adapter.enabled)
+                .map(|(name, _)| name.clone())
+                .collect();
+            return Ok(Some(defaults));
+        };
+        for (i, name) in requested.iter().enumerate() {
+            if !adapters.get(name).is_some_and(|adapter| adapter.enabled) {
+                return Err(
+                    ApiError::invalid_request(format!("Adapter `{name}` is not enabled."))
+                        .with_param(format!("adapters[{i}]")),
+                );
+            }
+        }
+        Ok(Some(requested))
+    }
+
+    fn list(&self) -> Vec<(String, Adapter)> {
+        let adapters = self.adapters.lock().unwrap();
+        adapters
+            .iter()
+            .map(|(name, adapter)| (name.clone(), adapter.clone()))
+            .collect()
+    }
+
+    /// Takes `name` in or out of service. Its weights stay in memory either way.
+    fn set_enabled(&self, name: &str, enabled: bool) -> Result<Adapter, ApiError> {
+        self.check_lora()?;
+        let mut adapters = self.adapters.lock().unwrap();
+        let adapter = adapters.get_mut(name).ok_or_else(|| unknown(name))?;
+        adapter.enabled = enabled;
+        Ok(adapter.clone())
+    }
+
+    fn check_lora(&self) -> Result<(), ApiError> {
+        match self.kind {
+            AdapterKind::Lora => Ok(()),
+            AdapterKind::Xlora => Err(ApiError::invalid_request(
+                "X-LoRA adapters are always all in use and cannot be enabled or disabled one by one.",
+            )
+            .with_param("model")),
+        }
+    }
+}
+
+/// An adapter not in the set, which mistral.rs cannot add to a running model.
+fn unknown(name: &str) -> ApiError {
+    ApiError::NotFound(format!(
+        "Adapter `{name}` was not loaded at startup. Add it to `preload_adapters` in the model's \
+         ordering file and restart."
+    ))
+}
+
+/// Checks a request's `adapters` against what `engine` has enabled, returning the adapters to
+/// run it with.
+pub fn select(
+    engine: &dyn Engine,
+    requested: Option<Vec<String>>,
+) -> Result<Option<Vec<String>>, ApiError> {
+    match (engine.adapters(), requested) {
+        (Some(adapters), requested) => adapters.select(requested),
+        (None, Some(_)) => Err(ApiError::invalid_request(
+            "This model was loaded without adapters.",
+        )
+        .with_param("adapters")),
+        (None, None) => Ok(None),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ModelQuery {
+    model: Option<String>,
+}
+
+fn adapter_object(model: &str, kind: AdapterKind, name: String, adapter: Adapter) -> AdapterObject {
+    AdapterObject {
+        object: "adapter",
+        name,
+        model: model.to_string(),
+        kind,
+        source: adapter.source,
+        default: adapter.default,
+        enabled: adapter.enabled,
+    }
+}
+
+/// The adapters of every model, or of `?model=`.
+pub async fn list_adapters(
+    State(app): State<Arc<AppState>>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<AdapterList>, ApiError> {
+    let models = match query.model {
+        Some(model) => vec![app.models.get(&model)?],
+        None => app
+            .models
+            .list()
+            .into_iter()
+            .map(|(name, model)| (name, model.engine))
+            .collect(),
+    };
+    let mut data = Vec::new();
+    for (model, engine) in models {
+        if let Some(adapters) = engine.adapters() {
+            data.extend(
+                adapters
+                    .list()
+                    .into_iter()
+                    .map(|(name, adapter)| adapter_object(&model, adapters.kind, name, adapter)),
+            );
+        }
+    }
+    Ok(Json(AdapterList {
+        object: "list",
+        data,
+    }))
+}
+
+pub async fn enable_adapter(
+    State(app): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<AdapterObject>, ApiError> {
+    set_enabled(&app, name, query, true)
+}
+
+pub async fn disable_adapter(
+    State(app): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(query): Query<ModelQuery>,
+) -> Result<Json<AdapterObject>, ApiError> {
+    set_enabled(&app, name, query, false)
+}
+
+fn set_enabled(
+    app: &AppState,
+    name: String,
+    query: ModelQuery,
+    enabled: bool,
+) -> Result<Json<AdapterObject>, ApiError> {
+    let model = query.model.as_deref().unwrap_or(DEFAULT_MODEL_NAME);
+    let (model, engine) = app.models.get(model)?;
+    let adapters = engine.adapters().ok_or_else(no_adapters)?;
+    let adapter = adapters.set_enabled(&name, enabled)?;
+    let action = if enabled { "enabled" } else { "disabled" };
+    println!("Adapter `{name}` of model `{model}` {action}.");
+    Ok(Json(adapter_object(&model, adapters.kind, name, adapter)))
+}
+
+fn no_adapters() -> ApiError {
+    ApiError::invalid_request("This model was loaded without adapters.").with_param("model")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::ApiKeys,
+        engine::fake::{app, chat_done, post, FakeEngine},
+    };
+    use axum::{
+        body::Body,
+        http::{header, Request as HttpRequest, StatusCode},
+    };
+    use mistralrs::Response;
+    use serde_json::{json, Value};
+    use tower::ServiceExt as _;
+
+    /// `math` from the adapter set, `code` preloaded from elsewhere.
+    fn lora() -> Adapters {
+        let adapter = |source: &str, default| Adapter {
+            source: source.to_string(),
+            default,
+            enabled: true,
+        };
+        Adapters::new(
+            AdapterKind::Lora,
+            [
+                ("math".to_string(), adapter("org/adapters", true)),
+                ("code".to_string(), adapter("org/code-adapter", false)),
+            ],
+        )
+    }
+
+    const KEYS: &str = r#"
+        [[keys]]
+        name = "ops"
+        key = "sk-ops"
+        admin = true
+
+        [[keys]]
+        name = "alice"
+        key = "sk-alice"
+    "#;
+
+    /// Sends a request through the full router. With a `key`, the router requires the keys in
+    /// [`KEYS`].
+    async fn call(
+        app: &Arc<AppState>,
+        method: &str,
+        uri: &str,
+        key: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut request = HttpRequest::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        let keys = key.map(|_| Arc::new(KEYS.parse::<ApiKeys>().unwrap()));
+        let response = crate::get_router(app.clone(), keys)
+            .oneshot(request.body(body).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    /// `POST /v1/adapters/{name}/{action}` as the admin.
+    async fn admin(app: &Arc<AppState>, name: &str, action: &str) -> (StatusCode, Value) {
+        let uri = format!("/v1/adapters/{name}/{action}");
+        call(app, "POST", &uri, Some("sk-ops"), None).await
+    }
+
+    fn chat(adapters: Option<Value>) -> Value {
+        let mut request = json!({ "messages": [{ "role": "user", "content": "hi" }] });
+        if let Some(adapters) = adapters {
+            request["adapters"] = adapters;
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn lists_the_adapters() {
+        let app = app(FakeEngine::with_adapters([], lora()));
+        let (status, body) = call(&app, "GET", "/v1/adapters", None, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0]["name"], "math");
+        assert_eq!(data[0]["model"], "fake");
+        assert_eq!(data[0]["kind"], "lora");
+        assert_eq!(data[0]["default"], true);
+        assert_eq!(data[1]["source"], "org/code-adapter");
+        assert_eq!(data[1]["enabled"], true);
+
+        let (status, _) = call(&app, "GET", "/v1/adapters?model=other", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn requests_run_with_enabled_adapters() {
+        let engine = FakeEngine::with_adapters(
+            [
+                vec![Response::Done(chat_done("a", "stop"))],
+                vec![Response::Done(chat_done("a", "stop"))],
+            ],
+            lora(),
+        );
+        let app = app(engine.clone());
+        let (status, _) = post(app.clone(), "/v1/chat/completions", chat(None)).await;
+        assert_eq!(status, StatusCode::OK);
+        let request = chat(Some(json!(["code"])));
+        let (status, _) = post(app.clone(), "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = chat(Some(json!(["code", "poetry"])));
+        let (status, body) = post(app, "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["error"]["param"], "adapters[1]");
+
+        let requests = engine.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].adapters, Some(vec!["math".to_string()]));
+        assert_eq!(requests[1].adapters, Some(vec!["code".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn disables_and_enables() {
+        let engine =
+            FakeEngine::with_adapters([vec![Response::Done(chat_done("a", "stop"))]], lora());
+        let app = app(engine.clone());
+        let (status, body) = admin(&app, "code", "disable").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["name"], "code");
+        assert_eq!(body["enabled"], false);
+        assert_eq!(admin(&app, "math", "disable").await.0, StatusCode::OK);
+
+        let request = chat(Some(json!(["code"])));
+        let (status, _) = post(app.clone(), "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = admin(&app, "code", "enable").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["enabled"], true);
+        // Disabled defaults are left out of requests that pick no adapters.
+        let (status, _) = post(app.clone(), "/v1/chat/completions", chat(None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(engine.requests()[0].adapters, Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn unknown_adapters_are_not_found() {
+        let app = app(FakeEngine::with_adapters([], lora()));
+        for action in ["enable", "disable"] {
+            let (status, body) = admin(&app, "poetry", action).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{action}");
+            let message = body["error"]["message"].as_str().unwrap();
+            assert!(
+                message.contains("`poetry` was not loaded at startup"),
+                "{message}"
+            );
+        }
+        let uri = "/v1/adapters/code/disable?model=other";
+        let (status, body) = call(&app, "POST", uri, Some("sk-ops"), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["code"], "model_not_found");
+    }
+
+    #[tokio::test]
+    async fn changes_need_an_admin_key() {
+        let app = app(FakeEngine::with_adapters([], lora()));
+        let disable = |key| call(&app, "POST", "/v1/adapters/code/disable", key, None);
+        let (status, body) = disable(Some("sk-alice")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["code"], "insufficient_permissions");
+        assert_eq!(disable(None).await.0, StatusCode::FORBIDDEN);
+        assert_eq!(disable(Some("sk-ops")).await.0, StatusCode::OK);
+
+        let enable = call(
+            &app,
+            "POST",
+            "/v1/adapters/code/enable",
+            Some("sk-alice"),
+            None,
+        );
+        assert_eq!(enable.await.0, StatusCode::FORBIDDEN);
+        let (status, body) = call(&app, "GET", "/v1/adapters", Some("sk-alice"), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["data"][1]["enabled"], false);
+    }
+
+    #[tokio::test]
+    async fn xlora_adapters_cannot_be_picked() {
+        let adapters = Adapters::new(AdapterKind::Xlora, lora().list());
+        let app = app(FakeEngine::with_adapters([], adapters));
+        let request = chat(Some(json!(["math"])));
+        let (status, _) = post(app.clone(), "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            admin(&app, "math", "disable").await.0,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn models_without_adapters_reject_them() {
+        let engine = FakeEngine::new([]);
+        let app = app(engine.clone());
+        let request = chat(Some(json!(["math"])));
+        let (status, body) = post(app.clone(), "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["error"]["param"], "adapters");
+        let (_, body) = call(&app, "GET", "/v1/adapters", None, None).await;
+        assert_eq!(body["data"], json!([]));
+    }
+}
@@ -0,0 +1,591 @@
+//! Admission queue in front of the engines.
+//!
+//! Each model runs at most `max_running` requests at once; the others wait here, so the engine
+//! only ever sees work it can start. Waiting requests are admitted by priority class first, and
+//! within a class round-robin across clients, so one client with many requests cannot starve the
+//! others. A request's client is its API key, and its class comes from the key, lowered by the
+//! `x-priority` header if the client asks. More than `max_queue` waiting requests, or a request
+//! still waiting at its deadline, gets a 503 without reaching the engine.
+
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use indexmap::IndexMap;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+
+use crate::{auth::KeyBudget, config::AdmissionConfig, error::ApiError};
+
+/// Lowers the priority of a request, e.g. `x-priority: low` for batch work.
+pub const PRIORITY_HEADER: &str = "x-priority";
+/// Seconds a request may wait in the queue, overriding `admission.queue_timeout_secs`.
+pub const TIMEOUT_HEADER: &str = "x-request-timeout";
+/// Client of requests without an API key.
+const ANONYMOUS: &str = "anonymous";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "low" => Some(Self::Low),
+            "normal" => Some(Self::Normal),
+            "high" => Some(Self::High),
+            _ => None,
+        }
+    }
+
+    /// Position in [`QueueState::classes`], highest first.
+    fn index(self) -> usize {
+        match self {
+            Self::High => 0,
+            Self::Normal => 1,
+            Self::Low => 2,
+        }
+    }
+}
+
+/// Where a request goes in the queue, attached to `/v1` requests by [`ticket`].
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub priority: Priority,
+    pub client: String,
+    /// Give up if not admitted by then.
+    pub deadline: Option<Instant>,
+}
+
+impl Default for Ticket {
+    fn default() -> Self {
+        Self {
+            priority: Priority::default(),
+            client: ANONYMOUS.to_string(),
+            deadline: None,
+        }
+    }
+}
+
+pub struct Admission {
+    config: AdmissionConfig,
+    queues: Mutex<HashMap<String, Arc<ModelQueue>>>,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Self {
+        Self {
+            config,
+            queues: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until `model` can take the request. The returned permit holds its running slot
+    /// until dropped, which should be once the response is done.
+    pub async fn admit(&self, model: &str, ticket: Ticket) -> Result<Permit, ApiError> {
+        if ticket
+            .deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            return Err(deadline_error());
+        }
+        let queue = self.queue(model);
+        let (id, rx) = {
+            let mut state = queue.state.lock().unwrap();
+            if state.waiting == 0 && state.running < self.config.max_running {
+                state.running += 1;
+                drop(state);
+                return Ok(Permit { queue });
+            }
+            if state.waiting >= self.config.max_queue {
+                return Err(ApiError::Unavailable(format!(
+                    "Too many requests are waiting for model `{model}`, try again later."
+                )));
+            }
+            let (tx, rx) = oneshot::channel();
+            let id = state.next_id;
+            state.next_id += 1;
+            state.waiting += 1;
+            state.classes[ticket.priority.index()]
+                .clients
+                .entry(ticket.client.clone())
+                .or_default()
+                .push_back(Waiter {
+                    id,
+                    deadline: ticket.deadline,
+                    tx,
+                });
+            (id, rx)
+        };
+
+        let mut waiting = Waiting {
+            queue: queue.clone(),
+            id,
+            priority: ticket.priority,
+            client: ticket.client,
+            rx,
+            admitted: false,
+        };
+        let admitted = match ticket.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline.into(), &mut waiting.rx)
+                .await
+                .is_ok_and(|result| result.is_ok()),
+            None => (&mut waiting.rx).await.is_ok(),
+        };
+        if !admitted {
+            return Err(deadline_error());
+        }
+        waiting.admitted = true;
+        Ok(Permit { queue })
+    }
+
+    /// Requests waiting for each model.
+    pub fn waiting(&self) -> Vec<(String, usize)> {
+        let queues = self.queues.lock().unwrap();
+        queues
+            .iter()
+            .map(|(model, queue)| (model.clone(), queue.state.lock().unwrap().waiting))
+            .collect()
+    }
+
+    fn queue(&self, model: &str) -> Arc<ModelQueue> {
+        let mut queues = self.queues.lock().unwrap();
+        queues
+            .entry(model.to_string())
+            .or_insert_with(|| {
+                Arc::new(ModelQueue {
+                    max_running: self.config.max_running,
+                    state: Mutex::new(QueueState::default()),
+                })
+            })
+            .clone()
+    }
+}
+
+fn deadline_error() -> ApiError {
+    ApiError::Unavailable("The request was not admitted before its deadline.".to_string())
+}
+
+struct ModelQueue {
+    max_running: usize,
+    state: Mutex<QueueState>,
+}
+
+impl ModelQueue {
+    /// Frees a running slot and hands it on.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        state.dispatch(self.max_running);
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    running: usize,
+    waiting: usize,
+    next_id: u64,
+    classes: [Class; 3],
+}
+
+impl QueueState {
+    /// Admits waiting requests while there are free slots.
+    fn dispatch(&mut self, max_running: usize) {
+        let now = Instant::now();
+        while self.running < max_running {
+            let Some(waiter) = self.pop() else {
+                return;
+            };
+            self.waiting -= 1;
+            // An expired waiter is dropped here, and fails on its own timeout.
+            if waiter.deadline.is_none_or(|deadline| deadline > now) && waiter.tx.send(()).is_ok() {
+                self.running += 1;
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Option<Waiter> {
+        self.classes.iter_mut().find_map(Class::pop)
+    }
+
+    fn remove(&mut self, priority: Priority, client: &str, id: u64) -> bool {
+        let class = &mut self.classes[priority.index()];
+        let Some(index) = class.clients.get_index_of(client) else {
+            return false;
+        };
+        let waiters = &mut class.clients[index];
+        let Some(position) = waiters.iter().position(|waiter| waiter.id == id) else {
+            return false;
+        };
+        waiters.remove(position);
+        if waiters.is_empty() {
+            class.remove_client(index);
+        }
+        self.waiting -= 1;
+        true
+    }
+}
+
+/// One priority class: a queue per client, served round-robin.
+#[derive(Default)]
+struct Class {
+    clients: IndexMap<String, VecDeque<Waiter>>,
+    /// Index of the client served next.
+    next: usize,
+}
+
+impl Class {
+    fn pop(&mut self) -> Option<Waiter> {
+        if self.clients.is_empty() {
+            return None;
+        }
+        let index = self.next % self.clients.len();
+        let waiters = &mut self.clients[index];
+        let waiter = waiters.pop_front();
+        if waiters.is_empty() {
+            // The client after this one moves into its place.
+            self.next = index;
+            self.remove_client(index);
+        } else {
+            self.next = index + 1;
+        }
+        waiter
+    }
+
+    fn remove_client(&mut self, index: usize) {
+        self.clients.shift_remove_index(index);
+        // The clients after `index` moved down by one.
+        if self.next > index {
+            self.next -= 1;
+        }
+    }
+}
+
+struct Waiter {
+    id: u64,
+    deadline: Option<Instant>,
+    tx: oneshot::Sender<()>,
+}
+
+/// A request in the queue. Leaves the queue when dropped, e.g. when the client goes away, and
+/// gives back the slot if it was admitted in the meantime.
+struct Waiting {
+    queue: Arc<ModelQueue>,
+    id: u64,
+    priority: Priority,
+    client: String,
+    rx: oneshot::Receiver<()>,
+    /// The slot now belongs to a [`Permit`].
+    admitted: bool,
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        if self.admitted {
+            return;
+        }
+        let mut state = self.queue.state.lock().unwrap();
+        if !state.remove(self.priority, &self.client, self.id) && self.rx.try_recv().is_ok() {
+            state.running -= 1;
+            state.dispatch(self.queue.max_running);
+        }
+    }
+}
+
+/// A running slot of a model, freed on drop.
+pub struct Permit {
+    queue: Arc<ModelQueue>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+/// Attaches a [`Ticket`] to the request from its API key and headers. Runs after auth, so the
+/// key is known.
+pub async fn ticket(
+    State(admission): State<Arc<Admission>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let key = request.extensions().get::<Arc<KeyBudget>>();
+    let ticket = match parse_ticket(request.headers(), key, admission.config.queue_timeout) {
+        Ok(ticket) => ticket,
+        Err(e) => return e.into_response(),
+    };
+    request.extensions_mut().insert(ticket);
+    next.run(request).await
+}
+
+fn parse_ticket(
+    headers: &HeaderMap,
+    key: Option<&Arc<KeyBudget>>,
+    queue_timeout: Option<Duration>,
+) -> Result<Ticket, ApiError> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .map(|value| value.to_str().map(str::trim))
+            .transpose()
+            .map_err(|_| ApiError::invalid_request(format!("`{name}` is not valid text.")))
+    };
+    let mut ticket = match key {
+        Some(key) => Ticket {
+            priority: key.priority,
+            client: key.name.clone(),
+            deadline: None,
+        },
+        None => Ticket::default(),
+    };
+    if let Some(value) = header(PRIORITY_HEADER)? {
+        let priority = Priority::parse(value).ok_or_else(|| {
+            ApiError::invalid_request(format!(
+                "`{PRIORITY_HEADER}` must be `low`, `normal` or `high`, got `{value}`."
+            ))
+        })?;
+        ticket.priority = match key {
+            // A key's priority is a ceiling: clients may lower it, not raise it.
+            Some(_) => ticket.priority.min(priority),
+            None => priority,
+        };
+    }
+    let timeout = match header(TIMEOUT_HEADER)? {
+        Some(value) => {
+            let secs = value
+                .parse::<f64>()
+                .ok()
+                .filter(|secs| *secs > 0.0 && secs.is_finite())
+                .ok_or_else(|| {
+                    ApiError::invalid_request(format!(
+                        "`{TIMEOUT_HEADER}` must be a number of seconds greater than 0, got `{value}`."
+                    ))
+                })?;
+            Some(Duration::from_secs_f64(secs))
+        }
+        None => queue_timeout,
+    };
+    ticket.deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    Ok(ticket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{app, app_with_admission, chat_chunk, post, FakeEngine};
+    use axum::{body::Body, http::StatusCode};
+    use futures::FutureExt as _;
+    use serde_json::json;
+
+    fn admission(max_running: usize, max_queue: usize) -> Arc<Admission> {
+        Arc::new(Admission::new(AdmissionConfig {
+            max_running,
+            max_queue,
+            queue_timeout: None,
+        }))
+    }
+
+    fn ticket(priority: Priority, client: &str) -> Ticket {
+        Ticket {
+            priority,
+            client: client.to_string(),
+            deadline: None,
+        }
+    }
+
+    /// Queues requests in order, returning the order they are admitted in as slots free up.
+    async fn admission_order(
+        admission: &Arc<Admission>,
+        tickets: Vec<(&str, Ticket)>,
+    ) -> Vec<String> {
+        let running = admission.admit("m", Ticket::default()).await.unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        for (name, ticket) in tickets {
+            let (admission, tx, name) = (admission.clone(), tx.clone(), name.to_string());
+            tokio::spawn(async move {
+                let permit = admission.admit("m", ticket).await.unwrap();
+                tx.send(name).unwrap();
+                // Hold the slot until the test has seen the order.
+                tokio::time::sleep(Duration::from_millis(5)).await;
+                drop(permit);
+            });
+            tokio::task::yield_now().await;
+        }
+        drop((tx, running));
+        let mut order = Vec::new();
+        while let Some(name) = rx.recv().await {
+            order.push(name);
+        }
+        order
+    }
+
+    #[tokio::test]
+    async fn admits_up_to_max_running() {
+        let admission = admission(2, 8);
+        let first = admission.admit("m", Ticket::default()).await.unwrap();
+        let _second = admission.admit("m", Ticket::default()).await.unwrap();
+        let mut third = Box::pin(admission.admit("m", Ticket::default()));
+        assert!((&mut third).now_or_never().is_none());
+        assert_eq!(admission.waiting(), [("m".to_string(), 1)]);
+        // Other models have their own slots.
+        let _other = admission.admit("other", Ticket::default()).await.unwrap();
+
+        drop(first);
+        third.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_when_the_queue_is_full() {
+        let admission = admission(1, 1);
+        let _running = admission.admit("m", Ticket::default()).await.unwrap();
+        let mut waiting = Box::pin(admission.admit("m", Ticket::default()));
+        assert!((&mut waiting).now_or_never().is_none());
+        let e = admission.admit("m", Ticket::default()).await.err().unwrap();
+        assert_eq!(e.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // A client that goes away frees its place.
+        drop(waiting);
+        assert_eq!(admission.waiting(), [("m".to_string(), 0)]);
+    }
+
+    #[tokio::test]
+    async fn higher_priority_goes_first() {
+        let admission = admission(1, 8);
+        let order = admission_order(
+            &admission,
+            vec![
+                ("low", ticket(Priority::Low, "a")),
+                ("normal", ticket(Priority::Normal, "a")),
+                ("high", ticket(Priority::High, "b")),
+            ],
+        )
+        .await;
+        assert_eq!(order, ["high", "normal", "low"]);
+    }
+
+    #[tokio::test]
+    async fn clients_take_turns() {
+        let admission = admission(1, 8);
+        let order = admission_order(
+            &admission,
+            vec![
+                ("batch 1", ticket(Priority::Normal, "batch")),
+                ("batch 2", ticket(Priority::Normal, "batch")),
+                ("batch 3", ticket(Priority::Normal, "batch")),
+                ("alice 1", ticket(Priority::Normal, "alice")),
+                ("bob 1", ticket(Priority::Normal, "bob")),
+                ("alice 2", ticket(Priority::Normal, "alice")),
+            ],
+        )
+        .await;
+        assert_eq!(
+            order,
+            ["batch 1", "alice 1", "bob 1", "batch 2", "alice 2", "batch 3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_requests_are_never_admitted() {
+        let admission = admission(1, 8);
+        let running = admission.admit("m", Ticket::default()).await.unwrap();
+        let expiring = Ticket {
+            deadline: Some(Instant::now() + Duration::from_millis(20)),
+            ..Ticket::default()
+        };
+        let e = admission.admit("m", expiring).await.err().unwrap();
+        assert_eq!(e.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(admission.waiting(), [("m".to_string(), 0)]);
+
+        // The slot it would have taken is still free for the next request.
+        drop(running);
+        admission.admit("m", Ticket::default()).await.unwrap();
+    }
+
+    #[test]
+    fn headers_set_the_ticket() {
+        let mut headers = HeaderMap::new();
+        headers.insert(PRIORITY_HEADER, "high".parse().unwrap());
+        headers.insert(TIMEOUT_HEADER, "2.5".parse().unwrap());
+        let ticket = parse_ticket(&headers, None, Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(ticket.priority, Priority::High);
+        assert_eq!(ticket.client, ANONYMOUS);
+        let left = ticket.deadline.unwrap() - Instant::now();
+        assert!(left <= Duration::from_millis(2500), "{left:?}");
+
+        headers.insert(PRIORITY_HEADER, "urgent".parse().unwrap());
+        assert!(parse_ticket(&headers, None, None).is_err());
+        headers.remove(PRIORITY_HEADER);
+        headers.insert(TIMEOUT_HEADER, "0".parse().unwrap());
+        assert!(parse_ticket(&headers, None, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn full_queue_answers_503() {
+        let engine = FakeEngine::hanging([vec![chat_chunk("hi", None)]]);
+        let app = app_with_admission(
+            engine.clone(),
+            AdmissionConfig {
+                max_running: 1,
+                max_queue: 0,
+                queue_timeout: None,
+            },
+        );
+        let request = json!({ "messages": "hi", "stream": true });
+        let stream = tokio::spawn(post(app.clone(), "/v1/chat/completions", request.clone()));
+        while engine.requests().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let (status, body) = post(app.clone(), "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{body}");
+        // The second request never reached the engine.
+        assert_eq!(engine.requests().len(), 1);
+        stream.abort();
+    }
+
+    #[tokio::test]
+    async fn only_generation_routes_read_the_ticket_headers() {
+        use tower::ServiceExt as _;
+
+        let app = app(FakeEngine::new([]));
+        let call = |method: &str, uri: &str, body: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(PRIORITY_HEADER, "urgent")
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let router = crate::get_router(app.clone(), None);
+            async move { router.oneshot(request).await.unwrap().status() }
+        };
+        for (uri, body) in [
+            ("/v1/chat/completions", r#"{"messages": "hi"}"#),
+            ("/v1/completions", r#"{"prompt": "hi"}"#),
+        ] {
+            assert_eq!(
+                call("POST", uri, body).await,
+                StatusCode::BAD_REQUEST,
+                "{uri}"
+            );
+        }
+        assert_eq!(call("GET", "/v1/models", "").await, StatusCode::OK);
+        assert_eq!(call("GET", "/v1/adapters", "").await, StatusCode::OK);
+        let tokenize = call("POST", "/v1/tokenize", r#"{"prompt": "hi"}"#).await;
+        assert_eq!(tokenize, StatusCode::OK);
+        let embeddings = call("POST", "/v1/embeddings", r#"{"input": "hi"}"#).await;
+        assert_eq!(embeddings, StatusCode::NOT_IMPLEMENTED);
+    }
+}
@@ -0,0 +1,401 @@
+//! Bearer API keys with per-key request and token budgets.
+//!
+//! Budgets are `leaky_bucket::RateLimiter`s. The request bucket is charged when a request comes
+//! in. Token usage is only known once the response is done, so the token bucket is checked on the
+//! way in and charged afterwards: a charge larger than the balance waits in the bucket, and until
+//! it is paid off the key gets 429s.
+
+use anyhow::Context as _;
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use leaky_bucket::RateLimiter;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{admission::Priority, error::ApiError};
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    default: FileBudgets,
+    keys: Vec<FileKey>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileBudgets {
+    requests: Option<BucketConfig>,
+    tokens: Option<BucketConfig>,
+    priority: Option<Priority>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileKey {
+    name: String,
+    key: String,
+    requests: Option<BucketConfig>,
+    tokens: Option<BucketConfig>,
+    priority: Option<Priority>,
+    #[serde(default)]
+    admin: bool,
+}
+
+/// Parameters of one `RateLimiter`, named after its builder methods.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BucketConfig {
+    max: usize,
+    refill: usize,
+    interval_ms: u64,
+}
+
+impl BucketConfig {
+    fn validate(&self, key: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.max > 0 && self.refill > 0 && self.interval_ms > 0,
+            "Key `{key}`: `max`, `refill` and `interval_ms` must be greater than 0."
+        );
+        anyhow::ensure!(
+            self.refill <= self.max,
+            "Key `{key}`: `refill` ({}) cannot be greater than `max` ({}).",
+            self.refill,
+            self.max
+        );
+        Ok(())
+    }
+
+    fn build(&self) -> RateLimiter {
+        RateLimiter::builder()
+            .initial(self.max)
+            .interval(Duration::from_millis(self.interval_ms))
+            .refill(self.refill)
+            .max(self.max)
+            .build()
+    }
+}
+
+/// Keys accepted by the server, loaded from the keys file.
+pub struct ApiKeys {
+    keys: HashMap<String, Arc<KeyBudget>>,
+}
+
+impl ApiKeys {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read API keys file `{}`.", path.display()))?;
+        raw.parse()
+            .with_context(|| format!("Invalid API keys file `{}`.", path.display()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn get(&self, key: &str) -> Option<Arc<KeyBudget>> {
+        self.keys.get(key).cloned()
+    }
+}
+
+impl FromStr for ApiKeys {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> anyhow::Result<Self> {
+        let file = toml::from_str::<KeysFile>(raw)?;
+        anyhow::ensure!(!file.keys.is_empty(), "There are no keys.");
+
+        let mut keys = HashMap::new();
+        for key in file.keys {
+            anyhow::ensure!(!key.key.is_empty(), "Key `{}` is empty.", key.name);
+            let requests = key.requests.or(file.default.requests);
+            let tokens = key.tokens.or(file.default.tokens);
+            for bucket in requests.iter().chain(&tokens) {
+                bucket.validate(&key.name)?;
+            }
+            let budget = KeyBudget {
+                requests: requests.as_ref().map(BucketConfig::build),
+                tokens: tokens.as_ref().map(BucketConfig::build),
+                tokens_owed: AtomicUsize::new(0),
+                priority: key.priority.or(file.default.priority).unwrap_or_default(),
+                admin: key.admin,
+                name: key.name,
+            };
+            if let Some(other) = keys.insert(key.key, Arc::new(budget)) {
+                anyhow::bail!("Key `{}` has the same value as another key.", other.name);
+            }
+        }
+        Ok(Self { keys })
+    }
+}
+
+/// Budgets of one API key. Handlers find it in the request extensions.
+pub struct KeyBudget {
+    pub name: String,
+    requests: Option<RateLimiter>,
+    tokens: Option<RateLimiter>,
+    /// Tokens charged but not yet paid off.
+    tokens_owed: AtomicUsize,
+    /// Highest admission priority of the key's requests.
+    pub priority: Priority,
+    /// May change what the server offers to every key, e.g. take adapters out of service.
+    pub admin: bool,
+}
+
+impl KeyBudget {
+    /// Takes one request and one token, or says how long to wait.
+    fn admit(&self) -> Result<(), ApiError> {
+        if let Some(requests) = &self.requests {
+            if !requests.try_acquire(1) {
+                return Err(self.rate_limited("requests", requests.interval()));
+            }
+        }
+        if let Some(tokens) = &self.tokens {
+            // Earlier charges still waiting in the bucket have to be paid off first.
+            let owed = self.tokens_owed.load(Ordering::Relaxed);
+            if owed > 0 || !tokens.try_acquire(1) {
+                let intervals = owed.div_ceil(tokens.refill()).max(1) as u32;
+                return Err(self.rate_limited("tokens", tokens.interval() * intervals));
+            }
+        }
+        Ok(())
+    }
+
+    fn rate_limited(&self, what: &str, retry_after: Duration) -> ApiError {
+        ApiError::RateLimited {
+            message: format!("Rate limit reached for {what} on API key `{}`.", self.name),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    /// Charges the prompt and completion tokens of a finished request. The token taken by
+    /// `admit` counts towards it.
+    pub fn charge(self: &Arc<Self>, tokens: usize) {
+        let tokens = tokens.saturating_sub(1);
+        let Some(bucket) = &self.tokens else {
+            return;
+        };
+        if tokens == 0 || bucket.try_acquire(tokens) {
+            return;
+        }
+        self.tokens_owed.fetch_add(tokens, Ordering::Relaxed);
+        let this = self.clone();
+        tokio::spawn(async move {
+            if let Some(bucket) = &this.tokens {
+                bucket.acquire(tokens).await;
+            }
+            this.tokens_owed.fetch_sub(tokens, Ordering::Relaxed);
+        });
+    }
+}
+
+/// Rejects requests without a known `Authorization: Bearer` key with 401, and over-budget keys
+/// with 429. Admitted requests carry their key's [`KeyBudget`] as an extension.
+pub async fn require_api_key(
+    State(keys): State<Arc<ApiKeys>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let budget = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|key| keys.get(key.trim()));
+    let Some(budget) = budget else {
+        return ApiError::Unauthorized(
+            "Missing or invalid API key. Send `Authorization: Bearer <key>`.".to_string(),
+        )
+        .into_response();
+    };
+    if let Err(e) = budget.admit() {
+        return e.into_response();
+    }
+    request.extensions_mut().insert(budget);
+    next.run(request).await
+}
+
+/// Rejects requests without an admin key with 403. Runs after [`require_api_key`]; a server
+/// without API keys has no admin, so it rejects every request.
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    let message = match request.extensions().get::<Arc<KeyBudget>>() {
+        Some(key) if key.admin => return next.run(request).await,
+        Some(key) => format!("API key `{}` is not an admin key.", key.name),
+        None => "This needs an admin API key, and the server has no API keys.".to_string(),
+    };
+    ApiError::Forbidden(message).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        engine::fake::{app, chat_chunk, chat_done, FakeEngine},
+        state::AppState,
+    };
+    use axum::{
+        body::Body,
+        http::{HeaderMap, Request as HttpRequest, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt as _;
+
+    const KEYS: &str = r#"
+        [[keys]]
+        name = "alice"
+        key = "sk-alice"
+        [keys.requests]
+        max = 1
+        refill = 1
+        interval_ms = 2000
+
+        [[keys]]
+        name = "bob"
+        key = "sk-bob"
+        [keys.tokens]
+        max = 10
+        refill = 1
+        interval_ms = 1000
+    "#;
+
+    fn request(stream: bool) -> Value {
+        json!({ "messages": [{ "role": "user", "content": "hi" }], "stream": stream })
+    }
+
+    /// Posts a chat request as `key` through the full router with `keys` required.
+    async fn chat(
+        app: &Arc<AppState>,
+        keys: &Arc<ApiKeys>,
+        key: Option<&str>,
+        body: Value,
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut request = HttpRequest::post("/v1/chat/completions")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(key) = key {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {key}"));
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = crate::get_router(app.clone(), Some(keys.clone()))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (
+            parts.status,
+            parts.headers,
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[test]
+    fn rejects_invalid_keys_files() {
+        for (raw, error) in [
+            ("keys = []", "no keys"),
+            (
+                r#"[[keys]]
+                name = "a"
+                key = """#,
+                "is empty",
+            ),
+            (
+                r#"[[keys]]
+                name = "a"
+                key = "k"
+                [[keys]]
+                name = "b"
+                key = "k""#,
+                "same value",
+            ),
+            (
+                r#"[[keys]]
+                name = "a"
+                key = "k"
+                [keys.tokens]
+                max = 1
+                refill = 2
+                interval_ms = 1"#,
+                "cannot be greater",
+            ),
+        ] {
+            let e = raw.parse::<ApiKeys>().err().unwrap();
+            assert!(e.to_string().contains(error), "{e}");
+        }
+        assert_eq!(KEYS.parse::<ApiKeys>().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_and_unknown_keys() {
+        let app = app(FakeEngine::new([]));
+        let keys = Arc::new(KEYS.parse::<ApiKeys>().unwrap());
+        for key in [None, Some("sk-mallory")] {
+            let (status, _, body) = chat(&app, &keys, key, request(false)).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert!(body.contains("Missing or invalid API key"), "{body}");
+        }
+    }
+
+    #[tokio::test]
+    async fn over_the_request_budget_gets_429_with_retry_after() {
+        let engine = FakeEngine::new([vec![mistralrs::Response::Done(chat_done("hi", "stop"))]]);
+        let app = app(engine);
+        let keys = Arc::new(KEYS.parse::<ApiKeys>().unwrap());
+        let (status, _, _) = chat(&app, &keys, Some("sk-alice"), request(false)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, headers, body) = chat(&app, &keys, Some("sk-alice"), request(false)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[header::RETRY_AFTER], "2");
+        assert!(body.contains("requests on API key `alice`"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn streams_are_charged_their_prompt_tokens() {
+        let engine = FakeEngine::new([vec![chat_chunk("hi", Some("stop"))]]);
+        let app = app(engine);
+        let keys = Arc::new(KEYS.parse::<ApiKeys>().unwrap());
+        let (status, _, _) = chat(&app, &keys, Some("sk-bob"), request(true)).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // The charge lands once the prompt count is in, after the stream ends.
+        let bob = keys.get("sk-bob").unwrap();
+        for _ in 0..100 {
+            if bob.tokens_owed.load(Ordering::Relaxed) > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        // 24 prompt characters of `<|user|>hi\n<|assistant|>` and 1 streamed token, less the
+        // token taken on admission, owed one per second.
+        let (status, headers, body) = chat(&app, &keys, Some("sk-bob"), request(true)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[header::RETRY_AFTER], "24");
+        assert!(body.contains("tokens on API key `bob`"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn output_rejected_by_response_format_is_not_charged() {
+        let done = mistralrs::Response::Done(chat_done("not json", "stop"));
+        let app = app(FakeEngine::new([vec![done]]));
+        let keys = Arc::new(KEYS.parse::<ApiKeys>().unwrap());
+        let mut request = request(false);
+        request["response_format"] = json!({ "type": "json_object" });
+        let (status, _, _) = chat(&app, &keys, Some("sk-bob"), request).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        // Only the token taken on admission is gone.
+        let bob = keys.get("sk-bob").unwrap();
+        assert_eq!(bob.tokens.as_ref().unwrap().balance(), 9);
+    }
+}
@@ -0,0 +1,327 @@
+//! `isq batch`: runs chat completion requests from a JSONL file and writes the results to
+//! another, without starting the HTTP server.
+//!
+//! Each input line is a `ChatCompletionRequest` and gives exactly one output line,
+//! `{"line", "status", "response"}`, where `response` is the body the HTTP endpoint would have
+//! returned. Requests are run like `/v1/chat/completions`, always without streaming, at most
+//! `max_num_seqs` at a time. Output lines are written in input order, so an interrupted run is
+//! resumed by running it again: complete output lines are kept, a cut-off last line is dropped,
+//! and the input continues after the last line written.
+//!
+//! Requests turned away for now (429 or 503, e.g. a full queue or a shutdown) are retried a few
+//! times. They never become output lines: if retrying does not help, the run stops before the
+//! line, and resuming runs it again.
+
+use anyhow::Context as _;
+use futures::{stream, StreamExt as _};
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, BufWriter, Seek as _, Write as _},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    admission::Ticket, chat_completion, config::BatchArgs, error::ApiError,
+    openai::ChatCompletionRequest, state::AppState,
+};
+
+/// Lines between progress messages.
+const PROGRESS_EVERY: usize = 100;
+/// Wait before the first retry of a request turned away, doubled for each further one.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+pub async fn run(app: Arc<AppState>, args: &BatchArgs, concurrency: usize) -> anyhow::Result<()> {
+    let mut input = File::open(&args.input)
+        .with_context(|| format!("Could not open batch input `{}`.", args.input.display()))?;
+    let (output, done) = open_output(&args.output)?;
+    if done > 0 {
+        let total = BufReader::new(&input).lines().count();
+        anyhow::ensure!(
+            total >= done,
+            "The output has {done} lines but the input only {total}. Is it the right output file?"
+        );
+        input.rewind()?;
+        println!(
+            "Resuming after {done} lines already in `{}`.",
+            args.output.display()
+        );
+    }
+    let mut output = BufWriter::new(output);
+
+    let lines = BufReader::new(input).lines().enumerate().skip(done);
+    let retries = args.retries;
+    let mut results = stream::iter(lines)
+        .map(|(i, line)| {
+            let app = app.clone();
+            async move {
+                let line = line.with_context(|| format!("Could not read input line {}.", i + 1))?;
+                anyhow::Ok((i, run_line(&app, &line, retries).await))
+            }
+        })
+        .buffered(concurrency);
+
+    let (mut written, mut failed) = (done, 0);
+    while let Some(result) = results.next().await {
+        let (i, (status, response)) = result?;
+        if turned_away(status) {
+            // Lines after this one are dropped with `results`, resuming runs them again.
+            anyhow::bail!(
+                "Input line {} was still turned away after {retries} retries, with status \
+                 {status}: {}. {written} lines are done, run again to resume.",
+                i + 1,
+                response["error"]["message"].as_str().unwrap_or_default()
+            );
+        }
+        let line = json!({ "line": i, "status": status, "response": response });
+        writeln!(output, "{line}")?;
+        // Flushed line by line, so a resume loses at most the line being written.
+        output.flush()?;
+        written += 1;
+        if status != 200 {
+            failed += 1;
+        }
+        if written % PROGRESS_EVERY == 0 {
+            println!("{written} lines done, {failed} failed in this run.");
+        }
+    }
+    println!(
+        "Batch done: {written} lines in `{}`, {failed} failed in this run.",
+        args.output.display()
+    );
+    Ok(())
+}
+
+/// Runs one input line, returning the status and body of the response. Retries it up to
+/// `retries` times while it is turned away.
+async fn run_line(app: &AppState, line: &str, retries: u32) -> (u16, Value) {
+    let mut delay = RETRY_DELAY;
+    for _ in 0..retries {
+        let (status, response) = run_once(app, line).await;
+        if !turned_away(status) {
+            return (status, response);
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+    run_once(app, line).await
+}
+
+/// Whether a request failed only for now, because the server was busy or shutting down.
+fn turned_away(status: u16) -> bool {
+    status == 429 || status == 503
+}
+
+async fn run_once(app: &AppState, line: &str) -> (u16, Value) {
+    let mut request = match serde_json::from_str::<ChatCompletionRequest>(line) {
+        Ok(request) => request,
+        Err(e) => {
+            let e = ApiError::invalid_request(format!("Invalid request: {e}"));
+            return (
+                e.status().as_u16(),
+                serde_json::to_value(e.body()).unwrap_or_default(),
+            );
+        }
+    };
+    request.stream = Some(false);
+    let responder =
+        chat_completion::respond(app, None, None, Ticket::default(), &mut None, request).await;
+    let (status, response, _) = responder
+        .finished()
+        .expect("Batch requests are not streamed.");
+    (status, response)
+}
+
+/// Opens the output for appending, returning how many complete lines it already has. A last
+/// line without a newline was cut off and is removed.
+fn open_output(path: &Path) -> anyhow::Result<(File, usize)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open batch output `{}`.", path.display()))?;
+
+    let mut reader = BufReader::new(&file);
+    let (mut lines, mut complete_len, mut last) = (0, 0, Vec::new());
+    loop {
+        let mut line = Vec::new();
+        let n = reader.read_until(b'\n', &mut line)?;
+        if n == 0 || line.last() != Some(&b'\n') {
+            break;
+        }
+        lines += 1;
+        complete_len += n as u64;
+        last = line;
+    }
+    if lines > 0 {
+        // Output lines are numbered, so check that this output belongs to a run like this one.
+        let number = serde_json::from_slice::<Value>(&last)
+            .ok()
+            .and_then(|line| line["line"].as_u64());
+        anyhow::ensure!(
+            number == Some(lines as u64 - 1),
+            "`{}` does not look like the output of a batch run: line {lines} is not result {}.",
+            path.display(),
+            lines - 1
+        );
+    }
+    if file.metadata()?.len() > complete_len {
+        file.set_len(complete_len)?;
+    }
+    Ok((file, lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{app, chat_done, FakeEngine, TempDir};
+    use mistralrs::Response;
+
+    fn args(dir: &TempDir) -> BatchArgs {
+        BatchArgs {
+            input: dir.0.join("input.jsonl"),
+            output: dir.0.join("output.jsonl"),
+            retries: 0,
+        }
+    }
+
+    fn write_input(args: &BatchArgs, contents: &[&str]) {
+        let lines = contents
+            .iter()
+            .map(|content| {
+                json!({ "messages": [{ "role": "user", "content": content }] }).to_string() + "\n"
+            })
+            .collect::<String>();
+        std::fs::write(&args.input, lines).unwrap();
+    }
+
+    fn done(content: &str) -> Vec<Response> {
+        vec![Response::Done(chat_done(content, "stop"))]
+    }
+
+    fn read_output(args: &BatchArgs) -> Vec<Value> {
+        std::fs::read_to_string(&args.output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    /// `(line, status, content)` of each output line.
+    fn results(args: &BatchArgs) -> Vec<(u64, u64, String)> {
+        read_output(args)
+            .iter()
+            .map(|line| {
+                let content = &line["response"]["choices"][0]["message"]["content"];
+                (
+                    line["line"].as_u64().unwrap(),
+                    line["status"].as_u64().unwrap(),
+                    content.as_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn writes_one_line_per_input_line_in_order() {
+        let dir = TempDir::new("batch-order");
+        let args = args(&dir);
+        write_input(&args, &["a", "b", "c"]);
+        // Not a request: answered with a 400 line without reaching the engine.
+        let mut input = std::fs::read_to_string(&args.input).unwrap();
+        input.insert_str(0, "{\"messages\": 1}\n");
+        std::fs::write(&args.input, input).unwrap();
+
+        let engine = FakeEngine::new([done("A"), done("B"), done("C")]);
+        run(app(engine.clone()), &args, 3).await.unwrap();
+        assert_eq!(
+            results(&args),
+            [
+                (0, 400, String::new()),
+                (1, 200, "A".to_string()),
+                (2, 200, "B".to_string()),
+                (3, 200, "C".to_string()),
+            ]
+        );
+        assert_eq!(engine.requests().len(), 3);
+        assert!(engine
+            .requests()
+            .iter()
+            .all(|request| !request.is_streaming));
+    }
+
+    #[tokio::test]
+    async fn resumes_after_the_complete_lines() {
+        let dir = TempDir::new("batch-resume");
+        let args = args(&dir);
+        write_input(&args, &["a", "b", "c"]);
+        let first = json!({ "line": 0, "status": 200, "response": {} }).to_string();
+        // The second line was cut off while being written.
+        std::fs::write(&args.output, format!("{first}\n{{\"line\": 1, \"sta")).unwrap();
+
+        let engine = FakeEngine::new([done("B"), done("C")]);
+        run(app(engine.clone()), &args, 2).await.unwrap();
+        let output = std::fs::read_to_string(&args.output).unwrap();
+        assert!(
+            output.starts_with(&format!("{first}\n{{\"line\":1,")),
+            "{output}"
+        );
+        assert_eq!(
+            results(&args)[1..],
+            [(1, 200, "B".to_string()), (2, 200, "C".to_string())]
+        );
+        assert_eq!(engine.requests().len(), 2);
+
+        // Nothing left to do.
+        run(app(FakeEngine::new([])), &args, 2).await.unwrap();
+        assert_eq!(results(&args).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_output_from_another_run() {
+        let dir = TempDir::new("batch-foreign");
+        let args = args(&dir);
+        write_input(&args, &["a"]);
+        std::fs::write(&args.output, "{\"line\": 5}\n").unwrap();
+        let e = run(app(FakeEngine::new([])), &args, 1).await.unwrap_err();
+        assert!(
+            e.to_string().contains("does not look like the output"),
+            "{e}"
+        );
+
+        std::fs::write(&args.output, "{\"line\": 0}\n{\"line\": 1}\n").unwrap();
+        let e = run(app(FakeEngine::new([])), &args, 1).await.unwrap_err();
+        assert!(e.to_string().contains("the input only 1"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn stops_before_a_line_turned_away_and_resumes_from_it() {
+        let dir = TempDir::new("batch-busy");
+        let args = args(&dir);
+        write_input(&args, &["a", "b", "c"]);
+        // With its script used up, the engine's queue is full for the second line.
+        let e = run(app(FakeEngine::new([done("A")])), &args, 1)
+            .await
+            .unwrap_err();
+        assert!(
+            e.to_string().contains("Input line 2 was still turned away"),
+            "{e}"
+        );
+        assert_eq!(results(&args), [(0, 200, "A".to_string())]);
+
+        run(app(FakeEngine::new([done("B"), done("C")])), &args, 2)
+            .await
+            .unwrap();
+        assert_eq!(
+            results(&args),
+            [
+                (0, 200, "A".to_string()),
+                (1, 200, "B".to_string()),
+                (2, 200, "C".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! `isq bench`: loads the first configured model once per ISQ type and PagedAttention setting
+//! and measures it for each prompt length and batch size.
+//!
+//! Every combination runs `warmup` unmeasured iterations, then `iterations` measured ones. An
+//! iteration sends `batch_size` streaming completion requests at once. Latency and time to first
+//! token are taken per request, throughput per iteration: prompt tokens of the whole batch over
+//! the time until every request had its first token, and generated tokens over the time until
+//! the last request finished.
+//!
+//! Every iteration sends the same prompt, so the prefix cache is turned off: otherwise every
+//! measured iteration after the first would skip the prompt processing it is meant to time.
+
+use anyhow::Context as _;
+use futures::future::try_join_all;
+use mistralrs::{
+    Constraint, IsqType, MistralRs, NormalRequest, Request, RequestMessage, Response,
+    SamplingParams,
+};
+use serde::Serialize;
+use std::{
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::channel;
+
+use crate::config::{BenchArgs, LoaderKind, ServerConfig, Toggle};
+
+/// Word repeated to build prompts, one token for common tokenizers.
+const PROMPT_WORD: &str = " hello";
+
+/// One line of the report.
+#[derive(Debug, Serialize)]
+struct BenchResult {
+    isq: String,
+    paged_attn: bool,
+    prompt_len: usize,
+    /// Prompt tokens as counted by the model.
+    prompt_tokens: usize,
+    batch_size: usize,
+    iterations: usize,
+    /// Mean generated tokens per request.
+    completion_tokens: f64,
+    latency_mean_ms: f64,
+    latency_p50_ms: f64,
+    latency_p95_ms: f64,
+    ttft_mean_ms: f64,
+    ttft_p50_ms: f64,
+    ttft_p95_ms: f64,
+    prompt_tok_per_sec: f64,
+    completion_tok_per_sec: f64,
+}
+
+const CSV_HEADER: &str = "isq,paged_attn,prompt_len,prompt_tokens,batch_size,iterations,\
+completion_tokens,latency_mean_ms,latency_p50_ms,latency_p95_ms,ttft_mean_ms,ttft_p50_ms,\
+ttft_p95_ms,prompt_tok_per_sec,completion_tok_per_sec";
+
+impl BenchResult {
+    fn csv_row(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1}",
+            self.isq,
+            self.paged_attn,
+            self.prompt_len,
+            self.prompt_tokens,
+            self.batch_size,
+            self.iterations,
+            self.completion_tokens,
+            self.latency_mean_ms,
+            self.latency_p50_ms,
+            self.latency_p95_ms,
+            self.ttft_mean_ms,
+            self.ttft_p50_ms,
+            self.ttft_p95_ms,
+            self.prompt_tok_per_sec,
+            self.completion_tok_per_sec,
+        )
+    }
+}
+
+/// Timings of one streamed request.
+struct RequestTiming {
+    ttft: Duration,
+    latency: Duration,
+    completion_tokens: usize,
+}
+
+pub async fn run(config: &ServerConfig, args: &BenchArgs) -> anyhow::Result<()> {
+    let model = &config.models[0];
+    if config.models.len() > 1 {
+        eprintln!(
+            "Warning: only the first model, `{}`, is benchmarked.",
+            model.name
+        );
+    }
+    let isq_types = if args.isq.is_empty() {
+        vec![model.isq]
+    } else {
+        args.isq.clone()
+    };
+    anyhow::ensure!(
+        model.loader == LoaderKind::Normal || isq_types.iter().all(Option::is_none),
+        "ISQ cannot be applied to a GGUF model, benchmark it with `--isq-types none`."
+    );
+    let paged_attn = if args.paged_attn.is_empty() {
+        vec![config.paged_attn.is_some()]
+    } else {
+        args.paged_attn.iter().map(|t| *t == Toggle::On).collect()
+    };
+    // When PagedAttention is off in the config, `on` uses the default settings.
+    let paged_attn_config = config.paged_attn.unwrap_or_default();
+    anyhow::ensure!(
+        !args.prompt_lens.contains(&0) && !args.batch_sizes.contains(&0),
+        "Prompt lengths and batch sizes must be at least 1."
+    );
+    anyhow::ensure!(
+        args.iterations > 0 && args.max_tokens > 0,
+        "`--iterations` and `--max-tokens` must be at least 1."
+    );
+    if paged_attn.contains(&true) {
+        let device = common::device::select(model.device, config.cpu_threads)?;
+        anyhow::ensure!(
+            device.supports_paged_attn(paged_attn_config.gpu_mem),
+            "PagedAttention is not available on `{}`, benchmark it with `--paged-attn off`.",
+            device.spec
+        );
+    }
+
+    let mut results = Vec::new();
+    for isq in &isq_types {
+        for &paged in &paged_attn {
+            let mut config = config.clone();
+            config.paged_attn = paged.then_some(paged_attn_config);
+            config.prefix_cache_n = Some(0);
+            let mut model = model.clone();
+            model.isq = *isq;
+            let (engine, _) = crate::setup(&config, &model).await?;
+            let mistralrs = engine.mistralrs;
+            for &prompt_len in &args.prompt_lens {
+                for &batch_size in &args.batch_sizes {
+                    let result = bench_one(
+                        &mistralrs,
+                        args,
+                        isq_name(*isq),
+                        paged,
+                        prompt_len,
+                        batch_size,
+                    )
+                    .await?;
+                    println!(
+                        "Latency p50 {:.1} ms, TTFT p50 {:.1} ms, {:.1} prompt tok/s, {:.1} completion tok/s.",
+                        result.latency_p50_ms,
+                        result.ttft_p50_ms,
+                        result.prompt_tok_per_sec,
+                        result.completion_tok_per_sec
+                    );
+                    results.push(result);
+                }
+            }
+            // Stop the engine so the next combination has the memory to itself.
+            mistralrs.get_sender()?.send(Request::Terminate).await?;
+        }
+    }
+
+    let json = args
+        .output
+        .as_ref()
+        .is_some_and(|path| path.extension().is_some_and(|ext| ext == "json"));
+    let report = report(&results, json)?;
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, report)
+                .with_context(|| format!("Could not write `{}`.", path.display()))?;
+            println!("Report written to `{}`.", path.display());
+        }
+        None => print!("{report}"),
+    }
+    Ok(())
+}
+
+/// The report as a JSON array, or as CSV with a header line.
+fn report(results: &[BenchResult], json: bool) -> serde_json::Result<String> {
+    if json {
+        return serde_json::to_string_pretty(results);
+    }
+    Ok(results
+        .iter()
+        .fold(format!("{CSV_HEADER}\n"), |mut csv, r| {
+            let _ = writeln!(csv, "{}", r.csv_row());
+            csv
+        }))
+}
+
+fn isq_name(isq: Option<IsqType>) -> String {
+    isq.map_or_else(|| "none".to_string(), |isq| format!("{isq:?}"))
+}
+
+async fn bench_one(
+    mistralrs: &MistralRs,
+    args: &BenchArgs,
+    isq: String,
+    paged_attn: bool,
+    prompt_len: usize,
+    batch_size: usize,
+) -> anyhow::Result<BenchResult> {
+    let prompt = PROMPT_WORD.repeat(prompt_len);
+    println!(
+        "Benchmarking isq={isq} paged_attn={paged_attn} prompt_len={prompt_len} batch_size={batch_size}."
+    );
+    for _ in 0..args.warmup {
+        iteration(mistralrs, &prompt, args.max_tokens, batch_size).await?;
+    }
+    let prompt_tokens = count_prompt_tokens(mistralrs, &prompt).await?;
+
+    let (mut ttfts, mut latencies) = (Vec::new(), Vec::new());
+    let (mut prompt_tok_per_sec, mut completion_tok_per_sec) = (0.0, 0.0);
+    let mut completion_tokens = 0;
+    for _ in 0..args.iterations {
+        let timings = iteration(mistralrs, &prompt, args.max_tokens, batch_size).await?;
+        let prefill = timings.iter().map(|t| t.ttft).max().unwrap_or_default();
+        let total = timings.iter().map(|t| t.latency).max().unwrap_or_default();
+        let generated = timings.iter().map(|t| t.completion_tokens).sum::<usize>();
+        prompt_tok_per_sec += (prompt_tokens * batch_size) as f64 / prefill.as_secs_f64();
+        completion_tok_per_sec += generated as f64 / total.as_secs_f64();
+        completion_tokens += generated;
+        ttfts.extend(timings.iter().map(|t| t.ttft));
+        latencies.extend(timings.iter().map(|t| t.latency));
+    }
+    let iterations = args.iterations as f64;
+    let (latency_mean_ms, latency_p50_ms, latency_p95_ms) = stats_ms(&mut latencies);
+    let (ttft_mean_ms, ttft_p50_ms, ttft_p95_ms) = stats_ms(&mut ttfts);
+    Ok(BenchResult {
+        isq,
+        paged_attn,
+        prompt_len,
+        prompt_tokens,
+        batch_size,
+        iterations: args.iterations,
+        completion_tokens: completion_tokens as f64 / latencies.len() as f64,
+        latency_mean_ms,
+        latency_p50_ms,
+        latency_p95_ms,
+        ttft_mean_ms,
+        ttft_p50_ms,
+        ttft_p95_ms,
+        prompt_tok_per_sec: prompt_tok_per_sec / iterations,
+        completion_tok_per_sec: completion_tok_per_sec / iterations,
+    })
+}
+
+/// Mean, median and 95th percentile (nearest rank) in milliseconds.
+fn stats_ms(durations: &mut [Duration]) -> (f64, f64, f64) {
+    durations.sort();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let percentile = |p: f64| {
+        let rank = (p * durations.len() as f64).ceil() as usize;
+        ms(durations[rank.clamp(1, durations.len()) - 1])
+    };
+    let mean = durations.iter().copied().map(ms).sum::<f64>() / durations.len() as f64;
+    (mean, percentile(0.5), percentile(0.95))
+}
+
+fn completion_request(
+    id: usize,
+    prompt: &str,
+    max_tokens: usize,
+    streaming: bool,
+) -> (Request, tokio::sync::mpsc::Receiver<Response>) {
+    let (tx, rx) = channel(10_000);
+    let request = Request::Normal(NormalRequest {
+        messages: RequestMessage::Completion {
+            text: prompt.to_string(),
+            echo_prompt: false,
+            best_of: 1,
+        },
+        sampling_params: SamplingParams {
+            max_len: Some(max_tokens),
+            ..SamplingParams::default()
+        },
+        response: tx,
+        return_logprobs: false,
+        is_streaming: streaming,
+        id,
+        constraint: Constraint::None,
+        suffix: None,
+        adapters: None,
+        tools: None,
+        tool_choice: None,
+        logits_processors: None,
+    });
+    (request, rx)
+}
+
+/// Sends `batch_size` streaming requests at once and times each of them.
+async fn iteration(
+    mistralrs: &MistralRs,
+    prompt: &str,
+    max_tokens: usize,
+    batch_size: usize,
+) -> anyhow::Result<Vec<RequestTiming>> {
+    let sender = mistralrs.get_sender()?;
+    let requests = (0..batch_size).map(|id| {
+        let sender = sender.clone();
+        async move {
+            let (request, mut rx) = completion_request(id, prompt, max_tokens, true);
+            let start = Instant::now();
+            sender.send(request).await?;
+            let mut ttft = None;
+            let mut completion_tokens = 0;
+            loop {
+                let response = rx
+                    .recv()
+                    .await
+                    .context("The model stopped without finishing a request.")?;
+                let chunk = match response {
+                    Response::CompletionChunk(chunk) => chunk,
+                    Response::CompletionModelError(e, _) => anyhow::bail!("Model error: {e}"),
+                    Response::ValidationError(e) => anyhow::bail!("Invalid request: {e}"),
+                    Response::InternalError(e) => anyhow::bail!("Internal error: {e}"),
+                    _ => anyhow::bail!("Unexpected response to a streaming completion request."),
+                };
+                let Some(choice) = chunk.choices.into_iter().next() else {
+                    continue;
+                };
+                ttft.get_or_insert_with(|| start.elapsed());
+                // Streams send one chunk per generated token.
+                completion_tokens += 1;
+                if choice.finish_reason.is_some() {
+                    break;
+                }
+            }
+            let latency = start.elapsed();
+            anyhow::Ok(RequestTiming {
+                ttft: ttft.unwrap_or(latency),
+                latency,
+                completion_tokens,
+            })
+        }
+    });
+    try_join_all(requests).await
+}
+
+/// Prompt length in tokens, from the usage of a non-streaming request.
+async fn count_prompt_tokens(mistralrs: &MistralRs, prompt: &str) -> anyhow::Result<usize> {
+    let (request, mut rx) = completion_request(0, prompt, 1, false);
+    mistralrs.get_sender()?.send(request).await?;
+    match rx
+        .recv()
+        .await
+        .context("The model stopped without answering.")?
+    {
+        Response::CompletionDone(response) => Ok(response.usage.prompt_tokens),
+        Response::CompletionModelError(e, _) => anyhow::bail!("Model error: {e}"),
+        Response::ValidationError(e) => anyhow::bail!("Invalid request: {e}"),
+        Response::InternalError(e) => anyhow::bail!("Internal error: {e}"),
+        _ => anyhow::bail!("Unexpected response to a completion request."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(isq: &str, batch_size: usize) -> BenchResult {
+        BenchResult {
+            isq: isq.to_string(),
+            paged_attn: false,
+            prompt_len: 128,
+            prompt_tokens: 129,
+            batch_size,
+            iterations: 5,
+            completion_tokens: 64.0,
+            latency_mean_ms: 1234.56,
+            latency_p50_ms: 1200.0,
+            latency_p95_ms: 1500.04,
+            ttft_mean_ms: 80.25,
+            ttft_p50_ms: 75.0,
+            ttft_p95_ms: 110.0,
+            prompt_tok_per_sec: 1612.5,
+            completion_tok_per_sec: 51.84,
+        }
+    }
+
+    #[test]
+    fn stats_are_in_milliseconds_with_nearest_rank_percentiles() {
+        let mut durations = (1..=20)
+            .rev()
+            .map(Duration::from_millis)
+            .collect::<Vec<_>>();
+        let (mean, p50, p95) = stats_ms(&mut durations);
+        assert!((mean - 10.5).abs() < 1e-9);
+        assert_eq!((p50, p95), (10.0, 19.0));
+
+        let mut one = [Duration::from_micros(2500)];
+        assert_eq!(stats_ms(&mut one), (2.5, 2.5, 2.5));
+
+        let mut two = [Duration::from_millis(30), Duration::from_millis(10)];
+        assert_eq!(stats_ms(&mut two), (20.0, 10.0, 30.0));
+    }
+
+    #[test]
+    fn csv_report_has_a_header_and_a_row_per_result() {
+        let csv = report(&[result("Q4K", 1), result("none", 8)], false).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "Q4K,false,128,129,1,5,64.0,1234.6,1200.0,1500.0,80.2,75.0,110.0,1612.5,51.8"
+        );
+        assert!(lines[2].starts_with("none,false,128,129,8,"));
+        // Every row has a value for every column.
+        let columns = CSV_HEADER.split(',').count();
+        assert!(lines.iter().all(|l| l.split(',').count() == columns));
+
+        assert_eq!(report(&[], false).unwrap(), format!("{CSV_HEADER}\n"));
+    }
+
+    #[test]
+    fn json_report_has_the_csv_columns() {
+        let json = report(&[result("Q4K", 1)], true).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let row = json.as_array().unwrap()[0].as_object().unwrap();
+        assert_eq!(
+            row.keys().map(String::as_str).collect::<Vec<_>>(),
+            CSV_HEADER.split(',').collect::<Vec<_>>()
+        );
+        assert_eq!(row["isq"], "Q4K");
+        assert_eq!(row["prompt_tokens"], 129);
+        assert_eq!(row["latency_mean_ms"], 1234.56);
+    }
+}
@@ -0,0 +1,703 @@
+use anyhow::{Context as _, Result};
+use axum::{
+    extract::{Extension, Json, State},
+    response::{IntoResponse, Sse},
+};
+use either::Either;
+use image::DynamicImage;
+use indexmap::IndexMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Sender};
+
+use mistralrs::{
+    ChatCompletionResponse, Constraint, DrySamplingParams, NormalRequest, Request, RequestMessage,
+    Response, SamplingParams, StopTokens as InternalStopTokens,
+};
+
+use crate::{
+    adapters,
+    admission::Ticket,
+    auth::KeyBudget,
+    config::LimitsConfig,
+    engine::{Engine, LoggedResponse},
+    error::{ApiError, JsonModelError, ModelErrorMessage, OaiJson},
+    journal::JournalRecord,
+    json_schema::OutputCheck,
+    metrics::RequestMetrics,
+    openai::{
+        ChatCompletionRequest, ContentPart, Grammar, ImageDetail, ImageUrl, Message,
+        MessageContent, StopTokens,
+    },
+    sampling::Sampling,
+    shutdown::Shutdown,
+    state::AppState,
+    streamer::{get_keep_alive, PromptTokens, Streamer},
+    util::ImageFetcher,
+};
+
+pub async fn chatcompletions(
+    State(app): State<Arc<AppState>>,
+    budget: Option<Extension<Arc<KeyBudget>>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    ticket: Option<Extension<Ticket>>,
+    OaiJson(oairequest): OaiJson<ChatCompletionRequest>,
+) -> ChatCompletionResponder {
+    let mut record = app
+        .journal
+        .as_ref()
+        .map(|journal| journal.record("/v1/chat/completions", &oairequest));
+    let ticket = ticket.map(|Extension(ticket)| ticket).unwrap_or_default();
+    let responder = respond(&app, budget, metrics, ticket, &mut record, oairequest).await;
+    if let Some(record) = record {
+        responder.journal(record);
+    }
+    responder
+}
+
+/// Runs the request. A streamed response takes `record` to journal it when the stream ends.
+pub(crate) async fn respond(
+    app: &AppState,
+    budget: Option<Extension<Arc<KeyBudget>>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    ticket: Ticket,
+    record: &mut Option<JournalRecord>,
+    oairequest: ChatCompletionRequest,
+) -> ChatCompletionResponder {
+    let (name, state) = match app.models.get(&oairequest.model) {
+        Ok(model) => model,
+        Err(e) => return ChatCompletionResponder::Error(e),
+    };
+    if let Some(Extension(metrics)) = &metrics {
+        metrics.set_model(&name, oairequest.stream.unwrap_or(false));
+    }
+    let (tx, mut rx) = channel(10_000);
+    let (request, is_streaming, check) =
+        match parse_request(oairequest, &*state, &app.images, &app.limits, tx).await {
+            Ok(x) => x,
+            Err(e) => {
+                state.log_error(&e);
+                return ChatCompletionResponder::Error(e);
+            }
+        };
+    // Held until the response is done, streamed or not.
+    let permit = match app.admission.admit(&name, ticket).await {
+        Ok(permit) => permit,
+        Err(e) => return ChatCompletionResponder::Error(e),
+    };
+    let prompt_tokens = is_streaming.then(|| PromptTokens::count(&state, &request));
+    if let Err(e) = state.submit(request) {
+        state.log_error(&e);
+        return ChatCompletionResponder::Error(e);
+    }
+
+    if is_streaming {
+        let streamer = Streamer::new(rx, state)
+            .with_budget(budget.map(|Extension(budget)| budget))
+            .with_metrics(metrics.map(|Extension(metrics)| metrics))
+            .with_journal(record.take())
+            .with_shutdown(&app.shutdown)
+            .with_output_check(check)
+            .with_prompt_tokens(prompt_tokens)
+            .with_permit(permit);
+        ChatCompletionResponder::Sse(Sse::new(streamer).keep_alive(get_keep_alive()))
+    } else {
+        let response = tokio::select! {
+            response = rx.recv() => response,
+            () = app.shutdown.expired() => {
+                rx.close();
+                return ChatCompletionResponder::Error(Shutdown::error());
+            }
+        };
+        let response = match response {
+            Some(response) => response,
+            None => {
+                let e = ApiError::internal("No response received from the model.");
+                state.log_error(&e);
+                return ChatCompletionResponder::Error(e);
+            }
+        };
+
+        match response {
+            Response::InternalError(e) => {
+                state.log_error(&*e);
+                ChatCompletionResponder::Error(ApiError::internal(e.to_string()))
+            }
+            Response::ModelError(msg, response) => {
+                if let Some(Extension(budget)) = &budget {
+                    budget.charge(response.usage.total_tokens);
+                }
+                if let Some(Extension(metrics)) = &metrics {
+                    metrics.usage(&response.usage);
+                }
+                state.log_error(&ModelErrorMessage(msg.to_string()));
+                state.log_response(LoggedResponse::Chat(&response));
+                ChatCompletionResponder::ModelError(msg, response)
+            }
+            Response::ValidationError(e) => {
+                ChatCompletionResponder::Error(ApiError::unprocessable(e.to_string()))
+            }
+            Response::Done(response) => {
+                if let Some(Extension(metrics)) = &metrics {
+                    metrics.usage(&response.usage);
+                }
+                state.log_response(LoggedResponse::Chat(&response));
+                // Output the client never gets is not charged to the key.
+                if let Err(e) = check.check_response(&response) {
+                    state.log_error(&e);
+                    return ChatCompletionResponder::Error(e);
+                }
+                if let Some(Extension(budget)) = &budget {
+                    budget.charge(response.usage.total_tokens);
+                }
+                ChatCompletionResponder::Json(response)
+            }
+            Response::Chunk(_)
+            | Response::CompletionDone(_)
+            | Response::CompletionModelError(_, _)
+            | Response::CompletionChunk(_) => {
+                let e = ApiError::internal("Unexpected response to a chat completion request.");
+                state.log_error(&e);
+                ChatCompletionResponder::Error(e)
+            }
+        }
+    }
+}
+
+/// Longest side, in pixels, of an image sent with `"detail": "low"`.
+const LOW_DETAIL_MAX_SIDE: u32 = 512;
+
+/// Converts the request's messages (or bare prompt) into the engine's message format,
+/// fetching any images they reference.
+///
+/// Content parts may come in any number and order, in any role. When the request has no
+/// images at all, array contents are flattened to a string so text-only chat templates
+/// keep working.
+pub(crate) async fn parse_messages(
+    messages: Either<Vec<Message>, String>,
+    fetcher: &ImageFetcher,
+) -> Result<RequestMessage, ApiError> {
+    let req_messages = match messages {
+        Either::Left(req_messages) => req_messages,
+        Either::Right(prompt) => vec![Message {
+            content: MessageContent::Text(prompt),
+            role: "user".to_string(),
+            name: None,
+        }],
+    };
+    let has_images = req_messages.iter().any(|message| match &message.content {
+        MessageContent::Parts(parts) => parts
+            .iter()
+            .any(|part| matches!(part, ContentPart::ImageUrl { .. })),
+        MessageContent::Text(_) => false,
+    });
+
+    let mut messages = Vec::new();
+    let mut images = Vec::new();
+    for (i, message) in req_messages.into_iter().enumerate() {
+        let content = match message.content {
+            MessageContent::Text(text) => Either::Left(text),
+            MessageContent::Parts(parts) if !has_images => Either::Left(
+                parts
+                    .into_iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text { text } => Some(text),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            MessageContent::Parts(parts) => {
+                let mut items = Vec::new();
+                for (j, part) in parts.into_iter().enumerate() {
+                    match part {
+                        ContentPart::Text { text } => items.push(IndexMap::from([
+                            ("type".to_string(), "text".to_string()),
+                            ("text".to_string(), text),
+                        ])),
+                        ContentPart::ImageUrl { image_url } => {
+                            let image = load_image(fetcher, &image_url).await.map_err(|e| {
+                                ApiError::invalid_request(format!(
+                                    "messages[{i}].content[{j}]: {e:#}"
+                                ))
+                                .with_param(format!("messages[{i}].content[{j}].image_url"))
+                            })?;
+                            images.push(image);
+                            items.push(IndexMap::from([("type".to_string(), "image".to_string())]));
+                        }
+                    }
+                }
+                Either::Right(items)
+            }
+        };
+        messages.push(IndexMap::from([
+            ("role".to_string(), Either::Left(message.role)),
+            ("content".to_string(), content),
+        ]));
+    }
+
+    Ok(if has_images {
+        RequestMessage::VisionChat { messages, images }
+    } else {
+        RequestMessage::Chat(messages)
+    })
+}
+
+async fn load_image(fetcher: &ImageFetcher, image_url: &ImageUrl) -> Result<DynamicImage> {
+    anyhow::ensure!(!image_url.url.trim().is_empty(), "`url` must not be empty.");
+    let image = fetcher
+        .parse_image_url(&image_url.url)
+        .await
+        .with_context(|| format!("Failed to parse image resource: {}", image_url.url))?;
+    Ok(match image_url.detail {
+        ImageDetail::Low => image.thumbnail(LOW_DETAIL_MAX_SIDE, LOW_DETAIL_MAX_SIDE),
+        ImageDetail::Auto | ImageDetail::High => image,
+    })
+}
+
+async fn parse_request(
+    oairequest: ChatCompletionRequest,
+    state: &dyn Engine,
+    fetcher: &ImageFetcher,
+    limits: &LimitsConfig,
+    tx: Sender<Response>,
+) -> Result<(Request, bool, OutputCheck), ApiError> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    state.log_request(repr);
+    Sampling::from(&oairequest).validate(limits, state.vocab_size())?;
+    let adapters = adapters::select(state, oairequest.adapters)?;
+
+    let stop_toks = match oairequest.stop_seqs {
+        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
+        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
+        None => None,
+    };
+    let messages = parse_messages(oairequest.messages, fetcher).await?;
+
+    let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
+        Some(
+            DrySamplingParams::new_with_defaults(
+                dry_multiplier,
+                oairequest.dry_sequence_breakers,
+                oairequest.dry_base,
+                oairequest.dry_allowed_length,
+            )
+            .map_err(|e| ApiError::invalid_request(e.to_string()).with_param("dry_multiplier"))?,
+        )
+    } else {
+        None
+    };
+
+    let (check, json_regex) = OutputCheck::new(
+        oairequest.response_format.as_ref(),
+        oairequest.tools.as_deref(),
+    )?;
+    let constraint = match (oairequest.grammar, json_regex) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::invalid_request(
+                "`response_format` cannot be combined with `grammar`.",
+            )
+            .with_param("response_format"))
+        }
+        (Some(Grammar::Yacc(yacc)), None) => Constraint::Yacc(yacc),
+        (Some(Grammar::Regex(regex)), None) => Constraint::Regex(regex),
+        (None, Some(regex)) => Constraint::Regex(regex),
+        (None, None) => Constraint::None,
+    };
+
+    let is_streaming = oairequest.stream.unwrap_or(false);
+    Ok((
+        Request::Normal(NormalRequest {
+            id: state.next_request_id(),
+            messages,
+            sampling_params: SamplingParams {
+                temperature: oairequest.temperature,
+                top_k: oairequest.top_k,
+                top_p: oairequest.top_p,
+                min_p: oairequest.min_p,
+                top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
+                frequency_penalty: oairequest.frequency_penalty,
+                presence_penalty: oairequest.presence_penalty,
+                max_len: oairequest.max_tokens.or(limits.max_tokens),
+                stop_toks,
+                logits_bias: oairequest.logit_bias,
+                n_choices: oairequest.n_choices,
+                dry_params,
+            },
+            response: tx,
+            return_logprobs: oairequest.logprobs,
+            is_streaming,
+            suffix: None,
+            constraint,
+            adapters,
+            tool_choice: oairequest.tool_choice,
+            tools: oairequest.tools,
+            logits_processors: None,
+        }),
+        is_streaming,
+        check,
+    ))
+}
+
+pub enum ChatCompletionResponder {
+    Sse(Sse<Streamer>),
+    Json(ChatCompletionResponse),
+    ModelError(String, ChatCompletionResponse),
+    Error(ApiError),
+}
+
+impl ChatCompletionResponder {
+    /// Status, body and usage of a finished response. `None` for a stream.
+    pub(crate) fn finished(&self) -> Option<(u16, serde_json::Value, Option<serde_json::Value>)> {
+        Some(match self {
+            ChatCompletionResponder::Sse(_) => return None,
+            ChatCompletionResponder::Json(response) => (
+                200,
+                serde_json::to_value(response).unwrap_or_default(),
+                serde_json::to_value(&response.usage).ok(),
+            ),
+            ChatCompletionResponder::ModelError(msg, response) => (
+                500,
+                serde_json::to_value(JsonModelError::new(msg.clone(), response))
+                    .unwrap_or_default(),
+                serde_json::to_value(&response.usage).ok(),
+            ),
+            ChatCompletionResponder::Error(e) => (
+                e.status().as_u16(),
+                serde_json::to_value(e.body()).unwrap_or_default(),
+                None,
+            ),
+        })
+    }
+
+    /// Journals a finished response. Streams journal themselves.
+    fn journal(&self, record: JournalRecord) {
+        if let Some((status, body, usage)) = self.finished() {
+            record.finish(status, body, usage);
+        }
+    }
+}
+
+impl IntoResponse for ChatCompletionResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ChatCompletionResponder::Sse(s) => s.into_response(),
+            ChatCompletionResponder::Json(s) => Json(s).into_response(),
+            ChatCompletionResponder::ModelError(msg, response) => {
+                JsonModelError::new(msg, response).into_response()
+            }
+            ChatCompletionResponder::Error(e) => e.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{app, chat_chunk, chat_done, post, sse_data, FakeEngine};
+    use axum::http::StatusCode;
+    use image::ImageFormat;
+    use serde_json::{json, Value};
+    use std::io::Cursor;
+
+    const URI: &str = "/v1/chat/completions";
+
+    fn request(stream: bool) -> Value {
+        json!({ "messages": [{ "role": "user", "content": "hi" }], "stream": stream })
+    }
+
+    fn error_param(body: &str) -> Value {
+        serde_json::from_str::<Value>(body).unwrap()["error"]["param"].clone()
+    }
+
+    fn png_data_url() -> String {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(8, 8)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        let encoded: String = bytes.iter().map(|b| format!("%{b:02X}")).collect();
+        format!("data:image/png,{encoded}")
+    }
+
+    #[tokio::test]
+    async fn returns_the_finished_response() {
+        let engine = FakeEngine::new([vec![Response::Done(chat_done("hello", "stop"))]]);
+        let (status, body) = post(app(engine.clone()), URI, request(false)).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["choices"][0]["message"]["content"], "hello");
+        assert_eq!(body["usage"]["total_tokens"], 5);
+
+        let requests = engine.requests();
+        assert!(!requests[0].is_streaming);
+        let RequestMessage::Chat(messages) = &requests[0].messages else {
+            panic!("Expected a text chat.");
+        };
+        assert_eq!(messages[0]["content"], Either::Left("hi".to_string()));
+    }
+
+    #[tokio::test]
+    async fn model_error_returns_the_partial_response() {
+        let engine = FakeEngine::new([vec![Response::ModelError(
+            "out of memory".to_string(),
+            chat_done("hel", "error"),
+        )]]);
+        let (status, body) = post(app(engine), URI, request(false)).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["error"]["message"], "out of memory");
+        assert_eq!(
+            body["partial_response"]["choices"][0]["message"]["content"],
+            "hel"
+        );
+    }
+
+    #[tokio::test]
+    async fn engine_errors_map_to_status_codes() {
+        let cases: [(Vec<Response>, StatusCode); 3] = [
+            (
+                vec![Response::ValidationError("prompt too long".into())],
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                vec![Response::InternalError("device lost".into())],
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            // The engine dropped the request without answering.
+            (vec![], StatusCode::INTERNAL_SERVER_ERROR),
+        ];
+        for (script, expected) in cases {
+            let engine = FakeEngine::new([script]);
+            let (status, _) = post(app(engine), URI, request(false)).await;
+            assert_eq!(status, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_rate_limited() {
+        let (status, _) = post(app(FakeEngine::new([])), URI, request(false)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn unknown_model_is_not_found() {
+        let engine = FakeEngine::new([vec![Response::Done(chat_done("hello", "stop"))]]);
+        let mut request = request(false);
+        request["model"] = "other".into();
+        let (status, body) = post(app(engine.clone()), URI, request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error_param(&body), "model");
+        assert!(engine.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn mistyped_fields_are_unprocessable() {
+        let (status, body) = post(
+            app(FakeEngine::new([])),
+            URI,
+            json!({ "messages": "hi", "n": "two" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    #[tokio::test]
+    async fn streams_chunks_then_done() {
+        let engine = FakeEngine::new([vec![
+            chat_chunk("hel", None),
+            chat_chunk("lo", Some("stop")),
+        ]]);
+        let (status, body) = post(app(engine.clone()), URI, request(true)).await;
+        assert_eq!(status, StatusCode::OK);
+        let events = sse_data(&body);
+        assert_eq!(events.len(), 3);
+        let first = serde_json::from_str::<Value>(&events[0]).unwrap();
+        assert_eq!(first["choices"][0]["delta"]["content"], "hel");
+        let second = serde_json::from_str::<Value>(&events[1]).unwrap();
+        assert_eq!(second["choices"][0]["finish_reason"], "stop");
+        assert_eq!(events[2], "[DONE]");
+        assert!(engine.requests()[0].is_streaming);
+    }
+
+    #[tokio::test]
+    async fn stream_errors_end_the_stream() {
+        let cases = [
+            (
+                Response::ModelError("out of memory".to_string(), chat_done("hel", "error")),
+                "server_error",
+            ),
+            (
+                Response::ValidationError("prompt too long".into()),
+                "invalid_request_error",
+            ),
+            (
+                Response::InternalError("device lost".into()),
+                "server_error",
+            ),
+        ];
+        for (error, tp) in cases {
+            let engine = FakeEngine::new([vec![chat_chunk("hel", None), error]]);
+            let (status, body) = post(app(engine), URI, request(true)).await;
+            assert_eq!(status, StatusCode::OK);
+            let events = sse_data(&body);
+            assert_eq!(events.len(), 3, "{events:?}");
+            let error = serde_json::from_str::<Value>(&events[1]).unwrap();
+            assert_eq!(error["error"]["type"], tp);
+            assert_eq!(events[2], "[DONE]");
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_cut_off_by_the_engine_sends_an_error() {
+        let engine = FakeEngine::new([vec![chat_chunk("hel", None)]]);
+        let (_, body) = post(app(engine), URI, request(true)).await;
+        let events = sse_data(&body);
+        assert_eq!(events.len(), 3, "{events:?}");
+        let error = serde_json::from_str::<Value>(&events[1]).unwrap();
+        assert_eq!(
+            error["error"]["message"],
+            "The model stopped without finishing the response."
+        );
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn images_make_a_vision_request() {
+        let engine = FakeEngine::new([vec![Response::Done(chat_done("a square", "stop"))]]);
+        let request = json!({
+            "messages": [{
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": "What is this?" },
+                    { "type": "image_url", "image_url": { "url": png_data_url() } },
+                ],
+            }],
+        });
+        let (status, _) = post(app(engine.clone()), URI, request).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let requests = engine.requests();
+        let RequestMessage::VisionChat { images, messages } = &requests[0].messages else {
+            panic!("Expected a vision chat.");
+        };
+        assert_eq!(images.len(), 1);
+        let Either::Right(items) = &messages[0]["content"] else {
+            panic!("Expected content parts.");
+        };
+        let types = items
+            .iter()
+            .map(|item| item["type"].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(types, ["text", "image"]);
+    }
+
+    #[tokio::test]
+    async fn text_parts_are_flattened_without_images() {
+        let engine = FakeEngine::new([vec![Response::Done(chat_done("ok", "stop"))]]);
+        let request = json!({
+            "messages": [{
+                "role": "user",
+                "content": [{ "type": "text", "text": "a" }, { "type": "text", "text": "b" }],
+            }],
+        });
+        let (status, _) = post(app(engine.clone()), URI, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let requests = engine.requests();
+        let RequestMessage::Chat(messages) = &requests[0].messages else {
+            panic!("Expected a text chat.");
+        };
+        assert_eq!(messages[0]["content"], Either::Left("a\nb".to_string()));
+    }
+
+    #[tokio::test]
+    async fn bad_images_are_rejected_before_the_engine() {
+        let engine = FakeEngine::new([vec![Response::Done(chat_done("ok", "stop"))]]);
+        for url in ["data:image/png,notapng", "https://example.com/cat.png", " "] {
+            let request = json!({
+                "messages": [{
+                    "role": "user",
+                    "content": [
+                        { "type": "text", "text": "What is this?" },
+                        { "type": "image_url", "image_url": { "url": url } },
+                    ],
+                }],
+            });
+            let (status, body) = post(app(engine.clone()), URI, request).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{url}");
+            assert_eq!(error_param(&body), "messages[0].content[1].image_url");
+        }
+        assert!(engine.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn response_format_is_checked() {
+        let engine = FakeEngine::new([
+            vec![Response::Done(chat_done("not json", "stop"))],
+            vec![Response::Done(chat_done(r#"{"a": 1}"#, "stop"))],
+        ]);
+        let mut request = request(false);
+        request["response_format"] = json!({ "type": "json_object" });
+        let (status, body) = post(app(engine.clone()), URI, request.clone()).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error_param(&body), "response_format");
+        assert!(matches!(
+            engine.requests()[0].constraint,
+            Constraint::Regex(_)
+        ));
+
+        let (status, _) = post(app(engine), URI, request).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn grammar_and_response_format_conflict() {
+        let mut request = request(false);
+        request["response_format"] = json!({ "type": "json_object" });
+        request["grammar"] = json!({ "type": "regex", "value": "a+" });
+        let (status, body) = post(app(FakeEngine::new([])), URI, request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error_param(&body), "response_format");
+    }
+
+    #[tokio::test]
+    async fn sampling_parameters_are_checked_before_the_engine() {
+        let engine = FakeEngine::new([]);
+        // The fake app caps `n` at 4 and `max_tokens` at 1024.
+        let cases = [
+            (json!({ "top_p": 1.5 }), "top_p"),
+            (json!({ "n": 0 }), "n"),
+            (json!({ "n": 5 }), "n"),
+            (json!({ "max_tokens": 1025 }), "max_tokens"),
+            (json!({ "logit_bias": { "1114112": 1.0 } }), "logit_bias"),
+            (json!({ "top_logprobs": 2 }), "top_logprobs"),
+            (json!({ "dry_allowed_length": 2 }), "dry_allowed_length"),
+        ];
+        for (fields, param) in cases {
+            let mut request = request(false);
+            request
+                .as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            let (status, body) = post(app(engine.clone()), URI, request).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{fields}");
+            assert_eq!(error_param(&body), param);
+        }
+        assert!(engine.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_tokens_defaults_to_the_cap() {
+        let engine = FakeEngine::new([
+            vec![Response::Done(chat_done("a", "stop"))],
+            vec![Response::Done(chat_done("a", "stop"))],
+        ]);
+        post(app(engine.clone()), URI, request(false)).await;
+        let mut capped = request(false);
+        capped["max_tokens"] = json!(8);
+        post(app(engine.clone()), URI, capped).await;
+        let requests = engine.requests();
+        assert_eq!(requests[0].sampling_params.max_len, Some(1024));
+        assert_eq!(requests[1].sampling_params.max_len, Some(8));
+    }
+}
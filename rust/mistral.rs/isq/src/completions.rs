@@ -0,0 +1,399 @@
+use axum::{
+    extract::{Extension, Json, State},
+    response::{IntoResponse, Sse},
+};
+use std::sync::Arc;
+use tokio::sync::mpsc::{channel, Sender};
+
+use mistralrs::{
+    CompletionResponse, Constraint, DrySamplingParams, NormalRequest, Request, RequestMessage,
+    Response, SamplingParams, StopTokens as InternalStopTokens,
+};
+
+use crate::{
+    adapters,
+    admission::Ticket,
+    auth::KeyBudget,
+    config::LimitsConfig,
+    engine::{Engine, LoggedResponse},
+    error::{ApiError, JsonModelError, ModelErrorMessage, OaiJson},
+    journal::JournalRecord,
+    metrics::RequestMetrics,
+    openai::{CompletionRequest, Grammar, StopTokens},
+    sampling::Sampling,
+    shutdown::Shutdown,
+    state::AppState,
+    streamer::{get_keep_alive, PromptTokens, Streamer},
+};
+
+pub async fn completions(
+    State(app): State<Arc<AppState>>,
+    budget: Option<Extension<Arc<KeyBudget>>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    ticket: Option<Extension<Ticket>>,
+    OaiJson(oairequest): OaiJson<CompletionRequest>,
+) -> CompletionResponder {
+    let mut record = app
+        .journal
+        .as_ref()
+        .map(|journal| journal.record("/v1/completions", &oairequest));
+    let ticket = ticket.map(|Extension(ticket)| ticket).unwrap_or_default();
+    let responder = respond(&app, budget, metrics, ticket, &mut record, oairequest).await;
+    if let Some(record) = record {
+        responder.journal(record);
+    }
+    responder
+}
+
+/// Runs the request. A streamed response takes `record` to journal it when the stream ends.
+async fn respond(
+    app: &AppState,
+    budget: Option<Extension<Arc<KeyBudget>>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    ticket: Ticket,
+    record: &mut Option<JournalRecord>,
+    oairequest: CompletionRequest,
+) -> CompletionResponder {
+    let (name, state) = match app.models.get(&oairequest.model) {
+        Ok(model) => model,
+        Err(e) => return CompletionResponder::Error(e),
+    };
+    if let Some(Extension(metrics)) = &metrics {
+        metrics.set_model(&name, oairequest.stream.unwrap_or(false));
+    }
+    let (tx, mut rx) = channel(10_000);
+    let (request, is_streaming) = match parse_request(oairequest, &*state, &app.limits, tx) {
+        Ok(x) => x,
+        Err(e) => {
+            state.log_error(&e);
+            return CompletionResponder::Error(e);
+        }
+    };
+    // Held until the response is done, streamed or not.
+    let permit = match app.admission.admit(&name, ticket).await {
+        Ok(permit) => permit,
+        Err(e) => return CompletionResponder::Error(e),
+    };
+    let prompt_tokens = is_streaming.then(|| PromptTokens::count(&state, &request));
+    if let Err(e) = state.submit(request) {
+        state.log_error(&e);
+        return CompletionResponder::Error(e);
+    }
+
+    if is_streaming {
+        let streamer = Streamer::new(rx, state)
+            .with_budget(budget.map(|Extension(budget)| budget))
+            .with_metrics(metrics.map(|Extension(metrics)| metrics))
+            .with_journal(record.take())
+            .with_shutdown(&app.shutdown)
+            .with_prompt_tokens(prompt_tokens)
+            .with_permit(permit);
+        CompletionResponder::Sse(Sse::new(streamer).keep_alive(get_keep_alive()))
+    } else {
+        let response = tokio::select! {
+            response = rx.recv() => response,
+            () = app.shutdown.expired() => {
+                rx.close();
+                return CompletionResponder::Error(Shutdown::error());
+            }
+        };
+        let response = match response {
+            Some(response) => response,
+            None => {
+                let e = ApiError::internal("No response received from the model.");
+                state.log_error(&e);
+                return CompletionResponder::Error(e);
+            }
+        };
+
+        match response {
+            Response::InternalError(e) => {
+                state.log_error(&*e);
+                CompletionResponder::Error(ApiError::internal(e.to_string()))
+            }
+            Response::CompletionModelError(msg, response) => {
+                if let Some(Extension(budget)) = &budget {
+                    budget.charge(response.usage.total_tokens);
+                }
+                if let Some(Extension(metrics)) = &metrics {
+                    metrics.usage(&response.usage);
+                }
+                state.log_error(&ModelErrorMessage(msg.to_string()));
+                state.log_response(LoggedResponse::Completion(&response));
+                CompletionResponder::ModelError(msg, response)
+            }
+            Response::ValidationError(e) => {
+                CompletionResponder::Error(ApiError::unprocessable(e.to_string()))
+            }
+            Response::CompletionDone(response) => {
+                if let Some(Extension(budget)) = &budget {
+                    budget.charge(response.usage.total_tokens);
+                }
+                if let Some(Extension(metrics)) = &metrics {
+                    metrics.usage(&response.usage);
+                }
+                state.log_response(LoggedResponse::Completion(&response));
+                CompletionResponder::Json(response)
+            }
+            Response::CompletionChunk(_)
+            | Response::Chunk(_)
+            | Response::Done(_)
+            | Response::ModelError(_, _) => {
+                let e = ApiError::internal("Unexpected response to a completion request.");
+                state.log_error(&e);
+                CompletionResponder::Error(e)
+            }
+        }
+    }
+}
+
+fn parse_request(
+    oairequest: CompletionRequest,
+    state: &dyn Engine,
+    limits: &LimitsConfig,
+    tx: Sender<Response>,
+) -> Result<(Request, bool), ApiError> {
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    state.log_request(repr);
+    Sampling::from(&oairequest).validate(limits, state.vocab_size())?;
+    let adapters = adapters::select(state, oairequest.adapters)?;
+
+    let is_streaming = oairequest.stream.unwrap_or(false);
+    if oairequest.logprobs.is_some() {
+        return Err(
+            ApiError::invalid_request("Completion requests do not support logprobs.")
+                .with_param("logprobs"),
+        );
+    }
+    if oairequest.best_of < oairequest.n_choices {
+        return Err(ApiError::invalid_request(format!(
+            "`best_of` ({}) must be greater than or equal to `n` ({}).",
+            oairequest.best_of, oairequest.n_choices
+        ))
+        .with_param("best_of"));
+    }
+    if oairequest.best_of > limits.max_n {
+        return Err(ApiError::invalid_request(format!(
+            "`best_of` must be at most {}, got {}.",
+            limits.max_n, oairequest.best_of
+        ))
+        .with_param("best_of"));
+    }
+    if is_streaming && oairequest.best_of > 1 {
+        return Err(
+            ApiError::invalid_request("`best_of` cannot be used with streaming.")
+                .with_param("best_of"),
+        );
+    }
+
+    let stop_toks = match oairequest.stop_seqs {
+        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
+        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
+        None => None,
+    };
+
+    let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
+        Some(
+            DrySamplingParams::new_with_defaults(
+                dry_multiplier,
+                oairequest.dry_sequence_breakers,
+                oairequest.dry_base,
+                oairequest.dry_allowed_length,
+            )
+            .map_err(|e| ApiError::invalid_request(e.to_string()).with_param("dry_multiplier"))?,
+        )
+    } else {
+        None
+    };
+
+    Ok((
+        Request::Normal(NormalRequest {
+            id: state.next_request_id(),
+            messages: RequestMessage::Completion {
+                text: oairequest.prompt,
+                echo_prompt: oairequest.echo_prompt,
+                best_of: oairequest.best_of,
+            },
+            sampling_params: SamplingParams {
+                temperature: oairequest.temperature,
+                top_k: oairequest.top_k,
+                top_p: oairequest.top_p,
+                min_p: oairequest.min_p,
+                top_n_logprobs: 1,
+                frequency_penalty: oairequest.frequency_penalty,
+                presence_penalty: oairequest.presence_penalty,
+                max_len: oairequest.max_tokens.or(limits.max_tokens),
+                stop_toks,
+                logits_bias: oairequest.logit_bias,
+                n_choices: oairequest.n_choices,
+                dry_params,
+            },
+            response: tx,
+            return_logprobs: false,
+            is_streaming,
+            suffix: oairequest.suffix,
+            constraint: match oairequest.grammar {
+                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
+                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
+                None => Constraint::None,
+            },
+            adapters,
+            tool_choice: oairequest.tool_choice,
+            tools: oairequest.tools,
+            logits_processors: None,
+        }),
+        is_streaming,
+    ))
+}
+
+pub enum CompletionResponder {
+    Sse(Sse<Streamer>),
+    Json(CompletionResponse),
+    ModelError(String, CompletionResponse),
+    Error(ApiError),
+}
+
+impl CompletionResponder {
+    /// Journals a finished response. Streams journal themselves.
+    fn journal(&self, record: JournalRecord) {
+        match self {
+            CompletionResponder::Sse(_) => {}
+            CompletionResponder::Json(response) => record.finish(
+                200,
+                serde_json::to_value(response).unwrap_or_default(),
+                serde_json::to_value(&response.usage).ok(),
+            ),
+            CompletionResponder::ModelError(msg, response) => record.finish(
+                500,
+                serde_json::to_value(JsonModelError::new(msg.clone(), response))
+                    .unwrap_or_default(),
+                serde_json::to_value(&response.usage).ok(),
+            ),
+            CompletionResponder::Error(e) => record.finish(
+                e.status().as_u16(),
+                serde_json::to_value(e.body()).unwrap_or_default(),
+                None,
+            ),
+        }
+    }
+}
+
+impl IntoResponse for CompletionResponder {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            CompletionResponder::Sse(s) => s.into_response(),
+            CompletionResponder::Json(s) => Json(s).into_response(),
+            CompletionResponder::ModelError(msg, response) => {
+                JsonModelError::new(msg, response).into_response()
+            }
+            CompletionResponder::Error(e) => e.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{
+        app, chat_done, completion_chunk, completion_done, post, sse_data, FakeEngine,
+    };
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    const URI: &str = "/v1/completions";
+
+    #[tokio::test]
+    async fn returns_the_finished_response() {
+        let engine = FakeEngine::new([vec![Response::CompletionDone(completion_done(" world"))]]);
+        let (status, body) = post(app(engine.clone()), URI, json!({ "prompt": "hello" })).await;
+        assert_eq!(status, StatusCode::OK);
+        let body = serde_json::from_str::<Value>(&body).unwrap();
+        assert_eq!(body["choices"][0]["text"], " world");
+
+        let requests = engine.requests();
+        let RequestMessage::Completion { text, .. } = &requests[0].messages else {
+            panic!("Expected a completion.");
+        };
+        assert_eq!(text, "hello");
+    }
+
+    #[tokio::test]
+    async fn engine_errors_map_to_status_codes() {
+        let cases: [(Vec<Response>, StatusCode); 5] = [
+            (
+                vec![Response::CompletionModelError(
+                    "out of memory".to_string(),
+                    completion_done(" wor"),
+                )],
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (
+                vec![Response::ValidationError("prompt too long".into())],
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (
+                vec![Response::InternalError("device lost".into())],
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+            (vec![], StatusCode::INTERNAL_SERVER_ERROR),
+            // A chat response on a completion request.
+            (
+                vec![Response::Done(chat_done("hi", "stop"))],
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (script, expected) in cases {
+            let engine = FakeEngine::new([script]);
+            let (status, _) = post(app(engine), URI, json!({ "prompt": "hello" })).await;
+            assert_eq!(status, expected);
+        }
+        let (status, _) = post(app(FakeEngine::new([])), URI, json!({ "prompt": "hello" })).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn streams_chunks_then_done() {
+        let engine = FakeEngine::new([vec![
+            completion_chunk(" wor", None),
+            completion_chunk("ld", Some("stop")),
+        ]]);
+        let request = json!({ "prompt": "hello", "stream": true });
+        let (status, body) = post(app(engine), URI, request).await;
+        assert_eq!(status, StatusCode::OK);
+        let events = sse_data(&body);
+        assert_eq!(events.len(), 3, "{events:?}");
+        let first = serde_json::from_str::<Value>(&events[0]).unwrap();
+        assert_eq!(first["choices"][0]["text"], " wor");
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn invalid_parameters_are_rejected_before_the_engine() {
+        let engine = FakeEngine::new([vec![Response::CompletionDone(completion_done("x"))]]);
+        let cases = [
+            (json!({ "prompt": "hello", "logprobs": 1 }), "logprobs"),
+            (
+                json!({ "prompt": "hello", "best_of": 1, "n": 2 }),
+                "best_of",
+            ),
+            (
+                json!({ "prompt": "hello", "best_of": 2, "n": 1, "stream": true }),
+                "best_of",
+            ),
+            // Over the fake app's `max_n` of 4.
+            (json!({ "prompt": "hello", "best_of": 5 }), "best_of"),
+            (
+                json!({ "prompt": "hello", "temperature": -1 }),
+                "temperature",
+            ),
+            (json!({ "prompt": "hello", "dry_base": 1.75 }), "dry_base"),
+        ];
+        for (request, param) in cases {
+            let (status, body) = post(app(engine.clone()), URI, request).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST);
+            let body = serde_json::from_str::<Value>(&body).unwrap();
+            assert_eq!(body["error"]["param"], param);
+        }
+        assert!(engine.requests().is_empty());
+    }
+}
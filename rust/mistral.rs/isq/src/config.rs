@@ -0,0 +1,1212 @@
+//! Server settings, read from an optional TOML file and overridden from the command line.
+//!
+//! Defaults reproduce the previously hard-coded setup: ELYZA 7B instruct, in-situ quantized to
+//! Q4K, PagedAttention with a block size of 32, 512 MB of CPU cache, 90% GPU utilization, 16
+//! sequences and `0.0.0.0:1234`.
+
+use anyhow::{Context as _, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use common::device::DeviceSpec;
+use mistralrs::{IsqType, MemoryGpuConfig, ModelDType, Ordering};
+use serde::{Deserialize, Serialize};
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "OpenAI-compatible server on top of mistral.rs")]
+pub struct Args {
+    /// What to do with the loaded models. Serves HTTP when not given.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML config file. Command line flags take precedence over its values.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. `0.0.0.0:1234`.
+    #[arg(long)]
+    pub listen: Option<String>,
+
+    /// Name clients use in the `model` field. Defaults to the model id.
+    #[arg(long)]
+    pub name: Option<String>,
+
+    /// Which loader to use for the model.
+    #[arg(long, value_enum)]
+    pub loader: Option<LoaderKind>,
+
+    /// Hugging Face model id or local directory.
+    #[arg(short, long)]
+    pub model_id: Option<String>,
+
+    /// GGUF file name(s) inside the model id (GGUF loader only).
+    #[arg(long, num_args = 1..)]
+    pub gguf_file: Option<Vec<String>>,
+
+    /// Model id or directory to load the tokenizer from (GGUF loader only).
+    #[arg(long)]
+    pub tok_model_id: Option<String>,
+
+    /// Chat template JSON file.
+    #[arg(long)]
+    pub chat_template: Option<String>,
+
+    /// In-situ quantization type, e.g. `Q4K`, `Q8_0`, or `none`.
+    #[arg(long)]
+    pub isq: Option<String>,
+
+    /// Model dtype: `auto`, `bf16`, `f16` or `f32`.
+    #[arg(long)]
+    pub dtype: Option<String>,
+
+    /// Device: `auto`, `cpu`, `cuda:N` or `metal:N`.
+    #[arg(short, long)]
+    pub device: Option<String>,
+
+    /// Threads used for inference on the CPU. Defaults to one per core.
+    #[arg(long)]
+    pub cpu_threads: Option<usize>,
+
+    /// Disable PagedAttention.
+    #[arg(long)]
+    pub no_paged_attn: bool,
+
+    /// PagedAttention block size.
+    #[arg(long)]
+    pub pa_block_size: Option<usize>,
+
+    /// PagedAttention CPU swap space in MB.
+    #[arg(long)]
+    pub pa_cpu_mem: Option<usize>,
+
+    /// PagedAttention GPU memory in MB. Takes precedence over `--pa-gpu-mem-usage`.
+    #[arg(long)]
+    pub pa_gpu_mem: Option<usize>,
+
+    /// PagedAttention fraction of GPU memory to use, between 0 and 1.
+    #[arg(long)]
+    pub pa_gpu_mem_usage: Option<f32>,
+
+    /// Scheduler method.
+    #[arg(long, value_enum)]
+    pub scheduler: Option<SchedulerMethod>,
+
+    /// Maximum number of sequences running at once.
+    #[arg(long)]
+    pub max_seqs: Option<usize>,
+
+    /// Requests per model that may wait for a running slot; more get a 503.
+    #[arg(long)]
+    pub max_queue: Option<usize>,
+
+    /// Largest `max_tokens` a request may ask for, also used when it sets none.
+    #[arg(long)]
+    pub max_tokens: Option<usize>,
+
+    /// Largest `n` (and `best_of`) a request may ask for.
+    #[arg(long)]
+    pub max_n: Option<usize>,
+
+    /// Finished sequences whose KV cache is kept for later prompts that start with them, least
+    /// recently used evicted first. 0 turns prefix caching off. Defaults to 16, except for
+    /// models using PagedAttention, which mistral.rs 0.3 cannot give a prefix cache.
+    #[arg(long)]
+    pub prefix_cache_n: Option<usize>,
+
+    /// API keys file. When set, `/v1` routes require `Authorization: Bearer <key>`.
+    #[arg(long)]
+    pub api_keys: Option<PathBuf>,
+
+    /// Request journal file (JSONL), rotated by size.
+    #[arg(long)]
+    pub journal: Option<PathBuf>,
+
+    /// Turn the request journal off, even if the config file sets it up.
+    #[arg(long)]
+    pub no_journal: bool,
+
+    /// Seconds running requests get to finish after SIGTERM or Ctrl-C.
+    #[arg(long)]
+    pub shutdown_grace: Option<f64>,
+
+    /// Seconds between canary generations checking readiness. 0 turns them off.
+    #[arg(long)]
+    pub canary_interval: Option<f64>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run chat completion requests from a JSONL file instead of serving HTTP.
+    Batch(BatchArgs),
+    /// Measure latency and throughput of the first model over a matrix of settings.
+    Bench(BenchArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BatchArgs {
+    /// Input file, one `ChatCompletionRequest` per line.
+    #[arg(short, long)]
+    pub input: PathBuf,
+
+    /// Output file, one result per input line in the same order. If it exists, the run resumes
+    /// after the lines it already has.
+    #[arg(short, long)]
+    pub output: PathBuf,
+
+    /// Times a request turned away for now (429 or 503) is retried, after 1, 2, 4, ... seconds.
+    /// If it is still turned away, the run stops before its line, so the next run retries it.
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BenchArgs {
+    /// ISQ types to compare, e.g. `Q4K,Q8_0,none`. Defaults to the model's own setting.
+    #[arg(long = "isq-types", value_delimiter = ',', value_parser = parse_isq)]
+    pub isq: Vec<Option<IsqType>>,
+
+    /// Prompt lengths in tokens (approximate, the measured count is reported).
+    #[arg(long, value_delimiter = ',', default_value = "128,512")]
+    pub prompt_lens: Vec<usize>,
+
+    /// Numbers of requests sent at once.
+    #[arg(long, value_delimiter = ',', default_value = "1,4")]
+    pub batch_sizes: Vec<usize>,
+
+    /// PagedAttention settings to compare. Defaults to the configured one.
+    #[arg(long, value_delimiter = ',', value_enum)]
+    pub paged_attn: Vec<Toggle>,
+
+    /// Tokens generated per request.
+    #[arg(long, default_value_t = 128)]
+    pub max_tokens: usize,
+
+    /// Unmeasured iterations run first for each combination.
+    #[arg(long, default_value_t = 1)]
+    pub warmup: usize,
+
+    /// Measured iterations for each combination.
+    #[arg(short = 'n', long, default_value_t = 5)]
+    pub iterations: usize,
+
+    /// Report file, JSON if it ends in `.json` and CSV otherwise. Printed as CSV when not given.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Toggle {
+    On,
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LoaderKind {
+    Normal,
+    Gguf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedulerMethod {
+    /// Run up to `max_num_seqs` sequences at once.
+    Fixed,
+}
+
+pub fn parse_isq(s: &str) -> Result<Option<IsqType>> {
+    let isq = match s.trim().to_uppercase().as_str() {
+        "NONE" | "" => return Ok(None),
+        "Q4_0" => IsqType::Q4_0,
+        "Q4_1" => IsqType::Q4_1,
+        "Q5_0" => IsqType::Q5_0,
+        "Q5_1" => IsqType::Q5_1,
+        "Q8_0" => IsqType::Q8_0,
+        "Q8_1" => IsqType::Q8_1,
+        "Q2K" => IsqType::Q2K,
+        "Q3K" => IsqType::Q3K,
+        "Q4K" => IsqType::Q4K,
+        "Q5K" => IsqType::Q5K,
+        "Q6K" => IsqType::Q6K,
+        "Q8K" => IsqType::Q8K,
+        "HQQ8" => IsqType::HQQ8,
+        "HQQ4" => IsqType::HQQ4,
+        other => anyhow::bail!(
+            "Unknown ISQ type `{other}`. Expected one of Q4_0, Q4_1, Q5_0, Q5_1, Q8_0, Q8_1, \
+             Q2K, Q3K, Q4K, Q5K, Q6K, Q8K, HQQ8, HQQ4 or none."
+        ),
+    };
+    Ok(Some(isq))
+}
+
+pub fn parse_dtype(s: &str) -> Result<ModelDType> {
+    match s.trim().to_lowercase().as_str() {
+        "auto" => Ok(ModelDType::Auto),
+        "bf16" => Ok(ModelDType::BF16),
+        "f16" => Ok(ModelDType::F16),
+        "f32" => Ok(ModelDType::F32),
+        other => anyhow::bail!("Unknown dtype `{other}`. Expected auto, bf16, f16 or f32."),
+    }
+}
+
+/// Raw contents of the TOML file. Every field is optional and falls back to the default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    listen: Option<String>,
+    cpu_threads: Option<usize>,
+    shutdown_grace_secs: Option<f64>,
+    /// A single model, for the common case.
+    model: Option<FileModelConfig>,
+    /// Several models served from one process, routed by the request's `model` field.
+    #[serde(default)]
+    models: Vec<FileModelConfig>,
+    #[serde(default)]
+    paged_attn: FilePagedAttnConfig,
+    #[serde(default)]
+    scheduler: FileSchedulerConfig,
+    #[serde(default)]
+    admission: FileAdmissionConfig,
+    #[serde(default)]
+    limits: FileLimitsConfig,
+    #[serde(default)]
+    prefix_cache: FilePrefixCacheConfig,
+    #[serde(default)]
+    images: FileImagesConfig,
+    #[serde(default)]
+    auth: FileAuthConfig,
+    #[serde(default)]
+    journal: FileJournalConfig,
+    #[serde(default)]
+    health: FileHealthConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileModelConfig {
+    name: Option<String>,
+    loader: Option<LoaderKind>,
+    model_id: Option<String>,
+    gguf_files: Option<Vec<String>>,
+    tok_model_id: Option<String>,
+    chat_template: Option<String>,
+    isq: Option<String>,
+    dtype: Option<String>,
+    device: Option<String>,
+    adapters: Option<FileAdaptersConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAdaptersConfig {
+    kind: AdapterKind,
+    model_id: String,
+    ordering: PathBuf,
+    tgt_non_granular_index: Option<usize>,
+}
+
+/// The adapter names in a mistral.rs ordering file, read separately from [`Ordering`] so the
+/// server can list them.
+#[derive(Debug, Deserialize)]
+struct OrderingNames {
+    order: Option<Vec<String>>,
+    preload_adapters: Option<Vec<PreloadedName>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PreloadedName {
+    name: String,
+    adapter_model_id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilePagedAttnConfig {
+    enabled: Option<bool>,
+    block_size: Option<usize>,
+    cpu_mem_mb: Option<usize>,
+    gpu_mem_mb: Option<usize>,
+    gpu_mem_utilization: Option<f32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSchedulerConfig {
+    method: Option<SchedulerMethod>,
+    max_num_seqs: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAdmissionConfig {
+    max_running: Option<usize>,
+    max_queue: Option<usize>,
+    queue_timeout_secs: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileLimitsConfig {
+    max_tokens: Option<usize>,
+    max_n: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilePrefixCacheConfig {
+    max_sequences: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileImagesConfig {
+    schemes: Option<Vec<String>>,
+    allowed_hosts: Option<Vec<String>>,
+    local_root: Option<PathBuf>,
+    max_bytes: Option<usize>,
+    max_width: Option<u32>,
+    max_height: Option<u32>,
+    timeout_secs: Option<f64>,
+    cache_size: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileAuthConfig {
+    keys_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileJournalConfig {
+    enabled: Option<bool>,
+    path: Option<PathBuf>,
+    max_bytes: Option<u64>,
+    max_files: Option<usize>,
+    redact: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileHealthConfig {
+    canary_interval_secs: Option<f64>,
+    canary_timeout_secs: Option<f64>,
+    canary_failures: Option<u32>,
+}
+
+/// Validated settings used by `setup()` and `main()`.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    pub cpu_threads: Option<usize>,
+    /// Time running requests get to finish once shutdown starts.
+    pub shutdown_grace: Duration,
+    /// Models to serve. The first one also answers to the name `default`.
+    pub models: Vec<ModelConfig>,
+    pub paged_attn: Option<PagedAttnConfig>,
+    pub scheduler: SchedulerMethod,
+    pub max_num_seqs: usize,
+    pub admission: AdmissionConfig,
+    pub limits: LimitsConfig,
+    /// Sequences kept in the prefix cache, 0 when it is off. `None` when not configured, which
+    /// uses the default size, or no cache for models using PagedAttention.
+    pub prefix_cache_n: Option<usize>,
+    pub images: ImagePolicy,
+    /// API keys file, see `auth::ApiKeys`. `None` leaves the server open.
+    pub api_keys: Option<PathBuf>,
+    pub journal: Option<JournalConfig>,
+    pub health: HealthConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelConfig {
+    pub name: String,
+    pub loader: LoaderKind,
+    pub model_id: String,
+    pub gguf_files: Vec<String>,
+    pub tok_model_id: Option<String>,
+    pub chat_template: Option<String>,
+    pub isq: Option<IsqType>,
+    pub dtype: ModelDType,
+    pub device: DeviceSpec,
+    pub adapters: Option<AdaptersConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdapterKind {
+    /// LoRA adapters, picked per request.
+    Lora,
+    /// X-LoRA: a classifier mixes all the adapters token by token.
+    Xlora,
+}
+
+#[derive(Debug, Clone)]
+pub struct AdaptersConfig {
+    pub kind: AdapterKind,
+    /// HF model id or local directory holding the adapters listed in the ordering's `order`.
+    pub model_id: String,
+    pub ordering: Ordering,
+    /// Adapters from `order`, used by requests that pick none.
+    pub ordered: Vec<String>,
+    /// `preload_adapters` from the ordering, as `(name, source)`.
+    pub preloaded: Vec<(String, String)>,
+    /// X-LoRA only: the layer whose scalings are reused for the rest of the sequence.
+    pub tgt_non_granular_index: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PagedAttnConfig {
+    pub block_size: usize,
+    pub cpu_mem_mb: usize,
+    pub gpu_mem: MemoryGpuConfig,
+}
+
+/// What images chat requests may reference and how they are fetched.
+#[derive(Debug, Clone)]
+pub struct ImagePolicy {
+    /// URL schemes allowed in `image_url`, out of `http`, `https` and `data`. Bare base64
+    /// counts as `data`.
+    pub schemes: Vec<String>,
+    /// Hosts http(s) images may be fetched from, `*.example.com` also matching subdomains.
+    /// Empty allows any host with a public address; loopback, private and link-local
+    /// addresses are only reachable through hosts listed here.
+    pub allowed_hosts: Vec<String>,
+    /// Directory local image paths are resolved in and must stay inside. `None` disables
+    /// local files.
+    pub local_root: Option<PathBuf>,
+    /// Largest encoded image accepted, in bytes.
+    pub max_bytes: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Time limit for fetching one http(s) image, redirects included.
+    pub timeout: Duration,
+    /// Number of decoded images kept in memory. 0 disables the cache.
+    pub cache_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct JournalConfig {
+    pub path: PathBuf,
+    /// Size at which the file is rotated.
+    pub max_bytes: u64,
+    /// Rotated files kept next to the current one.
+    pub max_files: usize,
+    /// Replace prompts, message contents and image URLs in logged requests.
+    pub redact: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AdmissionConfig {
+    /// Requests per model handed to the engine at once.
+    pub max_running: usize,
+    /// Requests per model waiting for a slot.
+    pub max_queue: usize,
+    /// Time a request may wait for a slot, unless it sets its own.
+    pub queue_timeout: Option<Duration>,
+}
+
+/// Server-side caps on what one request may ask the engine for.
+#[derive(Debug, Clone, Copy)]
+pub struct LimitsConfig {
+    /// Largest `max_tokens`, and the value used when a request sets none. `None` leaves
+    /// generation bounded by the model's context only.
+    pub max_tokens: Option<usize>,
+    /// Largest `n`, and `best_of` for completions.
+    pub max_n: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HealthConfig {
+    /// Time between canary generations. Zero turns them off.
+    pub canary_interval: Duration,
+    /// Time a canary generation may take before it counts as failed.
+    pub canary_timeout: Duration,
+    /// Canaries in a row that must fail before the model counts as unhealthy.
+    pub canary_failures: u32,
+}
+
+/// Name that always resolves to the first configured model.
+pub const DEFAULT_MODEL_NAME: &str = "default";
+const DEFAULT_LISTEN: &str = "0.0.0.0:1234";
+const DEFAULT_MODEL_ID: &str = "elyza/ELYZA-japanese-Llama-2-7b-instruct";
+const DEFAULT_ISQ: IsqType = IsqType::Q4K;
+const DEFAULT_BLOCK_SIZE: usize = 32;
+const DEFAULT_CPU_MEM_MB: usize = 512;
+// NOTE(EricLBuehler): default is to use 90% of memory
+const DEFAULT_GPU_MEM_UTILIZATION: f32 = 0.9;
+const DEFAULT_MAX_NUM_SEQS: usize = 16;
+pub const DEFAULT_PREFIX_CACHE_N: usize = 16;
+const DEFAULT_MAX_QUEUE: usize = 256;
+const DEFAULT_MAX_N: usize = 16;
+const DEFAULT_SHUTDOWN_GRACE_SECS: f64 = 30.0;
+const DEFAULT_CANARY_INTERVAL_SECS: f64 = 30.0;
+const DEFAULT_CANARY_TIMEOUT_SECS: f64 = 60.0;
+const DEFAULT_CANARY_FAILURES: u32 = 3;
+/// Block sizes supported by the PagedAttention kernels.
+const SUPPORTED_BLOCK_SIZES: [usize; 3] = [8, 16, 32];
+const IMAGE_SCHEMES: [&str; 3] = ["http", "https", "data"];
+const DEFAULT_IMAGE_MAX_BYTES: usize = 20 * 1024 * 1024;
+const DEFAULT_IMAGE_MAX_SIDE: u32 = 4096;
+const DEFAULT_IMAGE_TIMEOUT_SECS: f64 = 10.0;
+const DEFAULT_IMAGE_CACHE_SIZE: usize = 64;
+const DEFAULT_JOURNAL_MAX_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_JOURNAL_MAX_FILES: usize = 10;
+
+impl ServerConfig {
+    /// Reads the config file named by `--config` (if any) and applies the command line on top.
+    pub fn load(args: Args) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path)
+                    .with_context(|| format!("Could not read config file `{}`.", path.display()))?;
+                toml::from_str::<FileConfig>(&raw)
+                    .with_context(|| format!("Invalid config file `{}`.", path.display()))?
+            }
+            None => FileConfig::default(),
+        };
+        Self::merge(file, args)
+    }
+
+    fn merge(file: FileConfig, args: Args) -> Result<Self> {
+        let listen = args
+            .listen
+            .or(file.listen)
+            .unwrap_or_else(|| DEFAULT_LISTEN.to_string());
+        let listen = listen
+            .parse::<SocketAddr>()
+            .with_context(|| format!("Invalid listen address `{listen}`, expected `host:port`."))?;
+
+        let cli_model = FileModelConfig {
+            name: args.name,
+            loader: args.loader,
+            model_id: args.model_id,
+            gguf_files: args.gguf_file,
+            tok_model_id: args.tok_model_id,
+            chat_template: args.chat_template,
+            isq: args.isq,
+            dtype: args.dtype,
+            device: args.device,
+            adapters: None,
+        };
+        let mut file_models = file.models;
+        if let Some(model) = file.model {
+            anyhow::ensure!(
+                file_models.is_empty(),
+                "Use either `[model]` or `[[models]]` in the config file, not both."
+            );
+            file_models.push(model);
+        }
+        let models = match file_models.len() {
+            0 => vec![ModelConfig::from_file(cli_model)?],
+            1 => vec![ModelConfig::from_file(
+                cli_model.or(file_models.pop().unwrap()),
+            )?],
+            _ => {
+                anyhow::ensure!(
+                    cli_model.is_empty(),
+                    "Model flags on the command line are ambiguous with several `[[models]]` in the config file."
+                );
+                file_models
+                    .into_iter()
+                    .map(ModelConfig::from_file)
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+        for (i, model) in models.iter().enumerate() {
+            anyhow::ensure!(
+                model.name != DEFAULT_MODEL_NAME,
+                "`{DEFAULT_MODEL_NAME}` is reserved for the first model and cannot be used as a model name."
+            );
+            anyhow::ensure!(
+                models[..i].iter().all(|m| m.name != model.name),
+                "Model name `{}` is used more than once.",
+                model.name
+            );
+        }
+
+        let paged_attn = if args.no_paged_attn || file.paged_attn.enabled == Some(false) {
+            None
+        } else {
+            let block_size = args
+                .pa_block_size
+                .or(file.paged_attn.block_size)
+                .unwrap_or(DEFAULT_BLOCK_SIZE);
+            anyhow::ensure!(
+                SUPPORTED_BLOCK_SIZES.contains(&block_size),
+                "Unsupported PagedAttention block size {block_size}, expected one of {SUPPORTED_BLOCK_SIZES:?}."
+            );
+            let cpu_mem_mb = args
+                .pa_cpu_mem
+                .or(file.paged_attn.cpu_mem_mb)
+                .unwrap_or(DEFAULT_CPU_MEM_MB);
+            let gpu_mem = match (
+                args.pa_gpu_mem.or(file.paged_attn.gpu_mem_mb),
+                args.pa_gpu_mem_usage
+                    .or(file.paged_attn.gpu_mem_utilization),
+            ) {
+                (Some(mb), _) => {
+                    anyhow::ensure!(
+                        mb > 0,
+                        "PagedAttention GPU memory must be greater than 0 MB."
+                    );
+                    MemoryGpuConfig::Amount(mb)
+                }
+                (None, utilization) => {
+                    let utilization = utilization.unwrap_or(DEFAULT_GPU_MEM_UTILIZATION);
+                    anyhow::ensure!(
+                        utilization > 0.0 && utilization <= 1.0,
+                        "PagedAttention GPU memory utilization must be in (0, 1], got {utilization}."
+                    );
+                    MemoryGpuConfig::Utilization(utilization)
+                }
+            };
+            Some(PagedAttnConfig {
+                block_size,
+                cpu_mem_mb,
+                gpu_mem,
+            })
+        };
+
+        let scheduler = args
+            .scheduler
+            .or(file.scheduler.method)
+            .unwrap_or(SchedulerMethod::Fixed);
+        let max_num_seqs = args
+            .max_seqs
+            .or(file.scheduler.max_num_seqs)
+            .unwrap_or(DEFAULT_MAX_NUM_SEQS);
+        anyhow::ensure!(max_num_seqs > 0, "`max_num_seqs` must be at least 1.");
+        let prefix_cache_n = args.prefix_cache_n.or(file.prefix_cache.max_sequences);
+
+        let cpu_threads = args.cpu_threads.or(file.cpu_threads);
+        anyhow::ensure!(cpu_threads != Some(0), "`cpu_threads` must be at least 1.");
+
+        let shutdown_grace = args
+            .shutdown_grace
+            .or(file.shutdown_grace_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS);
+        anyhow::ensure!(
+            shutdown_grace >= 0.0 && shutdown_grace.is_finite(),
+            "`shutdown_grace_secs` must be a number of seconds, 0 or more."
+        );
+
+        Ok(Self {
+            listen,
+            cpu_threads,
+            shutdown_grace: Duration::from_secs_f64(shutdown_grace),
+            models,
+            paged_attn,
+            scheduler,
+            max_num_seqs,
+            admission: AdmissionConfig::from_file(args.max_queue, max_num_seqs, file.admission)?,
+            limits: LimitsConfig::from_file(args.max_tokens, args.max_n, file.limits)?,
+            prefix_cache_n,
+            images: ImagePolicy::from_file(file.images)?,
+            api_keys: args.api_keys.or(file.auth.keys_file),
+            journal: if args.no_journal || file.journal.enabled == Some(false) {
+                None
+            } else {
+                JournalConfig::from_file(args.journal, file.journal)?
+            },
+            health: HealthConfig::from_file(args.canary_interval, file.health)?,
+        })
+    }
+}
+
+impl Default for PagedAttnConfig {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            cpu_mem_mb: DEFAULT_CPU_MEM_MB,
+            gpu_mem: MemoryGpuConfig::Utilization(DEFAULT_GPU_MEM_UTILIZATION),
+        }
+    }
+}
+
+impl JournalConfig {
+    /// The journal is on when a path is set on the command line or in the file.
+    fn from_file(path: Option<PathBuf>, journal: FileJournalConfig) -> Result<Option<Self>> {
+        let Some(path) = path.or(journal.path) else {
+            return Ok(None);
+        };
+        let max_bytes = journal.max_bytes.unwrap_or(DEFAULT_JOURNAL_MAX_BYTES);
+        let max_files = journal.max_files.unwrap_or(DEFAULT_JOURNAL_MAX_FILES);
+        anyhow::ensure!(max_bytes > 0, "`journal.max_bytes` must be greater than 0.");
+        anyhow::ensure!(max_files > 0, "`journal.max_files` must be at least 1.");
+        Ok(Some(Self {
+            path,
+            max_bytes,
+            max_files,
+            redact: journal.redact.unwrap_or(false),
+        }))
+    }
+}
+
+impl AdmissionConfig {
+    /// `max_running` defaults to the scheduler's `max_num_seqs`.
+    fn from_file(
+        max_queue: Option<usize>,
+        max_num_seqs: usize,
+        admission: FileAdmissionConfig,
+    ) -> Result<Self> {
+        let max_running = admission.max_running.unwrap_or(max_num_seqs);
+        anyhow::ensure!(
+            max_running > 0,
+            "`admission.max_running` must be at least 1."
+        );
+        let queue_timeout = match admission.queue_timeout_secs {
+            Some(secs) => {
+                anyhow::ensure!(
+                    secs > 0.0 && secs.is_finite(),
+                    "`admission.queue_timeout_secs` must be a number of seconds greater than 0."
+                );
+                Some(Duration::from_secs_f64(secs))
+            }
+            None => None,
+        };
+        Ok(Self {
+            max_running,
+            max_queue: max_queue
+                .or(admission.max_queue)
+                .unwrap_or(DEFAULT_MAX_QUEUE),
+            queue_timeout,
+        })
+    }
+}
+
+impl LimitsConfig {
+    fn from_file(
+        max_tokens: Option<usize>,
+        max_n: Option<usize>,
+        limits: FileLimitsConfig,
+    ) -> Result<Self> {
+        let max_tokens = max_tokens.or(limits.max_tokens);
+        anyhow::ensure!(
+            max_tokens != Some(0),
+            "`limits.max_tokens` must be at least 1."
+        );
+        let max_n = max_n.or(limits.max_n).unwrap_or(DEFAULT_MAX_N);
+        anyhow::ensure!(max_n > 0, "`limits.max_n` must be at least 1.");
+        Ok(Self { max_tokens, max_n })
+    }
+}
+
+impl HealthConfig {
+    fn from_file(canary_interval: Option<f64>, health: FileHealthConfig) -> Result<Self> {
+        let interval = canary_interval
+            .or(health.canary_interval_secs)
+            .unwrap_or(DEFAULT_CANARY_INTERVAL_SECS);
+        let timeout = health
+            .canary_timeout_secs
+            .unwrap_or(DEFAULT_CANARY_TIMEOUT_SECS);
+        anyhow::ensure!(
+            interval >= 0.0 && interval.is_finite(),
+            "`health.canary_interval_secs` must be a number of seconds, 0 or more."
+        );
+        anyhow::ensure!(
+            timeout > 0.0 && timeout.is_finite(),
+            "`health.canary_timeout_secs` must be a positive number of seconds."
+        );
+        let failures = health.canary_failures.unwrap_or(DEFAULT_CANARY_FAILURES);
+        anyhow::ensure!(failures > 0, "`health.canary_failures` must be at least 1.");
+        Ok(Self {
+            canary_interval: Duration::from_secs_f64(interval),
+            canary_timeout: Duration::from_secs_f64(timeout),
+            canary_failures: failures,
+        })
+    }
+}
+
+impl ImagePolicy {
+    fn from_file(images: FileImagesConfig) -> Result<Self> {
+        let schemes = images
+            .schemes
+            .unwrap_or_else(|| IMAGE_SCHEMES.map(str::to_string).to_vec());
+        for scheme in &schemes {
+            anyhow::ensure!(
+                scheme != "file",
+                "Local image files are enabled with `images.local_root`, not through `images.schemes`."
+            );
+            anyhow::ensure!(
+                IMAGE_SCHEMES.contains(&scheme.as_str()),
+                "Unknown image scheme `{scheme}`, expected one of {IMAGE_SCHEMES:?}."
+            );
+        }
+        let timeout_secs = images.timeout_secs.unwrap_or(DEFAULT_IMAGE_TIMEOUT_SECS);
+        anyhow::ensure!(
+            timeout_secs > 0.0 && timeout_secs.is_finite(),
+            "`images.timeout_secs` must be a positive number of seconds."
+        );
+        Ok(Self {
+            schemes,
+            allowed_hosts: images
+                .allowed_hosts
+                .unwrap_or_default()
+                .into_iter()
+                .map(|host| host.to_lowercase())
+                .collect(),
+            local_root: images.local_root,
+            max_bytes: images.max_bytes.unwrap_or(DEFAULT_IMAGE_MAX_BYTES),
+            max_width: images.max_width.unwrap_or(DEFAULT_IMAGE_MAX_SIDE),
+            max_height: images.max_height.unwrap_or(DEFAULT_IMAGE_MAX_SIDE),
+            timeout: Duration::from_secs_f64(timeout_secs),
+            cache_size: images.cache_size.unwrap_or(DEFAULT_IMAGE_CACHE_SIZE),
+        })
+    }
+}
+
+impl FileModelConfig {
+    /// Field-wise `Option::or`: values from `self` win over values from `other`.
+    fn or(self, other: Self) -> Self {
+        Self {
+            name: self.name.or(other.name),
+            loader: self.loader.or(other.loader),
+            model_id: self.model_id.or(other.model_id),
+            gguf_files: self.gguf_files.or(other.gguf_files),
+            tok_model_id: self.tok_model_id.or(other.tok_model_id),
+            chat_template: self.chat_template.or(other.chat_template),
+            isq: self.isq.or(other.isq),
+            dtype: self.dtype.or(other.dtype),
+            device: self.device.or(other.device),
+            adapters: self.adapters.or(other.adapters),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.name.is_none()
+            && self.loader.is_none()
+            && self.model_id.is_none()
+            && self.gguf_files.is_none()
+            && self.tok_model_id.is_none()
+            && self.chat_template.is_none()
+            && self.isq.is_none()
+            && self.dtype.is_none()
+            && self.device.is_none()
+            && self.adapters.is_none()
+    }
+}
+
+impl ModelConfig {
+    fn from_file(model: FileModelConfig) -> Result<Self> {
+        let loader = model.loader.unwrap_or(LoaderKind::Normal);
+        let model_id = model
+            .model_id
+            .unwrap_or_else(|| DEFAULT_MODEL_ID.to_string());
+        let name = model.name.unwrap_or_else(|| model_id.clone());
+        let gguf_files = model.gguf_files.unwrap_or_default();
+        let isq = match model.isq {
+            Some(isq) => parse_isq(&isq)?,
+            None if loader == LoaderKind::Normal => Some(DEFAULT_ISQ),
+            None => None,
+        };
+        let dtype = match model.dtype {
+            Some(dtype) => parse_dtype(&dtype)?,
+            None => ModelDType::Auto,
+        };
+        let device = match model.device {
+            Some(device) => device.parse()?,
+            None => DeviceSpec::Auto,
+        };
+        match loader {
+            LoaderKind::Normal => anyhow::ensure!(
+                gguf_files.is_empty(),
+                "Model `{name}`: GGUF files were given, but the loader is `normal`. Use `--loader gguf`."
+            ),
+            LoaderKind::Gguf => {
+                anyhow::ensure!(
+                    !gguf_files.is_empty(),
+                    "Model `{name}`: the GGUF loader needs at least one GGUF file (`--gguf-file` or `gguf_files`)."
+                );
+                anyhow::ensure!(
+                    isq.is_none(),
+                    "Model `{name}`: ISQ cannot be applied to a GGUF model, which is already quantized. Set `isq` to `none`."
+                );
+                anyhow::ensure!(
+                    model.adapters.is_none(),
+                    "Model `{name}`: adapters are only supported with the `normal` loader."
+                );
+            }
+        }
+        let adapters = model
+            .adapters
+            .map(AdaptersConfig::from_file)
+            .transpose()
+            .with_context(|| format!("Model `{name}`: invalid adapters."))?;
+
+        Ok(Self {
+            name,
+            loader,
+            model_id,
+            gguf_files,
+            tok_model_id: model.tok_model_id,
+            chat_template: model.chat_template,
+            isq,
+            dtype,
+            device,
+            adapters,
+        })
+    }
+}
+
+impl AdaptersConfig {
+    fn from_file(adapters: FileAdaptersConfig) -> Result<Self> {
+        let path = &adapters.ordering;
+        let json = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read ordering file `{}`.", path.display()))?;
+        let ordering = serde_json::from_str::<Ordering>(&json)
+            .with_context(|| format!("Invalid ordering file `{}`.", path.display()))?;
+        let names = serde_json::from_str::<OrderingNames>(&json)
+            .with_context(|| format!("Invalid ordering file `{}`.", path.display()))?;
+        let ordered = names.order.unwrap_or_default();
+        let preloaded = names
+            .preload_adapters
+            .unwrap_or_default()
+            .into_iter()
+            .map(|p| (p.name, p.adapter_model_id))
+            .collect::<Vec<_>>();
+        let all = ordered.iter().chain(preloaded.iter().map(|(name, _)| name));
+        for (i, name) in all.clone().enumerate() {
+            anyhow::ensure!(
+                all.clone().take(i).all(|other| other != name),
+                "Adapter `{name}` is listed twice in `{}`.",
+                path.display()
+            );
+        }
+        anyhow::ensure!(
+            adapters.kind == AdapterKind::Xlora || adapters.tgt_non_granular_index.is_none(),
+            "`tgt_non_granular_index` only applies to X-LoRA."
+        );
+        Ok(Self {
+            kind: adapters.kind,
+            model_id: adapters.model_id,
+            ordering,
+            ordered,
+            preloaded,
+            tgt_non_granular_index: adapters.tgt_non_granular_index,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `toml` as the config file and `args` as the command line.
+    fn load(toml: &str, args: &[&str]) -> Result<ServerConfig> {
+        let args = Args::try_parse_from(std::iter::once("isq").chain(args.iter().copied()))?;
+        ServerConfig::merge(toml::from_str(toml)?, args)
+    }
+
+    /// The error `load` fails with, causes included.
+    fn error(toml: &str, args: &[&str]) -> String {
+        format!("{:#}", load(toml, args).unwrap_err())
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load("", &[]).unwrap();
+        assert_eq!(config.listen.to_string(), DEFAULT_LISTEN);
+        assert_eq!(config.models.len(), 1);
+        let model = &config.models[0];
+        assert_eq!(model.name, DEFAULT_MODEL_ID);
+        assert_eq!(model.loader, LoaderKind::Normal);
+        assert!(matches!(model.isq, Some(IsqType::Q4K)));
+        let paged_attn = config.paged_attn.unwrap();
+        assert_eq!(paged_attn.block_size, DEFAULT_BLOCK_SIZE);
+        assert!(matches!(paged_attn.gpu_mem, MemoryGpuConfig::Utilization(u) if u == 0.9));
+        assert_eq!(config.admission.max_running, DEFAULT_MAX_NUM_SEQS);
+        assert_eq!(config.admission.max_queue, DEFAULT_MAX_QUEUE);
+        assert_eq!(config.limits.max_tokens, None);
+        assert_eq!(config.prefix_cache_n, None);
+        assert!(config.journal.is_none());
+    }
+
+    #[test]
+    fn command_line_wins_over_the_file() {
+        let toml = r#"
+            listen = "127.0.0.1:8000"
+            [model]
+            model_id = "org/model"
+            isq = "Q8_0"
+            [paged_attn]
+            block_size = 16
+            gpu_mem_utilization = 0.5
+            [admission]
+            max_queue = 10
+            [limits]
+            max_n = 4
+            max_tokens = 100
+        "#;
+        let config = load(
+            toml,
+            &[
+                "--listen",
+                "127.0.0.1:9000",
+                "--isq",
+                "none",
+                "--pa-block-size",
+                "8",
+                "--max-queue",
+                "3",
+                "--max-tokens",
+                "50",
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.listen.to_string(), "127.0.0.1:9000");
+        let model = &config.models[0];
+        // Fields not given on the command line still come from the file.
+        assert_eq!(model.model_id, "org/model");
+        assert!(model.isq.is_none());
+        let paged_attn = config.paged_attn.unwrap();
+        assert_eq!(paged_attn.block_size, 8);
+        assert!(matches!(paged_attn.gpu_mem, MemoryGpuConfig::Utilization(u) if u == 0.5));
+        assert_eq!(config.admission.max_queue, 3);
+        assert_eq!(config.limits.max_tokens, Some(50));
+        assert_eq!(config.limits.max_n, 4);
+
+        let config = load(toml, &[]).unwrap();
+        assert_eq!(config.listen.to_string(), "127.0.0.1:8000");
+        assert!(matches!(config.models[0].isq, Some(IsqType::Q8_0)));
+        assert_eq!(config.paged_attn.unwrap().block_size, 16);
+
+        let config = load("[paged_attn]\nblock_size = 16", &["--no-paged-attn"]).unwrap();
+        assert!(config.paged_attn.is_none());
+    }
+
+    #[test]
+    fn gpu_memory_in_mb_wins_over_utilization() {
+        let config = load(
+            "[paged_attn]\ngpu_mem_utilization = 0.5",
+            &["--pa-gpu-mem", "4096"],
+        )
+        .unwrap();
+        let gpu_mem = config.paged_attn.unwrap().gpu_mem;
+        assert!(matches!(gpu_mem, MemoryGpuConfig::Amount(4096)));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for toml in [
+            "lisen = \"0.0.0.0:1\"",
+            "[paged_attn]\nblock_sise = 16",
+            "[model]\nquantization = \"Q4K\"",
+            "[health]\ncanary_every_secs = 1",
+        ] {
+            let e = error(toml, &[]);
+            assert!(e.contains("unknown field"), "{toml}: {e}");
+        }
+    }
+
+    #[test]
+    fn paged_attention_settings_are_checked() {
+        let e = error("[paged_attn]\nblock_size = 64", &[]);
+        assert_eq!(
+            e,
+            "Unsupported PagedAttention block size 64, expected one of [8, 16, 32]."
+        );
+        for utilization in ["0", "1.5"] {
+            let e = error("", &["--pa-gpu-mem-usage", utilization]);
+            assert!(e.starts_with("PagedAttention GPU memory utilization must be in (0, 1]"));
+        }
+        let e = error("[paged_attn]\ngpu_mem_mb = 0", &[]);
+        assert_eq!(e, "PagedAttention GPU memory must be greater than 0 MB.");
+        // Nothing is checked when PagedAttention is off.
+        assert!(load("[paged_attn]\nenabled = false\nblock_size = 64", &[]).is_ok());
+    }
+
+    #[test]
+    fn gguf_models_need_files_and_no_isq() {
+        let e = error(
+            "[model]\nloader = \"gguf\"\ngguf_files = [\"m.gguf\"]\nisq = \"Q4K\"",
+            &[],
+        );
+        assert!(e.contains("ISQ cannot be applied to a GGUF model"), "{e}");
+        let e = error("", &["--loader", "gguf"]);
+        assert!(
+            e.contains("the GGUF loader needs at least one GGUF file"),
+            "{e}"
+        );
+        let e = error("", &["--gguf-file", "m.gguf"]);
+        assert!(e.contains("the loader is `normal`"), "{e}");
+
+        let config = load("", &["--loader", "gguf", "--gguf-file", "m.gguf"]).unwrap();
+        // GGUF models default to no ISQ.
+        assert!(config.models[0].isq.is_none());
+    }
+
+    #[test]
+    fn several_models_are_named_uniquely() {
+        let models = "[[models]]\nname = \"a\"\n[[models]]\nname = \"b\"";
+        let config = load(models, &[]).unwrap();
+        let names = config.models.iter().map(|m| m.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["a", "b"]);
+
+        let e = error(models, &["--isq", "none"]);
+        assert!(e.contains("ambiguous"), "{e}");
+        let e = error("[model]\nname = \"a\"\n[[models]]\nname = \"b\"", &[]);
+        assert!(e.contains("either `[model]` or `[[models]]`"), "{e}");
+        let e = error("[[models]]\nname = \"a\"\n[[models]]\nname = \"a\"", &[]);
+        assert_eq!(e, "Model name `a` is used more than once.");
+        let e = error("", &["--name", "default"]);
+        assert!(e.contains("`default` is reserved"), "{e}");
+    }
+
+    #[test]
+    fn admission_and_limits_are_bounded() {
+        let config = load("[scheduler]\nmax_num_seqs = 4", &[]).unwrap();
+        assert_eq!(config.admission.max_running, 4);
+
+        for (toml, message) in [
+            (
+                "[admission]\nmax_running = 0",
+                "`admission.max_running` must be at least 1.",
+            ),
+            (
+                "[admission]\nqueue_timeout_secs = 0",
+                "`admission.queue_timeout_secs` must be a number of seconds greater than 0.",
+            ),
+            (
+                "[limits]\nmax_tokens = 0",
+                "`limits.max_tokens` must be at least 1.",
+            ),
+            ("[limits]\nmax_n = 0", "`limits.max_n` must be at least 1."),
+            (
+                "[scheduler]\nmax_num_seqs = 0",
+                "`max_num_seqs` must be at least 1.",
+            ),
+        ] {
+            assert_eq!(error(toml, &[]), message, "{toml}");
+        }
+        assert_eq!(
+            error("", &["--max-n", "0"]),
+            "`limits.max_n` must be at least 1."
+        );
+    }
+
+    #[test]
+    fn canary_settings_are_bounded() {
+        let config = load("[health]\ncanary_interval_secs = 0", &[]).unwrap();
+        assert!(config.health.canary_interval.is_zero());
+        let config = load(
+            "[health]\ncanary_interval_secs = 5",
+            &["--canary-interval", "1.5"],
+        );
+        assert_eq!(
+            config.unwrap().health.canary_interval,
+            Duration::from_millis(1500)
+        );
+
+        let interval = "`health.canary_interval_secs` must be a number of seconds, 0 or more.";
+        assert_eq!(error("[health]\ncanary_interval_secs = -1", &[]), interval);
+        assert_eq!(error("", &["--canary-interval=-1"]), interval);
+        assert_eq!(error("[health]\ncanary_interval_secs = inf", &[]), interval);
+        let timeout = "`health.canary_timeout_secs` must be a positive number of seconds.";
+        assert_eq!(error("[health]\ncanary_timeout_secs = 0", &[]), timeout);
+        assert_eq!(load("", &[]).unwrap().health.canary_failures, 3);
+        assert_eq!(
+            error("[health]\ncanary_failures = 0", &[]),
+            "`health.canary_failures` must be at least 1."
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! `/v1/embeddings`: answers 501 for any model that exists.
+//!
+//! Embeddings would pool the model's last hidden states, but mistral.rs 0.3 only hands out
+//! logits: neither the pipeline's forward pass nor any request type returns hidden states. The
+//! route stays registered so clients get a clear error rather than a 404.
+
+use axum::extract::{Extension, State};
+use std::sync::Arc;
+
+use crate::{
+    error::{ApiError, OaiJson},
+    metrics::RequestMetrics,
+    openai::EmbeddingRequest,
+    state::AppState,
+};
+
+pub async fn embeddings(
+    State(app): State<Arc<AppState>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    OaiJson(oairequest): OaiJson<EmbeddingRequest>,
+) -> Result<(), ApiError> {
+    let (name, state) = app.models.get(&oairequest.model)?;
+    if let Some(Extension(metrics)) = &metrics {
+        metrics.set_model(&name, false);
+    }
+    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
+    state.log_request(repr);
+
+    Err(ApiError::NotImplemented(format!(
+        "The model `{name}` cannot produce embeddings: mistral.rs 0.3 does not expose hidden \
+         states."
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::fake::{app, post, FakeEngine};
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    async fn embed(request: Value) -> (StatusCode, Value) {
+        let (status, body) = post(app(FakeEngine::new([])), "/v1/embeddings", request).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn answers_not_implemented() {
+        let (status, body) = embed(json!({ "input": ["a", "b c d"] })).await;
+        assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(body["error"]["type"], "server_error");
+        assert!(body["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("cannot produce embeddings"));
+    }
+
+    #[tokio::test]
+    async fn unknown_models_are_not_found() {
+        let (status, _) = embed(json!({ "model": "other", "input": "a" })).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,594 @@
+//! What the HTTP layer needs from a model: queue a request and get its responses back on the
+//! request's own channel. [`MistralRsEngine`] serves it from mistral.rs, tests use
+//! [`fake::FakeEngine`].
+
+use indexmap::IndexMap;
+use mistralrs::{
+    ChatCompletionChunkResponse, ChatCompletionResponse, CompletionChunkResponse,
+    CompletionResponse, MessageContent, MistralRs, Pipeline, Request,
+};
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::{adapters::Adapters, error::ApiError};
+
+pub trait Engine: Send + Sync {
+    /// Queues `request` without waiting. Its responses arrive on the request's `response`
+    /// channel, which is dropped once the request is finished.
+    fn submit(&self, request: Request) -> Result<(), ApiError>;
+
+    fn next_request_id(&self) -> usize;
+
+    /// Token ids of `input` as the model would see it. Blocks while the engine runs a step.
+    fn tokenize(&self, input: TokenizeInput) -> Result<Tokenized, ApiError>;
+
+    /// Text of `tokens`. Blocks while the engine runs a step.
+    fn detokenize(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String, ApiError>;
+
+    /// Number of token ids the model knows, `None` without a tokenizer.
+    fn vocab_size(&self) -> Option<usize>;
+
+    /// The model's LoRA or X-LoRA adapters, `None` if it was loaded without any.
+    fn adapters(&self) -> Option<&Adapters>;
+
+    /// Requests waiting to be picked up, `None` if the engine is not running.
+    fn queue_depth(&self) -> Option<usize>;
+
+    /// Whether finished prompts are kept for later requests that start with them.
+    fn prefix_cache_enabled(&self) -> bool;
+
+    /// Unix time the engine was created, in seconds.
+    fn creation_time(&self) -> u64;
+
+    fn log_request(&self, repr: String);
+
+    fn log_response(&self, response: LoggedResponse<'_>);
+
+    fn log_error(&self, error: &dyn std::error::Error);
+}
+
+/// What [`Engine::tokenize`] encodes.
+#[derive(Debug, Clone)]
+pub enum TokenizeInput {
+    /// Raw text, with the tokenizer's special tokens (such as BOS) if `add_special_tokens`.
+    Text {
+        text: String,
+        add_special_tokens: bool,
+    },
+    /// Messages rendered through the chat template exactly as for a chat completion.
+    Chat {
+        messages: Vec<IndexMap<String, MessageContent>>,
+        add_generation_prompt: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Tokenized {
+    pub tokens: Vec<u32>,
+    /// The text that was encoded: the input itself, or the rendered chat prompt.
+    pub prompt: String,
+    /// Longest sequence the model takes, prompt and completion together.
+    pub max_model_len: usize,
+}
+
+/// Responses passed to [`Engine::log_response`], serialized as themselves.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoggedResponse<'a> {
+    Chat(&'a ChatCompletionResponse),
+    ChatChunk(&'a ChatCompletionChunkResponse),
+    Completion(&'a CompletionResponse),
+    CompletionChunk(&'a CompletionChunkResponse),
+}
+
+/// A model loaded and run by mistral.rs.
+pub struct MistralRsEngine {
+    pub mistralrs: Arc<MistralRs>,
+    /// The runner's own pipeline, shared to reach its tokenizer and chat template.
+    pub pipeline: Arc<Mutex<dyn Pipeline + Send + Sync>>,
+    /// Read once at load, so requests can be checked without waiting for the pipeline.
+    pub vocab_size: Option<usize>,
+    /// Prefix caching was asked for and the scheduler supports it.
+    pub prefix_cache: bool,
+    pub adapters: Option<Adapters>,
+}
+
+impl Engine for MistralRsEngine {
+    fn submit(&self, request: Request) -> Result<(), ApiError> {
+        let sender = self
+            .mistralrs
+            .get_sender()
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        sender.try_send(request)?;
+        Ok(())
+    }
+
+    fn next_request_id(&self) -> usize {
+        self.mistralrs.next_request_id()
+    }
+
+    fn tokenize(&self, input: TokenizeInput) -> Result<Tokenized, ApiError> {
+        let pipeline = self.pipeline.blocking_lock();
+        let max_model_len = pipeline.get_metadata().max_seq_len;
+        let (tokens, prompt) = match input {
+            TokenizeInput::Text {
+                text,
+                add_special_tokens,
+            } => {
+                let encoding = pipeline
+                    .tokenizer()
+                    .ok_or_else(no_tokenizer)?
+                    .encode(text.as_str(), add_special_tokens)
+                    .map_err(|e| ApiError::internal(e.to_string()))?;
+                (encoding.get_ids().to_vec(), text)
+            }
+            // The same processor the engine uses, so the ids match what a chat request sends.
+            TokenizeInput::Chat {
+                messages,
+                add_generation_prompt,
+            } => pipeline
+                .get_processor()
+                .process(&*pipeline, messages, add_generation_prompt, Vec::new())
+                .map_err(|e| ApiError::invalid_request(format!("{e:#}")).with_param("messages"))?,
+        };
+        Ok(Tokenized {
+            tokens,
+            prompt,
+            max_model_len,
+        })
+    }
+
+    fn detokenize(&self, tokens: &[u32], skip_special_tokens: bool) -> Result<String, ApiError> {
+        let tokenizer = self
+            .pipeline
+            .blocking_lock()
+            .tokenizer()
+            .ok_or_else(no_tokenizer)?;
+        let vocab_size = tokenizer.get_vocab_size(true);
+        if let Some(i) = tokens.iter().position(|&t| t as usize >= vocab_size) {
+            return Err(ApiError::invalid_request(format!(
+                "Token {} at index {i} is not in the vocabulary of {vocab_size} tokens.",
+                tokens[i]
+            ))
+            .with_param("tokens"));
+        }
+        tokenizer
+            .decode(tokens, skip_special_tokens)
+            .map_err(|e| ApiError::internal(e.to_string()))
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        self.vocab_size
+    }
+
+    fn adapters(&self) -> Option<&Adapters> {
+        self.adapters.as_ref()
+    }
+
+    fn queue_depth(&self) -> Option<usize> {
+        let sender = self.mistralrs.get_sender().ok()?;
+        Some(sender.max_capacity() - sender.capacity())
+    }
+
+    fn prefix_cache_enabled(&self) -> bool {
+        self.prefix_cache
+    }
+
+    fn creation_time(&self) -> u64 {
+        self.mistralrs.get_creation_time()
+    }
+
+    fn log_request(&self, repr: String) {
+        MistralRs::maybe_log_request(self.mistralrs.clone(), repr);
+    }
+
+    fn log_response(&self, response: LoggedResponse<'_>) {
+        MistralRs::maybe_log_response(self.mistralrs.clone(), &response);
+    }
+
+    fn log_error(&self, error: &dyn std::error::Error) {
+        MistralRs::maybe_log_error(self.mistralrs.clone(), error);
+    }
+}
+
+fn no_tokenizer() -> ApiError {
+    ApiError::invalid_request("This model has no tokenizer.").with_param("model")
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    //! A scripted engine and helpers to call the router with it.
+
+    use axum::{
+        body::Body,
+        http::{header, Request as HttpRequest, StatusCode},
+    };
+    use mistralrs::{
+        ChatCompletionChunkResponse, ChatCompletionResponse, Choice, ChunkChoice, CompletionChoice,
+        CompletionChunkChoice, CompletionChunkResponse, CompletionResponse, Delta, NormalRequest,
+        Request, Response, ResponseMessage, Usage,
+    };
+    use serde_json::Value;
+    use std::{
+        collections::VecDeque,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::sync::mpsc::{channel, error::TrySendError};
+    use tower::ServiceExt as _;
+
+    use super::{Engine, LoggedResponse, TokenizeInput, Tokenized};
+    use crate::{
+        adapters::Adapters,
+        admission::Admission,
+        config::{AdmissionConfig, ImagePolicy, LimitsConfig},
+        error::ApiError,
+        health::Health,
+        journal::Journal,
+        metrics::Metrics,
+        registry::{ModelInfo, ModelRegistry},
+        shutdown::Shutdown,
+        state::AppState,
+        util::ImageFetcher,
+    };
+
+    pub const MODEL: &str = "fake";
+    const BOS: u32 = 1;
+
+    /// Replays one scripted list of responses per submitted request, in order, then closes the
+    /// request's channel. With no script left, `submit` fails as if the queue were full.
+    #[derive(Default)]
+    pub struct FakeEngine {
+        scripts: Mutex<VecDeque<Vec<Response>>>,
+        requests: Mutex<Vec<NormalRequest>>,
+        /// Keep the channel open after the script, like a generation that never finishes.
+        hang: bool,
+        adapters: Option<Adapters>,
+    }
+
+    impl FakeEngine {
+        pub fn new(scripts: impl IntoIterator<Item = Vec<Response>>) -> Arc<Self> {
+            Arc::new(Self {
+                scripts: Mutex::new(scripts.into_iter().collect()),
+                ..Self::default()
+            })
+        }
+
+        /// Like `new`, but requests stay open after their script until the client goes away.
+        pub fn hanging(scripts: impl IntoIterator<Item = Vec<Response>>) -> Arc<Self> {
+            Arc::new(Self {
+                scripts: Mutex::new(scripts.into_iter().collect()),
+                hang: true,
+                ..Self::default()
+            })
+        }
+
+        /// Like `new`, for a model loaded with `adapters`.
+        pub fn with_adapters(
+            scripts: impl IntoIterator<Item = Vec<Response>>,
+            adapters: Adapters,
+        ) -> Arc<Self> {
+            Arc::new(Self {
+                scripts: Mutex::new(scripts.into_iter().collect()),
+                adapters: Some(adapters),
+                ..Self::default()
+            })
+        }
+
+        /// Requests submitted so far, with their response channel replaced by a closed one.
+        pub fn requests(&self) -> std::sync::MutexGuard<'_, Vec<NormalRequest>> {
+            self.requests.lock().unwrap()
+        }
+    }
+
+    impl Engine for FakeEngine {
+        fn submit(&self, request: Request) -> Result<(), ApiError> {
+            let Request::Normal(mut request) = request else {
+                panic!("The fake engine only takes normal requests.");
+            };
+            let Some(script) = self.scripts.lock().unwrap().pop_front() else {
+                return Err(TrySendError::Full(()).into());
+            };
+            let tx = std::mem::replace(&mut request.response, channel(1).0);
+            self.requests.lock().unwrap().push(request);
+            let hang = self.hang;
+            tokio::spawn(async move {
+                for response in script {
+                    if tx.send(response).await.is_err() {
+                        return;
+                    }
+                }
+                if hang {
+                    tx.closed().await;
+                }
+            });
+            Ok(())
+        }
+
+        fn next_request_id(&self) -> usize {
+            self.requests.lock().unwrap().len()
+        }
+
+        /// One token per character, its code point, after BOS (1) if asked for. Chat messages
+        /// render as `<|role|>content` lines, ending with `<|assistant|>` for a generation.
+        fn tokenize(&self, input: TokenizeInput) -> Result<Tokenized, ApiError> {
+            let (prompt, bos) = match input {
+                TokenizeInput::Text {
+                    text,
+                    add_special_tokens,
+                } => (text, add_special_tokens),
+                TokenizeInput::Chat {
+                    messages,
+                    add_generation_prompt,
+                } => {
+                    let mut prompt = messages
+                        .iter()
+                        .map(|m| {
+                            format!(
+                                "<|{}|>{}\n",
+                                m["role"].as_ref().unwrap_left(),
+                                m["content"].as_ref().unwrap_left()
+                            )
+                        })
+                        .collect::<String>();
+                    if add_generation_prompt {
+                        prompt.push_str("<|assistant|>");
+                    }
+                    (prompt, false)
+                }
+            };
+            let tokens = bos
+                .then_some(BOS)
+                .into_iter()
+                .chain(prompt.chars().map(u32::from))
+                .collect();
+            Ok(Tokenized {
+                tokens,
+                prompt,
+                max_model_len: 32,
+            })
+        }
+
+        fn detokenize(
+            &self,
+            tokens: &[u32],
+            skip_special_tokens: bool,
+        ) -> Result<String, ApiError> {
+            tokens
+                .iter()
+                .filter(|&&t| !(skip_special_tokens && t == BOS))
+                .map(|&t| match t {
+                    BOS => Ok("<s>".to_string()),
+                    t => char::from_u32(t).map(String::from).ok_or_else(|| {
+                        ApiError::invalid_request(format!("Unknown token {t}."))
+                            .with_param("tokens")
+                    }),
+                })
+                .collect()
+        }
+
+        /// Every Unicode scalar value, as `tokenize` uses code points.
+        fn vocab_size(&self) -> Option<usize> {
+            Some(char::MAX as usize + 1)
+        }
+
+        fn adapters(&self) -> Option<&Adapters> {
+            self.adapters.as_ref()
+        }
+
+        fn queue_depth(&self) -> Option<usize> {
+            Some(0)
+        }
+
+        fn prefix_cache_enabled(&self) -> bool {
+            false
+        }
+
+        fn creation_time(&self) -> u64 {
+            0
+        }
+
+        fn log_request(&self, _repr: String) {}
+
+        fn log_response(&self, _response: LoggedResponse<'_>) {}
+
+        fn log_error(&self, _error: &dyn std::error::Error) {}
+    }
+
+    /// App state serving `engine` as [`MODEL`], loaded and ready. Images may only be data URLs.
+    pub fn app(engine: Arc<FakeEngine>) -> Arc<AppState> {
+        let app = loading_app(engine);
+        app.health.set_loaded();
+        app
+    }
+
+    /// Like [`app`], with its own admission queue settings.
+    pub fn app_with_admission(
+        engine: Arc<FakeEngine>,
+        admission: AdmissionConfig,
+    ) -> Arc<AppState> {
+        let app = Arc::new(build_app(engine, admission));
+        app.health.set_loaded();
+        app
+    }
+
+    /// Like [`app`], but still loading: the model is registered, the server is not ready.
+    pub fn loading_app(engine: Arc<FakeEngine>) -> Arc<AppState> {
+        let admission = AdmissionConfig {
+            max_running: 16,
+            max_queue: 16,
+            queue_timeout: None,
+        };
+        Arc::new(build_app(engine, admission))
+    }
+
+    /// Like [`app`], journaling requests to `journal`.
+    pub fn app_with_journal(engine: Arc<FakeEngine>, journal: Journal) -> Arc<AppState> {
+        let admission = AdmissionConfig {
+            max_running: 16,
+            max_queue: 16,
+            queue_timeout: None,
+        };
+        let mut app = build_app(engine, admission);
+        app.journal = Some(journal);
+        app.health.set_loaded();
+        Arc::new(app)
+    }
+
+    fn build_app(engine: Arc<FakeEngine>, admission: AdmissionConfig) -> AppState {
+        let models = ModelRegistry::new();
+        let info = ModelInfo {
+            model_id: "fake/model".to_string(),
+            device: "cpu".to_string(),
+            quantization: "Q4K".to_string(),
+        };
+        models.insert(MODEL.to_string(), engine, info);
+        let images = ImagePolicy {
+            schemes: vec!["data".into()],
+            allowed_hosts: Vec::new(),
+            local_root: None,
+            max_bytes: 1024 * 1024,
+            max_width: 64,
+            max_height: 64,
+            timeout: Duration::from_secs(1),
+            cache_size: 0,
+        };
+        AppState {
+            models,
+            admission: Arc::new(Admission::new(admission)),
+            limits: LimitsConfig {
+                max_tokens: Some(1024),
+                max_n: 4,
+            },
+            images: ImageFetcher::new(images).unwrap(),
+            metrics: Arc::new(Metrics::new().unwrap()),
+            journal: None,
+            shutdown: Arc::new(Shutdown::new()),
+            health: Health::new(),
+        }
+    }
+
+    /// Posts `body` to `uri` on the full router and returns the status and body text.
+    pub async fn post(app: Arc<AppState>, uri: &str, body: Value) -> (StatusCode, String) {
+        let request = HttpRequest::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = crate::get_router(app, None).oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /// Fresh directory under the system temp dir, removed on drop.
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("isq-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// The `data:` payloads of an SSE body, keep-alive comments left out.
+    pub fn sse_data(body: &str) -> Vec<String> {
+        body.lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(str::to_string)
+            .collect()
+    }
+
+    fn usage() -> Usage {
+        Usage {
+            completion_tokens: 2,
+            prompt_tokens: 3,
+            total_tokens: 5,
+            ..Usage::default()
+        }
+    }
+
+    pub fn chat_done(content: &str, finish_reason: &str) -> ChatCompletionResponse {
+        ChatCompletionResponse {
+            id: "0".to_string(),
+            choices: vec![Choice {
+                finish_reason: finish_reason.to_string(),
+                index: 0,
+                message: ResponseMessage {
+                    content: Some(content.to_string()),
+                    role: "assistant".to_string(),
+                    tool_calls: Vec::new(),
+                },
+                logprobs: None,
+            }],
+            created: 0,
+            model: MODEL.to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion".to_string(),
+            usage: usage(),
+        }
+    }
+
+    pub fn chat_chunk(content: &str, finish_reason: Option<&str>) -> Response {
+        Response::Chunk(ChatCompletionChunkResponse {
+            id: "0".to_string(),
+            choices: vec![ChunkChoice {
+                finish_reason: finish_reason.map(str::to_string),
+                index: 0,
+                delta: Delta {
+                    content: content.to_string(),
+                    role: "assistant".to_string(),
+                    tool_calls: None,
+                },
+                logprobs: None,
+            }],
+            created: 0,
+            model: MODEL.to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "chat.completion.chunk".to_string(),
+        })
+    }
+
+    pub fn completion_done(text: &str) -> CompletionResponse {
+        CompletionResponse {
+            id: "0".to_string(),
+            choices: vec![CompletionChoice {
+                finish_reason: "stop".to_string(),
+                index: 0,
+                text: text.to_string(),
+                logprobs: None,
+            }],
+            created: 0,
+            model: MODEL.to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "text_completion".to_string(),
+            usage: usage(),
+        }
+    }
+
+    pub fn completion_chunk(text: &str, finish_reason: Option<&str>) -> Response {
+        Response::CompletionChunk(CompletionChunkResponse {
+            id: "0".to_string(),
+            choices: vec![CompletionChunkChoice {
+                text: text.to_string(),
+                index: 0,
+                logprobs: None,
+                finish_reason: finish_reason.map(str::to_string),
+            }],
+            created: 0,
+            model: MODEL.to_string(),
+            system_fingerprint: "local".to_string(),
+            object: "text_completion".to_string(),
+        })
+    }
+}
@@ -0,0 +1,266 @@
+//! Errors returned by the HTTP layer, rendered in the OpenAI error schema.
+
+use axum::{
+    async_trait,
+    extract::{rejection::JsonRejection, FromRequest, Request},
+    http::{header, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt, time::Duration};
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::openai::{ErrorDetail, ErrorResponse};
+
+/// How long clients are told to wait when the engine's request queue is full.
+const ENGINE_QUEUE_FULL_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400: the request is malformed or has an invalid parameter.
+    InvalidRequest {
+        message: String,
+        param: Option<String>,
+    },
+    /// 401: the API key is missing or unknown.
+    Unauthorized(String),
+    /// 403: the API key may not do this, e.g. it is not an admin key.
+    Forbidden(String),
+    /// 404: the requested model is not loaded.
+    ModelNotFound(String),
+    /// 404: another resource named in the path does not exist, e.g. an adapter.
+    NotFound(String),
+    /// 413: the request body is larger than the server accepts.
+    PayloadTooLarge(String),
+    /// 422: the request is well-formed but cannot be processed, e.g. the engine rejected it.
+    Unprocessable {
+        message: String,
+        param: Option<String>,
+    },
+    /// 429: the caller is over its budget, or the engine queue is full.
+    RateLimited {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    /// 500: something went wrong on our side.
+    Internal(String),
+    /// 501: the server has the route but cannot serve it, e.g. mistral.rs lacks the feature.
+    NotImplemented(String),
+    /// 503: the server cannot take or finish the request right now, e.g. it is shutting down.
+    Unavailable(String),
+}
+
+impl ApiError {
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::InvalidRequest {
+            message: message.into(),
+            param: None,
+        }
+    }
+
+    pub fn unprocessable(message: impl Into<String>) -> Self {
+        Self::Unprocessable {
+            message: message.into(),
+            param: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::Internal(message.into())
+    }
+
+    /// Names the request parameter at fault. Only meaningful for 400 and 422.
+    pub fn with_param(mut self, name: impl Into<String>) -> Self {
+        if let Self::InvalidRequest { param, .. } | Self::Unprocessable { param, .. } = &mut self {
+            *param = Some(name.into());
+        }
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::ModelNotFound(_) | Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::Unprocessable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotImplemented(_) => StatusCode::NOT_IMPLEMENTED,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn detail(&self) -> ErrorDetail {
+        let (message, tp, param, code) = match self {
+            Self::InvalidRequest { message, param } => (
+                message.clone(),
+                "invalid_request_error",
+                param.clone(),
+                None,
+            ),
+            Self::Unauthorized(message) => (
+                message.clone(),
+                "invalid_request_error",
+                None,
+                Some("invalid_api_key"),
+            ),
+            Self::Forbidden(message) => (
+                message.clone(),
+                "invalid_request_error",
+                None,
+                Some("insufficient_permissions"),
+            ),
+            Self::ModelNotFound(model) => (
+                format!("The model `{model}` does not exist."),
+                "invalid_request_error",
+                Some("model".to_string()),
+                Some("model_not_found"),
+            ),
+            Self::NotFound(message) => (
+                message.clone(),
+                "invalid_request_error",
+                None,
+                Some("not_found"),
+            ),
+            Self::PayloadTooLarge(message) => (
+                message.clone(),
+                "invalid_request_error",
+                None,
+                Some("payload_too_large"),
+            ),
+            Self::Unprocessable { message, param } => (
+                message.clone(),
+                "invalid_request_error",
+                param.clone(),
+                None,
+            ),
+            Self::RateLimited { message, .. } => (
+                message.clone(),
+                "rate_limit_error",
+                None,
+                Some("rate_limit_exceeded"),
+            ),
+            Self::Internal(message)
+            | Self::NotImplemented(message)
+            | Self::Unavailable(message) => (message.clone(), "server_error", None, None),
+        };
+        ErrorDetail {
+            message,
+            tp: tp.to_string(),
+            param,
+            code: code.map(str::to_string),
+        }
+    }
+
+    pub fn body(&self) -> ErrorResponse {
+        ErrorResponse {
+            error: self.detail(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.detail().message)
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> axum::response::Response {
+        let mut r = (self.status(), Json(self.body())).into_response();
+        match self {
+            Self::RateLimited {
+                retry_after: Some(retry_after),
+                ..
+            } => {
+                // Round up so clients never retry before the budget is back.
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                r.headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            }
+            Self::Unauthorized(_) => {
+                r.headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => {}
+        }
+        r
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        let message = rejection.body_text();
+        match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge(message),
+            StatusCode::UNPROCESSABLE_ENTITY => Self::unprocessable(message),
+            _ => Self::invalid_request(message),
+        }
+    }
+}
+
+impl<T> From<TrySendError<T>> for ApiError {
+    fn from(e: TrySendError<T>) -> Self {
+        match e {
+            TrySendError::Full(_) => Self::RateLimited {
+                message: "The engine queue is full, try again later.".to_string(),
+                retry_after: Some(ENGINE_QUEUE_FULL_RETRY_AFTER),
+            },
+            TrySendError::Closed(_) => Self::internal("The engine is not running."),
+        }
+    }
+}
+
+/// `Json` extractor whose rejections (bad JSON, wrong types, body too large) use the
+/// OpenAI error schema instead of axum's plain-text bodies.
+pub struct OaiJson<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for OaiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// Error body for a generation that failed part-way, with what was generated so far.
+#[derive(Serialize)]
+pub struct JsonModelError<T> {
+    error: ErrorDetail,
+    partial_response: T,
+}
+
+impl<T: Serialize> JsonModelError<T> {
+    pub fn new(message: String, partial_response: T) -> Self {
+        Self {
+            error: ApiError::Internal(message).detail(),
+            partial_response,
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for JsonModelError<T> {
+    fn into_response(self) -> axum::response::Response {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ModelErrorMessage(pub(crate) String);
+impl std::fmt::Display for ModelErrorMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+impl std::error::Error for ModelErrorMessage {}
@@ -0,0 +1,386 @@
+//! Liveness and readiness probes.
+//!
+//! `/health/live` answers as long as the server does. `/health/ready` (and `/health`) fails
+//! until every model is loaded, while the server drains for shutdown, and while a model's last
+//! `canary_failures` canaries failed. The canary is a one-token completion sent through the
+//! normal queue, so it also catches an engine whose scheduler stopped picking up work. A canary
+//! turned away because the queue is full only says the model is busy: it neither counts as a
+//! failure nor clears earlier ones.
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json},
+};
+use mistralrs::{Constraint, NormalRequest, Request, RequestMessage, Response, SamplingParams};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{sync::mpsc::channel, time::MissedTickBehavior};
+
+use crate::{
+    config::HealthConfig, engine::Engine, error::ApiError, registry::ModelInfo, state::AppState,
+};
+
+const CANARY_PROMPT: &str = "Hello";
+
+pub struct Health {
+    started: Instant,
+    loaded: AtomicBool,
+    /// Last canary result by model name.
+    canaries: Mutex<HashMap<String, Canary>>,
+}
+
+#[derive(Debug, Clone)]
+struct Canary {
+    at: Instant,
+    outcome: Outcome,
+    /// Failed canaries in a row, busy ones skipped.
+    failures: u32,
+    /// `failures` reached the configured threshold.
+    unhealthy: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Outcome {
+    /// Generated its token in this time.
+    Ok(Duration),
+    /// The engine queue was full.
+    Busy,
+    /// Timed out or got an error from the engine.
+    Failed(String),
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            loaded: AtomicBool::new(false),
+            canaries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Every configured model is in the registry.
+    pub fn set_loaded(&self) {
+        self.loaded.store(true, Ordering::SeqCst);
+    }
+
+    fn record(&self, model: String, outcome: Outcome, threshold: u32) {
+        let mut canaries = self.canaries.lock().unwrap();
+        let failures = canaries.get(&model).map_or(0, |canary| canary.failures);
+        let failures = match &outcome {
+            Outcome::Ok(_) => 0,
+            Outcome::Busy => failures,
+            Outcome::Failed(e) => {
+                eprintln!(
+                    "Warning: canary for model `{model}` failed ({} in a row): {e}",
+                    failures + 1
+                );
+                failures + 1
+            }
+        };
+        let canary = Canary {
+            at: Instant::now(),
+            outcome,
+            failures,
+            unhealthy: failures >= threshold,
+        };
+        canaries.insert(model, canary);
+    }
+}
+
+#[derive(Serialize)]
+struct HealthReport {
+    status: &'static str,
+    uptime_secs: u64,
+    models: Vec<ModelReport>,
+}
+
+#[derive(Serialize)]
+struct ModelReport {
+    name: String,
+    #[serde(flatten)]
+    info: ModelInfo,
+    /// Requests waiting for the engine, `null` if the engine is not running.
+    queue_length: Option<usize>,
+    canary: Option<CanaryReport>,
+}
+
+#[derive(Serialize)]
+struct CanaryReport {
+    /// The last canary generated its token.
+    ok: bool,
+    /// The last canary was turned away by a full queue.
+    busy: bool,
+    consecutive_failures: u32,
+    secs_ago: u64,
+    latency_ms: Option<u64>,
+    error: Option<String>,
+    #[serde(skip)]
+    unhealthy: bool,
+}
+
+impl HealthReport {
+    fn new(app: &AppState) -> (bool, Self) {
+        let health = &app.health;
+        let canaries = health.canaries.lock().unwrap().clone();
+        let models = app
+            .models
+            .list()
+            .into_iter()
+            .map(|(name, model)| {
+                let canary = canaries.get(&name).map(|canary| CanaryReport {
+                    ok: matches!(canary.outcome, Outcome::Ok(_)),
+                    busy: matches!(canary.outcome, Outcome::Busy),
+                    consecutive_failures: canary.failures,
+                    secs_ago: canary.at.elapsed().as_secs(),
+                    latency_ms: match canary.outcome {
+                        Outcome::Ok(latency) => Some(latency.as_millis() as u64),
+                        _ => None,
+                    },
+                    error: match &canary.outcome {
+                        Outcome::Failed(e) => Some(e.clone()),
+                        _ => None,
+                    },
+                    unhealthy: canary.unhealthy,
+                });
+                ModelReport {
+                    name,
+                    info: model.info,
+                    queue_length: model.engine.queue_depth(),
+                    canary,
+                }
+            })
+            .collect::<Vec<_>>();
+        let status = if app.shutdown.is_draining() {
+            "draining"
+        } else if !health.loaded.load(Ordering::SeqCst) {
+            "loading"
+        } else if models
+            .iter()
+            .any(|m| m.queue_length.is_none() || m.canary.as_ref().is_some_and(|c| c.unhealthy))
+        {
+            "unhealthy"
+        } else {
+            "ready"
+        };
+        let report = Self {
+            status,
+            uptime_secs: health.started.elapsed().as_secs(),
+            models,
+        };
+        (status == "ready", report)
+    }
+}
+
+/// 200 while the process serves requests at all.
+pub async fn live(State(app): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(HealthReport::new(&app).1)
+}
+
+/// 200 when requests can be served, 503 otherwise. The body says why.
+pub async fn ready(State(app): State<Arc<AppState>>) -> impl IntoResponse {
+    let (ready, report) = HealthReport::new(&app);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Runs a canary for every model every `canary_interval`, starting now.
+pub fn spawn_canaries(app: Arc<AppState>, config: HealthConfig) {
+    if config.canary_interval.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(config.canary_interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            run_canaries(&app, &config).await;
+        }
+    });
+}
+
+/// One canary per model, recorded in `app.health`.
+pub async fn run_canaries(app: &AppState, config: &HealthConfig) {
+    for (name, model) in app.models.list() {
+        let outcome = canary(&*model.engine, config.canary_timeout).await;
+        app.health.record(name, outcome, config.canary_failures);
+    }
+}
+
+/// Generates one token, timing it.
+async fn canary(engine: &dyn Engine, timeout: Duration) -> Outcome {
+    let (tx, mut rx) = channel(1);
+    let request = Request::Normal(NormalRequest {
+        id: engine.next_request_id(),
+        messages: RequestMessage::Completion {
+            text: CANARY_PROMPT.to_string(),
+            echo_prompt: false,
+            best_of: 1,
+        },
+        sampling_params: SamplingParams {
+            max_len: Some(1),
+            ..SamplingParams::default()
+        },
+        response: tx,
+        return_logprobs: false,
+        is_streaming: false,
+        suffix: None,
+        constraint: Constraint::None,
+        adapters: None,
+        tool_choice: None,
+        tools: None,
+        logits_processors: None,
+    });
+    let start = Instant::now();
+    match engine.submit(request) {
+        Ok(()) => {}
+        // The queue is full: the engine has work, it isn't necessarily stuck.
+        Err(ApiError::RateLimited { .. }) => return Outcome::Busy,
+        Err(e) => return Outcome::Failed(e.to_string()),
+    }
+    let failed = |e: String| Outcome::Failed(e);
+    match tokio::time::timeout(timeout, rx.recv()).await {
+        Err(_) => failed(format!("No answer within {:.0}s.", timeout.as_secs_f64())),
+        Ok(None) => failed("The engine dropped the request.".to_string()),
+        Ok(Some(Response::CompletionDone(_))) => Outcome::Ok(start.elapsed()),
+        Ok(Some(Response::CompletionModelError(e, _))) => failed(e),
+        Ok(Some(Response::ValidationError(e) | Response::InternalError(e))) => {
+            failed(e.to_string())
+        }
+        Ok(Some(_)) => failed("Unexpected response to a completion request.".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{app, completion_done, loading_app, FakeEngine};
+    use axum::{body::Body, http::Request as HttpRequest};
+    use serde_json::Value;
+    use tower::ServiceExt as _;
+
+    async fn get(app: &Arc<AppState>, uri: &str) -> (StatusCode, Value) {
+        let response = crate::get_router(app.clone(), None)
+            .oneshot(HttpRequest::get(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_loading() {
+        let app = loading_app(FakeEngine::new([]));
+        let (status, body) = get(&app, "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "loading");
+        let (status, _) = get(&app, "/health/live").await;
+        assert_eq!(status, StatusCode::OK);
+
+        app.health.set_loaded();
+        let (status, body) = get(&app, "/health").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+    }
+
+    #[tokio::test]
+    async fn reports_the_models() {
+        let app = app(FakeEngine::new([]));
+        let (_, body) = get(&app, "/health/ready").await;
+        let model = &body["models"][0];
+        assert_eq!(model["name"], "fake");
+        assert_eq!(model["model_id"], "fake/model");
+        assert_eq!(model["device"], "cpu");
+        assert_eq!(model["quantization"], "Q4K");
+        assert_eq!(model["queue_length"], 0);
+        assert!(body["uptime_secs"].is_u64());
+    }
+
+    fn config(canary_failures: u32) -> HealthConfig {
+        HealthConfig {
+            canary_interval: Duration::ZERO,
+            canary_timeout: Duration::from_secs(1),
+            canary_failures,
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_canaries_make_the_model_unhealthy_after_the_threshold() {
+        let error = || vec![Response::InternalError("scheduler stuck".into())];
+        let engine = FakeEngine::new([
+            error(),
+            error(),
+            vec![Response::CompletionDone(completion_done("!"))],
+        ]);
+        let app = app(engine.clone());
+        run_canaries(&app, &config(2)).await;
+        let (status, body) = get(&app, "/health/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        let canary = &body["models"][0]["canary"];
+        assert_eq!(canary["ok"], false);
+        assert_eq!(canary["consecutive_failures"], 1);
+        assert_eq!(canary["error"], "scheduler stuck");
+
+        run_canaries(&app, &config(2)).await;
+        let (status, body) = get(&app, "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "unhealthy");
+        assert_eq!(body["models"][0]["canary"]["consecutive_failures"], 2);
+
+        run_canaries(&app, &config(2)).await;
+        let (status, body) = get(&app, "/health/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["models"][0]["canary"]["ok"], true);
+        assert_eq!(body["models"][0]["canary"]["consecutive_failures"], 0);
+
+        let canary = &engine.requests()[0];
+        assert!(matches!(canary.messages, RequestMessage::Completion { .. }));
+        assert_eq!(canary.sampling_params.max_len, Some(1));
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_busy_not_unhealthy() {
+        // With no script left, the fake engine's queue is full.
+        let app = app(FakeEngine::new([vec![Response::InternalError(
+            "scheduler stuck".into(),
+        )]]));
+        run_canaries(&app, &config(2)).await;
+        for _ in 0..5 {
+            run_canaries(&app, &config(2)).await;
+        }
+        let (status, body) = get(&app, "/health/ready").await;
+        assert_eq!(status, StatusCode::OK);
+        let canary = &body["models"][0]["canary"];
+        assert_eq!(canary["busy"], true);
+        assert_eq!(canary["ok"], false);
+        // The failure before is neither cleared nor added to.
+        assert_eq!(canary["consecutive_failures"], 1);
+    }
+
+    #[tokio::test]
+    async fn stuck_canary_times_out() {
+        let app = app(FakeEngine::hanging([vec![]]));
+        let config = HealthConfig {
+            canary_timeout: Duration::from_millis(20),
+            ..config(1)
+        };
+        run_canaries(&app, &config).await;
+        let (status, body) = get(&app, "/health/ready").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["models"][0]["canary"]["ok"], false);
+    }
+}
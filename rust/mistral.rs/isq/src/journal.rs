@@ -0,0 +1,525 @@
+//! Request journal: one JSONL record per finished request, for offline evaluation.
+//!
+//! Records are written by a background thread to `path`, which is rotated to `path.1`,
+//! `path.2`, ... once it would grow past `max_bytes`. If the writer falls behind, records are
+//! dropped rather than slowing down requests.
+
+use anyhow::Context as _;
+use mistralrs::{ChatCompletionChunkResponse, CompletionChunkResponse};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write as _,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::JournalConfig;
+
+/// Records waiting for the writer thread.
+const QUEUE_LEN: usize = 1024;
+/// Request fields whose strings are replaced by [`REDACTED`] when redaction is on, at any depth.
+/// `messages` is here for the bare string prompt it may be.
+const REDACTED_FIELDS: [&str; 7] = [
+    "content", "input", "messages", "prompt", "suffix", "text", "url",
+];
+const REDACTED: &str = "[redacted]";
+
+pub struct Journal {
+    tx: SyncSender<String>,
+    redact: bool,
+    /// Distinguishes ids across restarts.
+    id_prefix: String,
+    next_id: AtomicU64,
+}
+
+impl Journal {
+    pub fn open(config: &JournalConfig) -> anyhow::Result<Self> {
+        let writer = Writer::open(config)?;
+        let (tx, rx) = mpsc::sync_channel::<String>(QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("isq-journal".to_string())
+            .spawn(move || {
+                let mut writer = writer;
+                for line in rx {
+                    if let Err(e) = writer.write(&line) {
+                        eprintln!("Could not write to the request journal: {e:#}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            tx,
+            redact: config.redact,
+            id_prefix: format!("{:x}", unix_ms()),
+            next_id: AtomicU64::new(0),
+        })
+    }
+
+    /// Starts a record for a request to `endpoint`.
+    pub fn record<T: Serialize>(&self, endpoint: &str, request: &T) -> JournalRecord {
+        let mut request = serde_json::to_value(request).unwrap_or(Value::Null);
+        if self.redact {
+            redact(&mut request);
+        }
+        JournalRecord {
+            tx: self.tx.clone(),
+            id: format!(
+                "{}-{}",
+                self.id_prefix,
+                self.next_id.fetch_add(1, Ordering::Relaxed)
+            ),
+            timestamp_ms: unix_ms(),
+            start: Instant::now(),
+            endpoint: endpoint.to_string(),
+            request,
+        }
+    }
+}
+
+/// A request whose response is not finished yet.
+pub struct JournalRecord {
+    tx: SyncSender<String>,
+    id: String,
+    timestamp_ms: u128,
+    start: Instant,
+    endpoint: String,
+    request: Value,
+}
+
+impl JournalRecord {
+    /// Writes the record. `response` is the response body, or the body assembled from the stream.
+    pub fn finish(self, status: u16, response: Value, usage: Option<Value>) {
+        let line = json!({
+            "id": self.id,
+            "timestamp_ms": self.timestamp_ms,
+            "endpoint": self.endpoint,
+            "status": status,
+            "latency_ms": self.start.elapsed().as_secs_f64() * 1000.0,
+            "request": self.request,
+            "response": response,
+            "usage": usage,
+        })
+        .to_string();
+        match self.tx.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                eprintln!("Request journal is behind, dropping record {}.", self.id)
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Puts streamed chunks back together into one response, like the non-streaming one.
+#[derive(Default)]
+pub struct StreamAssembler {
+    head: Option<Value>,
+    /// Choices by index: role, content or text, tool calls and finish reason.
+    choices: BTreeMap<usize, AssembledChoice>,
+    error: Option<Value>,
+}
+
+#[derive(Default)]
+struct AssembledChoice {
+    role: Option<String>,
+    content: String,
+    tool_calls: Vec<Value>,
+    finish_reason: Option<String>,
+}
+
+impl StreamAssembler {
+    pub fn chat_chunk(&mut self, chunk: &ChatCompletionChunkResponse) {
+        self.head.get_or_insert_with(|| {
+            json!({
+                "id": chunk.id,
+                "object": "chat.completion",
+                "created": chunk.created,
+                "model": chunk.model,
+                "system_fingerprint": chunk.system_fingerprint,
+            })
+        });
+        for choice in &chunk.choices {
+            let assembled = self.choices.entry(choice.index).or_default();
+            assembled
+                .role
+                .get_or_insert_with(|| choice.delta.role.clone());
+            assembled.content.push_str(&choice.delta.content);
+            if let Some(tool_calls) = &choice.delta.tool_calls {
+                assembled.tool_calls.extend(
+                    tool_calls
+                        .iter()
+                        .filter_map(|c| serde_json::to_value(c).ok()),
+                );
+            }
+            if choice.finish_reason.is_some() {
+                assembled.finish_reason.clone_from(&choice.finish_reason);
+            }
+        }
+    }
+
+    pub fn completion_chunk(&mut self, chunk: &CompletionChunkResponse) {
+        self.head.get_or_insert_with(|| {
+            json!({
+                "id": chunk.id,
+                "object": "text_completion",
+                "created": chunk.created,
+                "model": chunk.model,
+                "system_fingerprint": chunk.system_fingerprint,
+            })
+        });
+        for choice in &chunk.choices {
+            let assembled = self.choices.entry(choice.index).or_default();
+            assembled.content.push_str(&choice.text);
+            if choice.finish_reason.is_some() {
+                assembled.finish_reason.clone_from(&choice.finish_reason);
+            }
+        }
+    }
+
+    /// Records the error event that ended the stream.
+    pub fn error<T: Serialize>(&mut self, error: &T) {
+        self.error = serde_json::to_value(error).ok();
+    }
+
+    pub fn into_value(self) -> Value {
+        let chat = self
+            .head
+            .as_ref()
+            .is_some_and(|head| head["object"] == "chat.completion");
+        let choices = self
+            .choices
+            .into_iter()
+            .map(|(index, choice)| {
+                if chat {
+                    json!({
+                        "index": index,
+                        "message": {
+                            "role": choice.role,
+                            "content": choice.content,
+                            "tool_calls": choice.tool_calls,
+                        },
+                        "finish_reason": choice.finish_reason,
+                    })
+                } else {
+                    json!({
+                        "index": index,
+                        "text": choice.content,
+                        "finish_reason": choice.finish_reason,
+                    })
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut value = self.head.unwrap_or_else(|| json!({}));
+        value["choices"] = Value::Array(choices);
+        if let Some(error) = self.error {
+            value["error"] = error;
+        }
+        value
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if REDACTED_FIELDS.contains(&key.as_str()) {
+                    redact_field(value);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Replaces the strings of a redacted field, also in a list such as a batch of prompts. Objects
+/// in it, like messages or content parts, are redacted field by field.
+fn redact_field(value: &mut Value) {
+    match value {
+        Value::String(_) => *value = Value::String(REDACTED.to_string()),
+        Value::Array(values) => values.iter_mut().for_each(redact_field),
+        _ => redact(value),
+    }
+}
+
+fn unix_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl Writer {
+    fn open(config: &JournalConfig) -> anyhow::Result<Self> {
+        if let Some(dir) = config
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir).with_context(|| {
+                format!("Could not create journal directory `{}`.", dir.display())
+            })?;
+        }
+        let (file, size) = open_append(&config.path)?;
+        Ok(Self {
+            path: config.path.clone(),
+            max_bytes: config.max_bytes,
+            max_files: config.max_files,
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> anyhow::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Shifts `path.N` to `path.N+1`, dropping the oldest, and starts a new `path`.
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        self.file.flush()?;
+        for i in (1..self.max_files).rev() {
+            let from = rotated(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(&self.path, 1))?;
+        (self.file, self.size) = open_append(&self.path)?;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> anyhow::Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open journal file `{}`.", path.display()))?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{i}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{
+        app_with_journal, chat_chunk, completion_chunk, post, sse_data, FakeEngine, TempDir,
+    };
+    use axum::http::StatusCode;
+    use mistralrs::Response;
+    use std::time::Duration;
+
+    fn config(dir: &TempDir, max_bytes: u64, redact: bool) -> JournalConfig {
+        JournalConfig {
+            path: dir.0.join("journal.jsonl"),
+            max_bytes,
+            max_files: 2,
+            redact,
+        }
+    }
+
+    /// Lines of `path`, waiting a little for the writer thread to get to them.
+    async fn lines(path: &Path, count: usize) -> Vec<Value> {
+        for _ in 0..100 {
+            let raw = fs::read_to_string(path).unwrap_or_default();
+            if raw.lines().count() >= count {
+                return raw
+                    .lines()
+                    .map(|line| serde_json::from_str(line).unwrap())
+                    .collect();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("{} never got {count} lines.", path.display());
+    }
+
+    #[test]
+    fn redacts_prompts_messages_and_image_urls() {
+        let mut request = json!({
+            "model": "fake",
+            "messages": [
+                { "role": "system", "content": "secret" },
+                { "role": "user", "content": [
+                    { "type": "text", "text": "secret" },
+                    { "type": "image_url", "image_url": { "url": "https://secret", "detail": "low" } },
+                ] },
+            ],
+            "max_tokens": 5,
+        });
+        redact(&mut request);
+        assert_eq!(
+            request,
+            json!({
+                "model": "fake",
+                "messages": [
+                    { "role": "system", "content": REDACTED },
+                    { "role": "user", "content": [
+                        { "type": "text", "text": REDACTED },
+                        { "type": "image_url", "image_url": { "url": REDACTED, "detail": "low" } },
+                    ] },
+                ],
+                "max_tokens": 5,
+            })
+        );
+
+        let mut request = json!({ "messages": "secret", "model": "fake" });
+        redact(&mut request);
+        assert_eq!(request, json!({ "messages": REDACTED, "model": "fake" }));
+
+        let mut request = json!({ "prompt": ["secret", "secret"], "suffix": "secret" });
+        redact(&mut request);
+        assert_eq!(
+            request,
+            json!({ "prompt": [REDACTED, REDACTED], "suffix": REDACTED })
+        );
+
+        // Token ids say little about the text and are kept.
+        let mut request = json!({ "prompt": [1, 2, 3], "input": ["secret"] });
+        redact(&mut request);
+        assert_eq!(request, json!({ "prompt": [1, 2, 3], "input": [REDACTED] }));
+    }
+
+    #[test]
+    fn rotates_and_drops_the_oldest_file() {
+        let dir = TempDir::new("journal-rotate");
+        let config = config(&dir, 20, false);
+        let mut writer = Writer::open(&config).unwrap();
+        // 10 bytes each with the newline, two per file.
+        for line in [
+            "aaaaaaaaa",
+            "bbbbbbbbb",
+            "ccccccccc",
+            "ddddddddd",
+            "eeeeeeeee",
+            "fffffffff",
+            "ggggggggg",
+        ] {
+            writer.write(line).unwrap();
+        }
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(config.path.clone()), "ggggggggg\n");
+        assert_eq!(read(rotated(&config.path, 1)), "eeeeeeeee\nfffffffff\n");
+        assert_eq!(read(rotated(&config.path, 2)), "ccccccccc\nddddddddd\n");
+        assert!(!rotated(&config.path, 3).exists());
+
+        // Reopening appends to the current file and keeps counting its size.
+        let mut writer = Writer::open(&config).unwrap();
+        writer.write("hhhhhhhhh").unwrap();
+        writer.write("iiiiiiiii").unwrap();
+        assert_eq!(read(config.path.clone()), "iiiiiiiii\n");
+        assert_eq!(read(rotated(&config.path, 1)), "ggggggggg\nhhhhhhhhh\n");
+
+        // A line longer than `max_bytes` still goes into a file of its own.
+        writer.write(&"j".repeat(30)).unwrap();
+        assert_eq!(read(config.path.clone()), format!("{}\n", "j".repeat(30)));
+    }
+
+    #[test]
+    fn assembles_chat_chunks() {
+        let mut assembler = StreamAssembler::default();
+        for response in [chat_chunk("hel", None), chat_chunk("lo", Some("stop"))] {
+            let Response::Chunk(chunk) = response else {
+                unreachable!()
+            };
+            assembler.chat_chunk(&chunk);
+        }
+        let value = assembler.into_value();
+        assert_eq!(value["object"], "chat.completion");
+        assert_eq!(value["model"], "fake");
+        assert_eq!(
+            value["choices"],
+            json!([{
+                "index": 0,
+                "message": { "role": "assistant", "content": "hello", "tool_calls": [] },
+                "finish_reason": "stop",
+            }])
+        );
+        assert!(value.get("error").is_none());
+    }
+
+    #[test]
+    fn assembles_completion_chunks_up_to_an_error() {
+        let mut assembler = StreamAssembler::default();
+        let Response::CompletionChunk(mut chunk) = completion_chunk("a", None) else {
+            unreachable!()
+        };
+        assembler.completion_chunk(&chunk);
+        // A second choice, interleaved with the first.
+        chunk.choices[0].index = 1;
+        chunk.choices[0].text = "x".to_string();
+        assembler.completion_chunk(&chunk);
+        chunk.choices[0].index = 0;
+        chunk.choices[0].text = "b".to_string();
+        assembler.completion_chunk(&chunk);
+        assembler.error(&json!({ "error": { "message": "boom" } }));
+
+        let value = assembler.into_value();
+        assert_eq!(value["object"], "text_completion");
+        assert_eq!(
+            value["choices"],
+            json!([
+                { "index": 0, "text": "ab", "finish_reason": null },
+                { "index": 1, "text": "x", "finish_reason": null },
+            ])
+        );
+        assert_eq!(value["error"]["error"]["message"], "boom");
+    }
+
+    #[tokio::test]
+    async fn journals_streams_with_their_prompt_tokens() {
+        let dir = TempDir::new("journal-stream");
+        let config = config(&dir, 1024 * 1024, true);
+        let engine = FakeEngine::new([vec![
+            chat_chunk("hel", None),
+            chat_chunk("lo", Some("stop")),
+        ]]);
+        let app = app_with_journal(engine, Journal::open(&config).unwrap());
+        let request = json!({ "messages": "hi", "stream": true });
+        let (status, body) = post(app, "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(sse_data(&body).len(), 3);
+
+        let record = lines(&config.path, 1).await.remove(0);
+        assert_eq!(record["endpoint"], "/v1/chat/completions");
+        assert_eq!(record["status"], 200);
+        assert_eq!(record["request"]["messages"], REDACTED);
+        assert_eq!(
+            record["response"]["choices"][0]["message"]["content"],
+            "hello"
+        );
+        // `<|user|>hi\n<|assistant|>` and one token per chunk.
+        assert_eq!(
+            record["usage"],
+            json!({ "prompt_tokens": 24, "completion_tokens": 2, "total_tokens": 26 })
+        );
+    }
+}
@@ -0,0 +1,802 @@
+//! JSON output for `/v1/chat/completions`: `response_format` and tool parameter schemas.
+//!
+//! The engine only enforces regex and yacc constraints, so a `response_format` schema is
+//! compiled to a regex over compact JSON (at most one space around punctuation). The regex covers
+//! types, properties, `required`, `enum`, `const`, `anyOf`/`oneOf`, `items`, item counts, string
+//! lengths, some string `format`s and local `$ref`s. Other keywords, like numeric bounds and
+//! `pattern`, are only checked once generation is done, together with the tool call arguments.
+
+use jsonschema::JSONSchema;
+use mistralrs::{ChatCompletionChunkResponse, ChatCompletionResponse, Tool, ToolCallResponse};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{error::ApiError, openai::ResponseFormat};
+
+const WS: &str = "[ ]?";
+const STRING_CHAR: &str = r#"([^"\\\x00-\x1F]|\\(["\\/bfnrt]|u[0-9a-fA-F]{4}))"#;
+const INTEGER: &str = "-?(0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(0|[1-9][0-9]*)(\.[0-9]+)?([eE][+-]?[0-9]+)?";
+const BOOLEAN: &str = "(true|false)";
+const NULL: &str = "null";
+/// How many levels untyped values (`{}`, `json_object`) and recursive `$ref`s may nest. Each
+/// level multiplies the size of the regex.
+const MAX_DEPTH: usize = 3;
+/// Validation errors reported per output, the rest are left out.
+const MAX_ERRORS: usize = 3;
+
+/// Compiles `schema` to a regex matching the JSON documents it describes.
+pub fn schema_regex(schema: &Value) -> Result<String, String> {
+    RegexBuilder {
+        root: schema,
+        depth: 0,
+    }
+    .value(schema)
+    .map_err(|e| match e {
+        RegexError::Invalid(e) => e,
+        RegexError::TooDeep(reference) => {
+            format!("`$ref` `{reference}` always nests deeper than {MAX_DEPTH} levels")
+        }
+    })
+}
+
+/// Regex matching any JSON object.
+pub fn object_regex() -> String {
+    object_of(&any_value(MAX_DEPTH - 1))
+}
+
+enum RegexError {
+    Invalid(String),
+    /// A `$ref` went past `MAX_DEPTH`. Optional parts of the schema that hit this are left
+    /// out, so recursive schemas are unrolled `MAX_DEPTH` times.
+    TooDeep(String),
+}
+
+impl From<String> for RegexError {
+    fn from(e: String) -> Self {
+        Self::Invalid(e)
+    }
+}
+
+impl From<&str> for RegexError {
+    fn from(e: &str) -> Self {
+        Self::Invalid(e.to_string())
+    }
+}
+
+struct RegexBuilder<'a> {
+    root: &'a Value,
+    /// `$ref`s followed to get to the current schema.
+    depth: usize,
+}
+
+impl RegexBuilder<'_> {
+    fn value(&mut self, schema: &Value) -> Result<String, RegexError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(any_value(MAX_DEPTH.saturating_sub(self.depth))),
+            Value::Bool(false) => return Err("the schema `false` matches nothing".into()),
+            Value::Object(schema) => schema,
+            _ => return Err("a schema must be an object or a boolean".into()),
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            return self.reference(reference);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(literal(value));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values.as_array().ok_or("`enum` must be an array")?;
+            if values.is_empty() {
+                return Err("`enum` must not be empty".into());
+            }
+            return Ok(alternation(values.iter().map(literal)));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas
+                    .as_array()
+                    .filter(|schemas| !schemas.is_empty())
+                    .ok_or_else(|| format!("`{keyword}` must be a non-empty array"))?;
+                let alternatives = schemas.iter().map(|schema| self.value(schema)).collect();
+                return fitting_alternatives(alternatives);
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            return match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => self.value(schema),
+                _ => Err("`allOf` is only supported with a single schema".into()),
+            };
+        }
+
+        match schema.get("type") {
+            Some(Value::String(tp)) => self.typed(tp, schema),
+            Some(Value::Array(types)) => {
+                let alternatives = types
+                    .iter()
+                    .map(|tp| match tp {
+                        Value::String(tp) => self.typed(tp, schema),
+                        _ => Err("`type` must be a string or an array of strings".into()),
+                    })
+                    .collect();
+                fitting_alternatives(alternatives)
+            }
+            Some(_) => Err("`type` must be a string or an array of strings".into()),
+            None if schema.contains_key("properties") => self.typed("object", schema),
+            None if schema.contains_key("items") => self.typed("array", schema),
+            None => Ok(any_value(MAX_DEPTH.saturating_sub(self.depth))),
+        }
+    }
+
+    fn reference(&mut self, reference: &Value) -> Result<String, RegexError> {
+        let reference = reference.as_str().ok_or("`$ref` must be a string")?;
+        let target = reference
+            .strip_prefix('#')
+            .and_then(|pointer| self.root.pointer(pointer))
+            .ok_or_else(|| {
+                format!("`$ref` `{reference}` must point into the schema, like `#/$defs/name`")
+            })?;
+        if self.depth == MAX_DEPTH {
+            return Err(RegexError::TooDeep(reference.to_string()));
+        }
+        self.depth += 1;
+        let regex = self.value(target);
+        self.depth -= 1;
+        regex
+    }
+
+    fn typed(
+        &mut self,
+        tp: &str,
+        schema: &serde_json::Map<String, Value>,
+    ) -> Result<String, RegexError> {
+        Ok(match tp {
+            "string" => string(schema)?,
+            "integer" => INTEGER.to_string(),
+            "number" => NUMBER.to_string(),
+            "boolean" => BOOLEAN.to_string(),
+            "null" => NULL.to_string(),
+            "array" => {
+                let min = count(schema, "minItems")?.unwrap_or(0);
+                let max = count(schema, "maxItems")?;
+                let item = match schema.get("items") {
+                    Some(items) => match self.value(items) {
+                        Ok(item) => item,
+                        // Too deep for items, but an empty array is fine.
+                        Err(RegexError::TooDeep(_)) if min == 0 => {
+                            return Ok(format!(r"\[{WS}\]"));
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    None => any_value(MAX_DEPTH.saturating_sub(self.depth + 1)),
+                };
+                array_of(&item, min, max)?
+            }
+            "object" => self.object(schema)?,
+            _ => return Err(format!("unknown type `{tp}`").into()),
+        })
+    }
+
+    /// Declared properties in the order the schema lists them (`serde_json` is built with
+    /// `preserve_order`), with the required ones always present. Properties that are not
+    /// declared are not generated.
+    fn object(&mut self, schema: &serde_json::Map<String, Value>) -> Result<String, RegexError> {
+        let Some(properties) = schema.get("properties") else {
+            return Ok(match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => format!(r"\{{{WS}\}}"),
+                Some(additional @ Value::Object(_)) => match self.value(additional) {
+                    Ok(value) => object_of(&value),
+                    Err(RegexError::TooDeep(_)) => format!(r"\{{{WS}\}}"),
+                    Err(e) => return Err(e),
+                },
+                _ => object_of(&any_value(MAX_DEPTH.saturating_sub(self.depth + 1))),
+            });
+        };
+        let properties = properties
+            .as_object()
+            .ok_or("`properties` must be an object")?;
+        let required = match schema.get("required") {
+            Some(required) => required
+                .as_array()
+                .ok_or("`required` must be an array")?
+                .iter()
+                .map(|name| name.as_str().ok_or("`required` must only hold strings"))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        if let Some(missing) = required
+            .iter()
+            .find(|name| !properties.contains_key(**name))
+        {
+            return Err(format!("required property `{missing}` is not in `properties`").into());
+        }
+
+        let mut members = Vec::new();
+        for (name, property) in properties {
+            let required = required.contains(&name.as_str());
+            let value = match self.value(property) {
+                Ok(value) => value,
+                Err(RegexError::TooDeep(_)) if !required => continue,
+                Err(RegexError::Invalid(e)) => return Err(format!("property `{name}`: {e}").into()),
+                Err(e) => return Err(e),
+            };
+            let key = literal(&Value::String(name.clone()));
+            members.push((format!("{key}{WS}:{WS}{value}"), required));
+        }
+        Ok(format!(r"\{{{WS}{}{WS}\}}", members_regex(&members, false)))
+    }
+}
+
+/// Alternation of the alternatives that compiled. Those that nest too deep are left out.
+fn fitting_alternatives(
+    alternatives: Vec<Result<String, RegexError>>,
+) -> Result<String, RegexError> {
+    let mut regexes = Vec::new();
+    let mut too_deep = None;
+    for alternative in alternatives {
+        match alternative {
+            Ok(regex) => regexes.push(regex),
+            Err(RegexError::TooDeep(reference)) => too_deep = Some(reference),
+            Err(e) => return Err(e),
+        }
+    }
+    match too_deep {
+        Some(reference) if regexes.is_empty() => Err(RegexError::TooDeep(reference)),
+        _ => Ok(alternation(regexes.into_iter())),
+    }
+}
+
+/// Members from the first of `members` on. `after_first` is whether a member was already
+/// written, so the next one needs a comma.
+fn members_regex(members: &[(String, bool)], after_first: bool) -> String {
+    let Some(((member, required), rest)) = members.split_first() else {
+        return String::new();
+    };
+    match (after_first, required) {
+        (true, true) => format!("{WS},{WS}{member}{}", members_regex(rest, true)),
+        (true, false) => format!("({WS},{WS}{member})?{}", members_regex(rest, true)),
+        (false, true) => format!("{member}{}", members_regex(rest, true)),
+        (false, false) => format!(
+            "({member}{}|{})",
+            members_regex(rest, true),
+            members_regex(rest, false)
+        ),
+    }
+}
+
+fn string(schema: &serde_json::Map<String, Value>) -> Result<String, String> {
+    let format = match schema.get("format").and_then(Value::as_str) {
+        Some("date-time") => Some(format!("{DATE}T{TIME}")),
+        Some("date") => Some(DATE.to_string()),
+        Some("time") => Some(TIME.to_string()),
+        Some("uuid") => Some(
+            "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}"
+                .to_string(),
+        ),
+        _ => None,
+    };
+    if let Some(format) = format {
+        return Ok(format!("\"{format}\""));
+    }
+    let min = count(schema, "minLength")?.unwrap_or(0);
+    Ok(match count(schema, "maxLength")? {
+        Some(max) if max < min => return Err("`maxLength` is less than `minLength`".to_string()),
+        Some(max) => format!("\"{STRING_CHAR}{{{min},{max}}}\""),
+        None if min > 0 => format!("\"{STRING_CHAR}{{{min},}}\""),
+        None => format!("\"{STRING_CHAR}*\""),
+    })
+}
+
+const DATE: &str = "[0-9]{4}-(0[1-9]|1[0-2])-(0[1-9]|[12][0-9]|3[01])";
+const TIME: &str =
+    r"([01][0-9]|2[0-3]):[0-5][0-9]:[0-5][0-9](\.[0-9]+)?(Z|[+-]([01][0-9]|2[0-3]):[0-5][0-9])";
+
+fn count(schema: &serde_json::Map<String, Value>, keyword: &str) -> Result<Option<usize>, String> {
+    schema
+        .get(keyword)
+        .map(|n| {
+            n.as_u64()
+                .map(|n| n as usize)
+                .ok_or_else(|| format!("`{keyword}` must be a non-negative integer"))
+        })
+        .transpose()
+}
+
+/// Any JSON value, with arrays and objects nested at most `levels` deep.
+fn any_value(levels: usize) -> String {
+    let scalars = [
+        format!("\"{STRING_CHAR}*\""),
+        NUMBER.to_string(),
+        BOOLEAN.to_string(),
+        NULL.to_string(),
+    ];
+    if levels == 0 {
+        return alternation(scalars.into_iter());
+    }
+    let inner = any_value(levels - 1);
+    let containers = [
+        array_of(&inner, 0, None).expect("unbounded arrays are valid"),
+        object_of(&inner),
+    ];
+    alternation(scalars.into_iter().chain(containers))
+}
+
+fn array_of(item: &str, min: usize, max: Option<usize>) -> Result<String, String> {
+    let next = format!("{WS},{WS}{item}");
+    let items = match (min, max) {
+        (_, Some(max)) if max < min => return Err("`maxItems` is less than `minItems`".to_string()),
+        (_, Some(0)) => String::new(),
+        (0, None) => format!("({item}({next})*)?"),
+        (0, Some(max)) => format!("({item}({next}){{0,{}}})?", max - 1),
+        (min, None) => format!("{item}({next}){{{},}}", min - 1),
+        (min, Some(max)) => format!("{item}({next}){{{},{}}}", min - 1, max - 1),
+    };
+    Ok(format!(r"\[{WS}{items}{WS}\]"))
+}
+
+/// An object with any string keys and values matching `value`.
+fn object_of(value: &str) -> String {
+    let member = format!("\"{STRING_CHAR}*\"{WS}:{WS}{value}");
+    format!(r"\{{{WS}({member}({WS},{WS}{member})*)?{WS}\}}")
+}
+
+fn alternation(alternatives: impl Iterator<Item = String>) -> String {
+    format!("({})", alternatives.collect::<Vec<_>>().join("|"))
+}
+
+/// Regex matching exactly `value`, written as compact JSON.
+fn literal(value: &Value) -> String {
+    let mut regex = String::new();
+    for c in value.to_string().chars() {
+        if r"\.+*?()|[]{}^$#&-~".contains(c) {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex
+}
+
+/// A compiled JSON schema for checking the model's output. Boxed, it's large.
+pub struct Validator(Box<JSONSchema>);
+
+impl Validator {
+    /// Fails if `schema` is not a valid JSON schema.
+    pub fn new(schema: &Value) -> Result<Self, String> {
+        JSONSchema::compile(schema)
+            .map(|schema| Self(Box::new(schema)))
+            .map_err(|e| format!("{e} (at `{}`)", pointer(&e.instance_path.to_string())))
+    }
+
+    pub fn validate(&self, instance: &Value) -> Result<(), String> {
+        self.0.validate(instance).map_err(|errors| {
+            errors
+                .take(MAX_ERRORS)
+                .map(|e| format!("{e} (at `{}`)", pointer(&e.instance_path.to_string())))
+                .collect::<Vec<_>>()
+                .join("; ")
+        })
+    }
+}
+
+fn pointer(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+/// What `response_format` expects of the message content.
+enum ContentFormat {
+    JsonObject,
+    Schema(Validator),
+}
+
+/// What a chat request expects of the model's output.
+pub struct OutputCheck {
+    content: Option<ContentFormat>,
+    /// Parameter schemas of the declared tools, by function name.
+    tools: HashMap<String, Option<Validator>>,
+}
+
+impl OutputCheck {
+    /// Checks the schemas in the request. Returns the regex the engine should enforce, if
+    /// `response_format` asks for JSON.
+    pub fn new(
+        response_format: Option<&ResponseFormat>,
+        tools: Option<&[Tool]>,
+    ) -> Result<(Self, Option<String>), ApiError> {
+        let (content, regex) = match response_format {
+            None | Some(ResponseFormat::Text) => (None, None),
+            Some(ResponseFormat::JsonObject) => {
+                (Some(ContentFormat::JsonObject), Some(object_regex()))
+            }
+            Some(ResponseFormat::JsonSchema { json_schema }) => match &json_schema.schema {
+                None => (Some(ContentFormat::JsonObject), Some(object_regex())),
+                Some(schema) => {
+                    let invalid = |e: String| {
+                        ApiError::unprocessable(format!(
+                            "Invalid schema for response format `{}`: {e}.",
+                            json_schema.name
+                        ))
+                        .with_param("response_format.json_schema.schema")
+                    };
+                    let validator = Validator::new(schema).map_err(invalid)?;
+                    let regex = schema_regex(schema).map_err(invalid)?;
+                    (Some(ContentFormat::Schema(validator)), Some(regex))
+                }
+            },
+        };
+
+        let mut validators = HashMap::new();
+        for (i, tool) in tools.unwrap_or_default().iter().enumerate() {
+            let validator = match &tool.function.parameters {
+                Some(parameters) => {
+                    let parameters = Value::Object(
+                        parameters
+                            .iter()
+                            .map(|(k, v)| (k.clone(), v.clone()))
+                            .collect(),
+                    );
+                    Some(Validator::new(&parameters).map_err(|e| {
+                        ApiError::unprocessable(format!(
+                            "Invalid parameters schema for function `{}`: {e}.",
+                            tool.function.name
+                        ))
+                        .with_param(format!("tools[{i}].function.parameters"))
+                    })?)
+                }
+                None => None,
+            };
+            if validators
+                .insert(tool.function.name.clone(), validator)
+                .is_some()
+            {
+                return Err(ApiError::invalid_request(format!(
+                    "Function `{}` is declared more than once.",
+                    tool.function.name
+                ))
+                .with_param(format!("tools[{i}].function.name")));
+            }
+        }
+
+        Ok((
+            Self {
+                content,
+                tools: validators,
+            },
+            regex,
+        ))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.content.is_none() && self.tools.is_empty()
+    }
+
+    /// Checks every choice of a finished response.
+    pub fn check_response(&self, response: &ChatCompletionResponse) -> Result<(), ApiError> {
+        for choice in &response.choices {
+            self.check_tool_calls(choice.index, &choice.message.tool_calls)?;
+            if choice.message.tool_calls.is_empty() && choice.finish_reason != "length" {
+                self.check_content(
+                    choice.index,
+                    choice.message.content.as_deref().unwrap_or(""),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a streamed chunk. `contents` collects each choice's content so far, and is
+    /// checked when the choice finishes.
+    pub fn check_chunk(
+        &self,
+        contents: &mut BTreeMap<usize, String>,
+        chunk: &ChatCompletionChunkResponse,
+    ) -> Result<(), ApiError> {
+        for choice in &chunk.choices {
+            let tool_calls = choice.delta.tool_calls.as_deref().unwrap_or_default();
+            self.check_tool_calls(choice.index, tool_calls)?;
+            if self.content.is_none() {
+                continue;
+            }
+            let content = contents.entry(choice.index).or_default();
+            content.push_str(&choice.delta.content);
+            match choice.finish_reason.as_deref() {
+                Some("length") | None => {}
+                Some(_) if tool_calls.is_empty() => self.check_content(choice.index, content)?,
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_content(&self, index: usize, content: &str) -> Result<(), ApiError> {
+        let Some(format) = &self.content else {
+            return Ok(());
+        };
+        let invalid = |e: String| {
+            ApiError::unprocessable(format!(
+                "The output of choice {index} does not match `response_format`: {e}."
+            ))
+            .with_param("response_format")
+        };
+        let value = serde_json::from_str::<Value>(content)
+            .map_err(|e| invalid(format!("not valid JSON, {e}")))?;
+        match format {
+            ContentFormat::Schema(validator) => validator.validate(&value).map_err(invalid),
+            ContentFormat::JsonObject if value.is_object() => Ok(()),
+            ContentFormat::JsonObject => Err(invalid("not a JSON object".to_string())),
+        }
+    }
+
+    fn check_tool_calls(&self, index: usize, calls: &[ToolCallResponse]) -> Result<(), ApiError> {
+        for call in calls {
+            let name = &call.function.name;
+            let invalid = |e: String| {
+                ApiError::unprocessable(format!(
+                    "Tool call `{name}` of choice {index} does not match the declared function: {e}."
+                ))
+                .with_param("tools")
+            };
+            let validator = self
+                .tools
+                .get(name)
+                .ok_or_else(|| invalid("no function with this name was declared".to_string()))?;
+            let arguments = serde_json::from_str::<Value>(&call.function.arguments)
+                .map_err(|e| invalid(format!("the arguments are not valid JSON, {e}")))?;
+            if let Some(validator) = validator {
+                validator.validate(&arguments).map_err(invalid)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mistralrs::Tool;
+    use serde_json::json;
+
+    /// Whether `document` is one of the documents `schema`'s regex matches.
+    fn matches(schema: &Value, document: &str) -> bool {
+        let regex = schema_regex(schema).unwrap();
+        regex::Regex::new(&format!("^(?:{regex})$"))
+            .unwrap()
+            .is_match(document)
+    }
+
+    #[track_caller]
+    fn assert_matches(schema: &Value, yes: &[&str], no: &[&str]) {
+        for document in yes {
+            assert!(matches(schema, document), "should match: {document}");
+        }
+        for document in no {
+            assert!(!matches(schema, document), "should not match: {document}");
+        }
+    }
+
+    #[test]
+    fn objects_keep_the_declared_property_order() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "zebra": { "type": "integer" },
+                "apple": { "type": "string" },
+                "mango": { "type": "boolean" },
+            },
+            "required": ["zebra", "mango"],
+        });
+        assert_matches(
+            &schema,
+            &[
+                r#"{"zebra":1,"mango":true}"#,
+                r#"{ "zebra" : 1 , "apple" : "x" , "mango" : false }"#,
+            ],
+            &[
+                r#"{"apple":"x","zebra":1,"mango":true}"#,
+                r#"{"zebra":1}"#,
+                r#"{"zebra":1,"mango":true,"other":1}"#,
+                r#"{"zebra":"1","mango":true}"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn optional_properties_may_all_be_left_out() {
+        let schema = json!({
+            "properties": { "a": { "type": "integer" }, "b": { "type": "null" } },
+        });
+        assert_matches(
+            &schema,
+            &["{}", r#"{"a":1}"#, r#"{"b":null}"#, r#"{"a":1,"b":null}"#],
+            &[r#"{,"b":null}"#, r#"{"a":1,}"#],
+        );
+    }
+
+    #[test]
+    fn enums_consts_and_any_of() {
+        let schema = json!({ "enum": ["red", 1, null, "a.b(c)*"] });
+        assert_matches(
+            &schema,
+            &[r#""red""#, "1", "null", r#""a.b(c)*""#],
+            &[r#""blue""#, "2", r#""aXb(c)""#, r#""a.b(c)""#],
+        );
+        assert_matches(
+            &json!({ "const": { "k": [1] } }),
+            &[r#"{"k":[1]}"#],
+            &["{}"],
+        );
+        let schema = json!({ "anyOf": [{ "type": "integer" }, { "type": "array", "items": { "type": "boolean" } }] });
+        assert_matches(
+            &schema,
+            &["-3", "[true, false]", "[]"],
+            &["1.5", "[1]", r#""1""#],
+        );
+        let schema = json!({ "type": ["string", "null"] });
+        assert_matches(&schema, &[r#""x""#, "null"], &["0"]);
+    }
+
+    #[test]
+    fn refs_resolve_and_recursion_is_unrolled() {
+        let schema = json!({
+            "$defs": { "n": { "type": "integer" } },
+            "type": "object",
+            "properties": { "x": { "$ref": "#/$defs/n" } },
+            "required": ["x"],
+        });
+        assert_matches(&schema, &[r#"{"x":3}"#], &[r#"{"x":"3"}"#]);
+
+        let tree = json!({
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {
+                        "value": { "type": "integer" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/node" } },
+                    },
+                    "required": ["value"],
+                },
+            },
+            "$ref": "#/$defs/node",
+        });
+        assert_matches(
+            &tree,
+            &[
+                r#"{"value":1}"#,
+                r#"{"value":1,"children":[{"value":2,"children":[]}]}"#,
+            ],
+            &[r#"{"children":[]}"#],
+        );
+
+        let e = schema_regex(&json!({ "$ref": "#/$defs/missing" })).unwrap_err();
+        assert!(e.contains("must point into the schema"), "{e}");
+        let e = schema_regex(
+            &json!({ "$defs": { "a": { "$ref": "#/$defs/a" } }, "$ref": "#/$defs/a" }),
+        )
+        .unwrap_err();
+        assert!(e.contains("always nests deeper"), "{e}");
+    }
+
+    #[test]
+    fn array_item_counts() {
+        let schema = json!({ "items": { "type": "integer" }, "minItems": 1, "maxItems": 2 });
+        assert_matches(&schema, &["[1]", "[1, 2]"], &["[]", "[1,2,3]", "[1,]"]);
+        let schema = json!({ "type": "array", "minItems": 2 });
+        assert_matches(&schema, &[r#"[1,"a",null]"#], &["[1]"]);
+        let e =
+            schema_regex(&json!({ "type": "array", "minItems": 2, "maxItems": 1 })).unwrap_err();
+        assert!(e.contains("`maxItems` is less than `minItems`"), "{e}");
+    }
+
+    #[test]
+    fn strings_formats_and_escaping() {
+        let schema = json!({ "type": "string", "minLength": 1, "maxLength": 3 });
+        assert_matches(
+            &schema,
+            &[r#""a""#, r#""\"\\\n""#, r#""éé""#],
+            &[r#""""#, r#""abcd""#, "\"a\u{1}\"", r#""\x""#, r#""a"b""#],
+        );
+        let date = json!({ "type": "string", "format": "date" });
+        assert_matches(
+            &date,
+            &[r#""2024-02-29""#],
+            &[r#""2024-13-01""#, r#""24-01-01""#],
+        );
+        let date_time = json!({ "type": "string", "format": "date-time" });
+        assert_matches(
+            &date_time,
+            &[
+                r#""2024-02-29T23:59:59Z""#,
+                r#""2024-01-01T00:00:00.5+02:00""#,
+            ],
+            &[r#""2024-01-01 00:00:00Z""#, r#""2024-01-01T24:00:00Z""#],
+        );
+        let uuid = json!({ "type": "string", "format": "uuid" });
+        assert_matches(
+            &uuid,
+            &[r#""123e4567-e89b-12d3-a456-426614174000""#],
+            &[r#""123e4567e89b12d3a456426614174000""#],
+        );
+        // Property names are escaped like any other literal.
+        let schema = json!({ "properties": { "a+b": { "const": 1 } }, "required": ["a+b"] });
+        assert_matches(&schema, &[r#"{"a+b":1}"#], &[r#"{"aab":1}"#]);
+    }
+
+    #[test]
+    fn rejects_schemas_it_cannot_compile() {
+        for (schema, error) in [
+            (json!(false), "matches nothing"),
+            (json!({ "enum": [] }), "must not be empty"),
+            (json!({ "type": "date" }), "unknown type"),
+            (
+                json!({ "properties": {}, "required": ["a"] }),
+                "not in `properties`",
+            ),
+            (json!({ "allOf": [{}, {}] }), "single schema"),
+        ] {
+            let e = schema_regex(&schema).unwrap_err();
+            assert!(e.contains(error), "{schema}: {e}");
+        }
+    }
+
+    fn tool(name: &str, parameters: Value) -> Tool {
+        serde_json::from_value(json!({
+            "type": "function",
+            "function": { "name": name, "parameters": parameters },
+        }))
+        .unwrap()
+    }
+
+    fn call(name: &str, arguments: &str) -> ToolCallResponse {
+        serde_json::from_value(json!({
+            "id": "call-0",
+            "type": "function",
+            "function": { "name": name, "arguments": arguments },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn checks_tool_call_arguments() {
+        let tools = [tool(
+            "weather",
+            json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" }, "days": { "type": "integer", "minimum": 1 } },
+                "required": ["city"],
+            }),
+        )];
+        let (check, regex) = OutputCheck::new(None, Some(&tools)).unwrap();
+        assert!(regex.is_none());
+        assert!(!check.is_empty());
+
+        check
+            .check_tool_calls(0, &[call("weather", r#"{"city":"Oslo","days":2}"#)])
+            .unwrap();
+        for (call, error) in [
+            (
+                call("weather", r#"{"days":2}"#),
+                "\"city\" is a required property",
+            ),
+            (call("weather", r#"{"city":"Oslo","days":0}"#), "minimum"),
+            (call("weather", "{"), "not valid JSON"),
+            (call("stocks", "{}"), "no function with this name"),
+        ] {
+            let e = check.check_tool_calls(0, &[call]).unwrap_err();
+            assert_eq!(e.detail().param.as_deref(), Some("tools"));
+            assert!(e.to_string().contains(error), "{e}");
+        }
+
+        let tools = [tool("a", json!({})), tool("a", json!({}))];
+        let e = OutputCheck::new(None, Some(&tools)).err().unwrap();
+        assert_eq!(e.detail().param.as_deref(), Some("tools[1].function.name"));
+        let tools = [tool("a", json!({ "type": "nope" }))];
+        let e = OutputCheck::new(None, Some(&tools)).err().unwrap();
+        assert_eq!(
+            e.detail().param.as_deref(),
+            Some("tools[0].function.parameters")
+        );
+    }
+}
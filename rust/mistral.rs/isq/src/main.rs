@@ -1,670 +1,366 @@
-use anyhow::{Context as _, Result};
 use axum::{
-    extract::{DefaultBodyLimit, State},
+    extract::DefaultBodyLimit,
     http::{self, Method},
-    response::{
-        sse::{Event, KeepAlive},
-        IntoResponse, Sse,
-    },
+    middleware,
     routing::{get, post},
-    Json, Router,
+    Router,
 };
-use either::Either;
-use indexmap::IndexMap;
-use reqwest::StatusCode;
-use serde::{Deserialize, Serialize};
-use std::{
-    collections::HashMap, env, error::Error, num::NonZero, ops::Deref, pin::Pin, sync::Arc, task::{Context, Poll}, time::Duration
-};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use std::{num::NonZero, sync::Arc, time::Duration};
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
+use clap::Parser;
 use mistralrs::{
-    ChatCompletionResponse, Constraint, DefaultSchedulerMethod, Device, DeviceMapMetadata,
-    DrySamplingParams, IsqType, MemoryGpuConfig, MistralRs, MistralRsBuilder, ModelDType,
-    NormalLoaderBuilder, NormalRequest, NormalSpecificConfig, PagedAttentionConfig, Request,
-    RequestMessage, Response, SamplingParams, SchedulerConfig, StopTokens as InternalStopTokens,
-    TokenSource, Tool, ToolChoice,
+    DefaultSchedulerMethod, DeviceMapMetadata, GGUFLoaderBuilder, GGUFSpecificConfig,
+    MistralRsBuilder, NormalLoaderBuilder, NormalSpecificConfig, PagedAttentionConfig,
+    SchedulerConfig, TokenSource,
 };
 
+mod adapters;
+mod admission;
+mod auth;
+mod batch;
+mod bench;
+mod chat_completion;
+mod completions;
+mod config;
+mod embeddings;
+mod engine;
+mod error;
+mod health;
+mod journal;
+mod json_schema;
+mod metrics;
+mod openai;
+mod registry;
+mod sampling;
+mod shutdown;
+mod state;
+mod streamer;
+mod tokenize;
+mod util;
+
+use adapters::Adapters;
+use admission::Admission;
+use auth::ApiKeys;
+use chat_completion::chatcompletions;
+use common::device::DeviceSpec;
+use completions::completions;
+use config::{AdapterKind, Args, Command, LoaderKind, ModelConfig, SchedulerMethod, ServerConfig};
+use embeddings::embeddings;
+use engine::MistralRsEngine;
+use health::Health;
+use journal::Journal;
+use metrics::Metrics;
+use registry::{models, ModelInfo, ModelRegistry};
+use shutdown::Shutdown;
+use state::AppState;
+use tokenize::{detokenize, tokenize};
+use util::ImageFetcher;
+
 // NOTE(EricLBuehler): Accept up to 50mb input
 const N_INPUT_SIZE: usize = 50;
 const MB_TO_B: usize = 1024 * 1024; // 1024 kb in a mb
-
-/// Gets the best device, cpu, cuda if compiled with CUDA
-pub(crate) fn best_device() -> Result<Device> {
-    #[cfg(not(feature = "metal"))]
-    {
-        Ok(Device::cuda_if_available(0)?)
-    }
-    #[cfg(feature = "metal")]
-    {
-        Device::new_metal(0)
-    }
-}
-
-async fn setup() -> anyhow::Result<Arc<MistralRs>> {
-    // Select a Mistral model
-    let loader = NormalLoaderBuilder::new(
-        NormalSpecificConfig {
-            use_flash_attn: false,
-            prompt_batchsize: None,
-            topology: None,
-            organization: Default::default(),
-        },
-        None,
-        None,
-        Some("elyza/ELYZA-japanese-Llama-2-7b-instruct".to_string()),
-    )
-    .build(None)?;
+/// Time connections get to close after the shutdown grace period, before the process exits.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Loads `model`, returning its engine and the device it was put on.
+async fn setup(
+    config: &ServerConfig,
+    model: &ModelConfig,
+) -> anyhow::Result<(MistralRsEngine, DeviceSpec)> {
+    // Select a model
+    let loader = match model.loader {
+        LoaderKind::Normal => {
+            let builder = NormalLoaderBuilder::new(
+                NormalSpecificConfig {
+                    use_flash_attn: false,
+                    prompt_batchsize: None,
+                    topology: None,
+                    organization: Default::default(),
+                },
+                model.chat_template.clone(),
+                None,
+                Some(model.model_id.clone()),
+            );
+            let builder = match &model.adapters {
+                None => builder,
+                Some(adapters) => match adapters.kind {
+                    AdapterKind::Lora => {
+                        builder.with_lora(adapters.model_id.clone(), adapters.ordering.clone())
+                    }
+                    AdapterKind::Xlora => builder.with_xlora(
+                        adapters.model_id.clone(),
+                        adapters.ordering.clone(),
+                        false,
+                        adapters.tgt_non_granular_index,
+                    ),
+                },
+            };
+            builder.build(None)?
+        }
+        LoaderKind::Gguf => GGUFLoaderBuilder::new(
+            model.chat_template.clone(),
+            model.tok_model_id.clone(),
+            model.model_id.clone(),
+            model.gguf_files.clone(),
+            GGUFSpecificConfig {
+                prompt_batchsize: None,
+                topology: None,
+            },
+        )
+        .build(),
+    };
+    let device = common::device::select(model.device, config.cpu_threads)?;
     // Load, into a Pipeline
-    let cache_config = Some(PagedAttentionConfig::new(
-        Some(32),
-        512,
-        MemoryGpuConfig::Utilization(0.9), // NOTE(EricLBuehler): default is to use 90% of memory
-    )?);
+    let cache_config = match config.paged_attn {
+        Some(pa) if device.supports_paged_attn(pa.gpu_mem) => Some(PagedAttentionConfig::new(
+            Some(pa.block_size),
+            pa.cpu_mem_mb,
+            pa.gpu_mem,
+        )?),
+        _ => None,
+    };
 
     let pipeline = loader.load_model_from_hf(
         None,
         TokenSource::CacheToken,
-        &ModelDType::Auto,
-        &best_device()?,
+        &model.dtype,
+        &device.device,
         false,
         DeviceMapMetadata::dummy(),
-        Some(IsqType::Q4K), // In-situ quantize the model into q4k
-        cache_config,       // No PagedAttention.
+        model.isq,
+        cache_config,
     )?;
-    println!("Model loaded.");
+    println!("Model `{}` loaded.", model.name);
+    let max_num_seqs = config.max_num_seqs;
+    let default_scheduler = || match config.scheduler {
+        SchedulerMethod::Fixed => SchedulerConfig::DefaultScheduler {
+            method: DefaultSchedulerMethod::Fixed(NonZero::new(max_num_seqs).unwrap()),
+        },
+    };
     let scheduler_config = if cache_config.is_some() {
         // Handle case where we may have device mapping
         if let Some(ref cache_config) = pipeline.lock().await.get_metadata().cache_config {
             SchedulerConfig::PagedAttentionMeta {
-                max_num_seqs: 16,
+                max_num_seqs,
                 config: cache_config.clone(),
             }
         } else {
-            SchedulerConfig::DefaultScheduler {
-                method: DefaultSchedulerMethod::Fixed(NonZero::new(16).unwrap()),
-            }
+            default_scheduler()
         }
     } else {
-        SchedulerConfig::DefaultScheduler {
-            method: DefaultSchedulerMethod::Fixed(NonZero::new(16).unwrap()),
-        }
+        default_scheduler()
     };
+    // mistral.rs keeps prefixes as plain KV caches and cannot share PagedAttention blocks, so it
+    // turns the prefix cache off for those models. Only a cache asked for is worth a warning.
+    let prefix_cache_n = config
+        .prefix_cache_n
+        .unwrap_or(config::DEFAULT_PREFIX_CACHE_N);
+    let paged = matches!(scheduler_config, SchedulerConfig::PagedAttentionMeta { .. });
+    if paged && config.prefix_cache_n.is_some_and(|n| n > 0) {
+        eprintln!(
+            "Warning: prefix caching is off for `{}`, mistral.rs does not support it with \
+             PagedAttention.",
+            model.name
+        );
+    }
+    let vocab_size = pipeline
+        .lock()
+        .await
+        .tokenizer()
+        .map(|tokenizer| tokenizer.get_vocab_size(true));
     // Create the MistralRs, which is a runner
-    Ok(MistralRsBuilder::new(pipeline, scheduler_config).build())
+    let mistralrs = MistralRsBuilder::new(pipeline.clone(), scheduler_config)
+        .with_no_prefix_cache(prefix_cache_n == 0)
+        .with_prefix_cache_n(prefix_cache_n)
+        .build();
+    Ok((
+        MistralRsEngine {
+            mistralrs,
+            pipeline,
+            vocab_size,
+            prefix_cache: prefix_cache_n > 0 && !paged,
+            adapters: model.adapters.as_ref().map(Adapters::from_config),
+        },
+        device.spec,
+    ))
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let mistralrs = setup().await?;
-
-    // let (tx, mut rx) = channel(10_000);
-    // let request = Request::Normal(NormalRequest {
-    //     messages: RequestMessage::Chat(vec![IndexMap::from([
-    //         ("role".to_string(), Either::Left("user".to_string())),
-    //         ("content".to_string(), Either::Left("Hello!".to_string())),
-    //     ])]),
-    //     sampling_params: SamplingParams::default(),
-    //     response: tx,
-    //     return_logprobs: false,
-    //     is_streaming: false,
-    //     id: 0,
-    //     constraint: Constraint::None,
-    //     suffix: None,
-    //     adapters: None,
-    //     tools: None,
-    //     tool_choice: None,
-    //     logits_processors: None,
-    // });
-    // mistralrs.get_sender()?.blocking_send(request)?;
-
-    // let response = rx.blocking_recv().unwrap().as_result().unwrap();
-    // match response {
-    //     ResponseOk::Done(c) => println!(
-    //         "Text: {}, Prompt T/s: {}, Completion T/s: {}",
-    //         c.choices[0].message.content.as_ref().unwrap(),
-    //         c.usage.avg_prompt_tok_per_sec,
-    //         c.usage.avg_compl_tok_per_sec
-    //     ),
-    //     _ => unreachable!(),
-    // }
-
-    let app = get_router(mistralrs);
-
-    let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:1234")).await?;
-    println!("Serving on localhost:1234.");
-    axum::serve(listener, app).await?;
+/// Loads the configured models in order, registering each one as soon as it is ready.
+async fn load_models(config: &ServerConfig, app: &AppState) -> anyhow::Result<()> {
+    for model in &config.models {
+        let (engine, device) = setup(config, model).await?;
+        let quantization = match (model.loader, model.isq) {
+            (_, Some(isq)) => format!("{isq:?}"),
+            (LoaderKind::Gguf, None) => "gguf".to_string(),
+            (LoaderKind::Normal, None) => "none".to_string(),
+        };
+        let info = ModelInfo {
+            model_id: model.model_id.clone(),
+            device: device.to_string(),
+            quantization,
+        };
+        app.models
+            .insert(model.name.clone(), Arc::new(engine), info);
+    }
+    app.health.set_loaded();
     Ok(())
 }
 
-fn get_router(state: Arc<MistralRs>) -> Router {
-    let allow_origin = AllowOrigin::any();
-    let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE, http::header::AUTHORIZATION])
-        .allow_origin(allow_origin);
-
-    Router::new()
-        .route("/v1/chat/completions", post(chatcompletions))
-        .route("/health", get(health))
-        .layer(cors_layer)
-        .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
-        .with_state(state)
-}
-
-async fn health() -> &'static str {
-    "OK"
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    let command = args.command.take();
+    let config = ServerConfig::load(args)?;
+    // While the process is still single threaded.
+    common::device::init_cpu_threads(config.cpu_threads)?;
+    tokio::runtime::Runtime::new()?.block_on(run(command, config))
 }
 
-pub async fn chatcompletions(
-    State(state): State<Arc<MistralRs>>,
-    Json(oairequest): Json<ChatCompletionRequest>,
-) -> ChatCompletionResponder {
-    let (tx, mut rx) = channel(10_000);
-    let (request, is_streaming) = match parse_request(oairequest, state.clone(), tx).await {
-        Ok(x) => x,
-        Err(e) => {
-            let e = anyhow::Error::msg(e.to_string());
-            MistralRs::maybe_log_error(state, &*e);
-            return ChatCompletionResponder::InternalError(e.into());
-        }
-    };
-    let sender = state.get_sender().unwrap();
-
-    if let Err(e) = sender.send(request).await {
-        let e = anyhow::Error::msg(e.to_string());
-        MistralRs::maybe_log_error(state, &*e);
-        return ChatCompletionResponder::InternalError(e.into());
+async fn run(command: Option<Command>, config: ServerConfig) -> anyhow::Result<()> {
+    if let Some(Command::Bench(bench)) = &command {
+        // Loads the model itself, once per setting.
+        return bench::run(&config, bench).await;
     }
 
-    if is_streaming {
-        let streamer = Streamer {
-            rx,
-            is_done: false,
-            state,
-        };
-
-        ChatCompletionResponder::Sse(
-            Sse::new(streamer).keep_alive(
-                KeepAlive::new()
-                    .interval(Duration::from_millis(
-                        env::var("KEEP_ALIVE_INTERVAL")
-                            .map(|val| val.parse::<u64>().unwrap_or(1000))
-                            .unwrap_or(1000),
-                    ))
-                    .text("keep-alive-text"),
-            ),
-        )
-    } else {
-        let response = match rx.recv().await {
-            Some(response) => response,
-            None => {
-                let e = anyhow::Error::msg("No response received from the model.");
-                MistralRs::maybe_log_error(state, &*e);
-                return ChatCompletionResponder::InternalError(e.into());
-            }
-        };
-
-        match response {
-            Response::InternalError(e) => {
-                MistralRs::maybe_log_error(state, &*e);
-                ChatCompletionResponder::InternalError(e)
-            }
-            Response::ModelError(msg, response) => {
-                MistralRs::maybe_log_error(state.clone(), &ModelErrorMessage(msg.to_string()));
-                MistralRs::maybe_log_response(state, &response);
-                ChatCompletionResponder::ModelError(msg, response)
-            }
-            Response::ValidationError(e) => ChatCompletionResponder::ValidationError(e),
-            Response::Done(response) => {
-                MistralRs::maybe_log_response(state, &response);
-                ChatCompletionResponder::Json(response)
-            }
-            Response::Chunk(_) => unreachable!(),
-            Response::CompletionDone(_) => unreachable!(),
-            Response::CompletionModelError(_, _) => unreachable!(),
-            Response::CompletionChunk(_) => unreachable!(),
-        }
+    if let Some(Command::Batch(batch)) = command {
+        let app = Arc::new(AppState {
+            models: ModelRegistry::new(),
+            admission: Arc::new(Admission::new(config.admission)),
+            limits: config.limits,
+            images: ImageFetcher::new(config.images.clone())?,
+            metrics: Arc::new(Metrics::new()?),
+            journal: None,
+            shutdown: Arc::new(Shutdown::new()),
+            health: Health::new(),
+        });
+        load_models(&config, &app).await?;
+        return batch::run(app, &batch, config.max_num_seqs).await;
     }
-}
 
-async fn parse_request(
-    oairequest: ChatCompletionRequest,
-    state: Arc<MistralRs>,
-    tx: Sender<Response>,
-) -> Result<(Request, bool)> {
-    let repr = serde_json::to_string(&oairequest).expect("Serialization of request failed.");
-    MistralRs::maybe_log_request(state.clone(), repr);
-
-    let stop_toks = match oairequest.stop_seqs {
-        Some(StopTokens::Multi(m)) => Some(InternalStopTokens::Seqs(m)),
-        Some(StopTokens::Single(s)) => Some(InternalStopTokens::Seqs(vec![s])),
+    let keys = match &config.api_keys {
+        Some(path) => {
+            let keys = ApiKeys::load(path)?;
+            println!("Loaded {} API keys.", keys.len());
+            Some(Arc::new(keys))
+        }
         None => None,
     };
-    let messages = match oairequest.messages {
-        Either::Left(req_messages) => {
-            let mut messages = Vec::new();
-            let mut image_urls = Vec::new();
-            for message in req_messages {
-                match message.content.0 {
-                    Either::Left(content) => {
-                        let mut message_map: IndexMap<
-                            String,
-                            Either<String, Vec<IndexMap<String, String>>>,
-                        > = IndexMap::new();
-                        message_map.insert("role".to_string(), Either::Left(message.role));
-                        message_map
-                            .insert("content".to_string(), Either::Left(content.to_string()));
-                        messages.push(message_map);
-                    }
-                    Either::Right(image_messages) => {
-                        if image_messages.len() != 2 {
-                            anyhow::bail!(
-                                "Expected 2 items for the content of a message with an image."
-                            );
-                        }
-                        if message.role != "user" {
-                            anyhow::bail!(
-                                "Role for an image message must be `user`, but it is {}",
-                                message.role
-                            );
-                        }
-
-                        let mut items = Vec::new();
-                        for image_message in &image_messages {
-                            if image_message.len() != 2 {
-                                anyhow::bail!("Expected 2 items for the sub-content of a message with an image.");
-                            }
-                            if !image_message.contains_key("type") {
-                                anyhow::bail!("Expected `type` key in input message.");
-                            }
-                            if image_message["type"].is_right() {
-                                anyhow::bail!("Expected string value in `type`.");
-                            }
-                            items.push(image_message["type"].as_ref().unwrap_left().clone())
-                        }
-
-                        fn get_content_and_url(
-                            text_idx: usize,
-                            url_idx: usize,
-                            image_messages: &[HashMap<String, MessageInnerContent>],
-                        ) -> Result<(String, String)> {
-                            if image_messages[text_idx]["text"].is_right() {
-                                anyhow::bail!("Expected string value in `text`.");
-                            }
-                            let content = image_messages[text_idx]["text"]
-                                .as_ref()
-                                .unwrap_left()
-                                .clone();
-                            if image_messages[url_idx]["image_url"].is_left()
-                                || !image_messages[url_idx]["image_url"]
-                                    .as_ref()
-                                    .unwrap_right()
-                                    .contains_key("url")
-                            {
-                                anyhow::bail!("Expected content of format {{`type`: `text`, `text`: ...}} and {{`type`: `url`, `image_url`: {{`url`: ...}}}}")
-                            }
-                            let url = image_messages[url_idx]["image_url"].as_ref().unwrap_right()
-                                ["url"]
-                                .clone();
-                            Ok((content, url))
-                        }
-                        let mut message_map: IndexMap<
-                            String,
-                            Either<String, Vec<IndexMap<String, String>>>,
-                        > = IndexMap::new();
-                        message_map.insert("role".to_string(), Either::Left(message.role));
-                        let (content, url) = if items[0] == "text" {
-                            get_content_and_url(0, 1, &image_messages)?
-                        } else {
-                            get_content_and_url(1, 0, &image_messages)?
-                        };
-
-                        let mut content_map = Vec::new();
-                        let mut content_image_map = IndexMap::new();
-                        content_image_map.insert("type".to_string(), "image".to_string());
-                        content_map.push(content_image_map);
-                        let mut content_text_map = IndexMap::new();
-                        content_text_map.insert("type".to_string(), "text".to_string());
-                        content_text_map.insert("text".to_string(), content);
-                        content_map.push(content_text_map);
-
-                        message_map.insert("content".to_string(), Either::Right(content_map));
-                        messages.push(message_map);
-                        image_urls.push(url);
-                    }
-                }
-            }
-            if !image_urls.is_empty() {
-                let mut images = Vec::new();
-                for url_unparsed in image_urls {
-                    let image = util::parse_image_url(&url_unparsed)
-                        .await
-                        .with_context(|| {
-                            format!("Failed to parse image resource: {}", url_unparsed)
-                        })?;
-
-                    images.push(image);
-                }
-                RequestMessage::VisionChat { messages, images }
-            } else {
-                RequestMessage::Chat(messages)
-            }
-        }
-        Either::Right(prompt) => {
-            let mut messages = Vec::new();
-            let mut message_map: IndexMap<String, Either<String, Vec<IndexMap<String, String>>>> =
-                IndexMap::new();
-            message_map.insert("role".to_string(), Either::Left("user".to_string()));
-            message_map.insert("content".to_string(), Either::Left(prompt));
-            messages.push(message_map);
-            RequestMessage::Chat(messages)
+    let journal = match &config.journal {
+        Some(journal) => {
+            println!("Journaling requests to `{}`.", journal.path.display());
+            Some(Journal::open(journal)?)
         }
+        None => None,
     };
-
-    let dry_params = if let Some(dry_multiplier) = oairequest.dry_multiplier {
-        Some(DrySamplingParams::new_with_defaults(
-            dry_multiplier,
-            oairequest.dry_sequence_breakers,
-            oairequest.dry_base,
-            oairequest.dry_allowed_length,
-        )?)
-    } else {
-        None
+    let shutdown = Arc::new(Shutdown::new());
+    let state = Arc::new(AppState {
+        models: ModelRegistry::new(),
+        admission: Arc::new(Admission::new(config.admission)),
+        limits: config.limits,
+        images: ImageFetcher::new(config.images.clone())?,
+        metrics: Arc::new(Metrics::new()?),
+        journal,
+        shutdown: shutdown.clone(),
+        health: Health::new(),
+    });
+    let app = get_router(state.clone(), keys);
+
+    // Serve the probes while the models load, so orchestrators can tell loading from dead.
+    let listener = tokio::net::TcpListener::bind(config.listen).await?;
+    println!("Serving on {}.", config.listen);
+    let loading = {
+        let (config, state) = (config.clone(), state.clone());
+        let handle = tokio::runtime::Handle::current();
+        // Loading blocks for long stretches, keep it off the runtime's worker threads.
+        tokio::task::spawn_blocking(move || handle.block_on(load_models(&config, &state)))
     };
-
-    let is_streaming = oairequest.stream.unwrap_or(false);
-    Ok((
-        Request::Normal(NormalRequest {
-            id: state.next_request_id(),
-            messages,
-            sampling_params: SamplingParams {
-                temperature: oairequest.temperature,
-                top_k: oairequest.top_k,
-                top_p: oairequest.top_p,
-                min_p: oairequest.min_p,
-                top_n_logprobs: oairequest.top_logprobs.unwrap_or(1),
-                frequency_penalty: oairequest.frequency_penalty,
-                presence_penalty: oairequest.presence_penalty,
-                max_len: oairequest.max_tokens,
-                stop_toks,
-                logits_bias: oairequest.logit_bias,
-                n_choices: oairequest.n_choices,
-                dry_params,
-            },
-            response: tx,
-            return_logprobs: oairequest.logprobs,
-            is_streaming,
-            suffix: None,
-            constraint: match oairequest.grammar {
-                Some(Grammar::Yacc(yacc)) => Constraint::Yacc(yacc),
-                Some(Grammar::Regex(regex)) => Constraint::Regex(regex),
-                None => Constraint::None,
-            },
-            adapters: oairequest.adapters,
-            tool_choice: oairequest.tool_choice,
-            tools: oairequest.tools,
-            logits_processors: None,
-        }),
-        is_streaming,
-    ))
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(tag = "type", content = "value")]
-pub enum Grammar {
-    #[serde(rename = "regex")]
-    Regex(String),
-    #[serde(rename = "yacc")]
-    Yacc(String),
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct ChatCompletionRequest {
-    #[serde(with = "either::serde_untagged")]
-    pub messages: Either<Vec<Message>, String>,
-    #[serde(default = "default_model")]
-    pub model: String,
-    pub logit_bias: Option<HashMap<u32, f32>>,
-    #[serde(default = "default_false")]
-    pub logprobs: bool,
-    pub top_logprobs: Option<usize>,
-    pub max_tokens: Option<usize>,
-    #[serde(rename = "n")]
-    #[serde(default = "default_1usize")]
-    pub n_choices: usize,
-    pub presence_penalty: Option<f32>,
-    pub frequency_penalty: Option<f32>,
-    #[serde(rename = "stop")]
-    pub stop_seqs: Option<StopTokens>,
-    pub temperature: Option<f64>,
-    pub top_p: Option<f64>,
-    pub stream: Option<bool>,
-    pub tools: Option<Vec<Tool>>,
-    pub tool_choice: Option<ToolChoice>,
-
-    // mistral.rs additional
-    pub top_k: Option<usize>,
-    pub grammar: Option<Grammar>,
-    pub adapters: Option<Vec<String>>,
-    pub min_p: Option<f64>,
-    pub dry_multiplier: Option<f32>,
-    pub dry_base: Option<f32>,
-    pub dry_allowed_length: Option<usize>,
-    pub dry_sequence_breakers: Option<Vec<String>>,
-}
-
-fn default_false() -> bool {
-    false
-}
-
-fn default_1usize() -> usize {
-    1
-}
-
-fn default_model() -> String {
-    "default".to_string()
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct Message {
-    pub content: MessageContent,
-    pub role: String,
-    pub name: Option<String>,
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct MessageContent(
-    #[serde(with = "either::serde_untagged")]
-    Either<String, Vec<HashMap<String, MessageInnerContent>>>,
-);
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct MessageInnerContent(
-    #[serde(with = "either::serde_untagged")] Either<String, HashMap<String, String>>,
-);
-
-impl Deref for MessageInnerContent {
-    type Target = Either<String, HashMap<String, String>>;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-#[derive(Debug, Clone, Deserialize, Serialize)]
-#[serde(untagged)]
-pub enum StopTokens {
-    Multi(Vec<String>),
-    Single(String),
-}
-
-pub mod util {
-    use image::DynamicImage;
-    use tokio::{
-        fs::{self, File},
-        io::AsyncReadExt,
+    let loaded = async {
+        loading.await??;
+        println!("All models loaded, ready.");
+        health::spawn_canaries(state.clone(), config.health);
+        // Keep serving until the server stops.
+        std::future::pending::<anyhow::Result<()>>().await
     };
-
-    pub async fn parse_image_url(url_unparsed: &str) -> Result<DynamicImage, anyhow::Error> {
-        let url = if let Ok(url) = url::Url::parse(url_unparsed) {
-            url
-        } else if File::open(url_unparsed).await.is_ok() {
-            url::Url::from_file_path(std::path::absolute(url_unparsed)?)
-                .map_err(|_| anyhow::anyhow!("Could not parse file path: {}", url_unparsed))?
-        } else {
-            url::Url::parse(&format!("data:image/png;base64,{}", url_unparsed))
-                .map_err(|_| anyhow::anyhow!("Could not parse as base64 data: {}", url_unparsed))?
-        };
-
-        let bytes = if url.scheme() == "http" || url.scheme() == "https" {
-            // Read from http
-            match reqwest::get(url.clone()).await {
-                Ok(http_resp) => http_resp.bytes().await?.to_vec(),
-                Err(e) => anyhow::bail!(e),
-            }
-        } else if url.scheme() == "file" {
-            let path = url
-                .to_file_path()
-                .map_err(|_| anyhow::anyhow!("Could not parse file path: {}", url))?;
-
-            if let Ok(mut f) = File::open(&path).await {
-                // Read from local file
-                let metadata = fs::metadata(&path).await?;
-                let mut buffer = vec![0; metadata.len() as usize];
-                f.read_exact(&mut buffer).await?;
-                buffer
-            } else {
-                anyhow::bail!("Could not open file at path: {}", url);
-            }
-        } else if url.scheme() == "data" {
-            // Decode with base64
-            let data_url = data_url::DataUrl::process(url.as_str())?;
-            data_url.decode_to_vec()?.0
-        } else {
-            anyhow::bail!("Unsupported URL scheme: {}", url.scheme());
-        };
-
-        Ok(image::load_from_memory(&bytes)?)
+    let grace = config.shutdown_grace;
+    let drain = shutdown.clone();
+    let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+        shutdown::signal().await;
+        println!(
+            "Shutting down, giving running requests {:.0}s to finish.",
+            grace.as_secs_f64()
+        );
+        drain.drain(grace);
+    });
+    tokio::select! {
+        result = server => result?,
+        result = loaded => return result,
+        // Expired streams end right away, but a client may keep its connection open.
+        () = async {
+            shutdown.expired().await;
+            tokio::time::sleep(CLOSE_TIMEOUT).await;
+        } => eprintln!("Warning: closing connections still open after the grace period."),
     }
+    println!("Server stopped.");
+    Ok(())
 }
 
-pub enum ChatCompletionResponder {
-    Sse(Sse<Streamer>),
-    Json(ChatCompletionResponse),
-    ModelError(String, ChatCompletionResponse),
-    InternalError(Box<dyn Error>),
-    ValidationError(Box<dyn Error>),
-}
-
-impl IntoResponse for ChatCompletionResponder {
-    fn into_response(self) -> axum::response::Response {
-        match self {
-            ChatCompletionResponder::Sse(s) => s.into_response(),
-            ChatCompletionResponder::Json(s) => Json(s).into_response(),
-            ChatCompletionResponder::InternalError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
-            }
-            ChatCompletionResponder::ValidationError(e) => {
-                JsonError::new(e.to_string()).to_response(http::StatusCode::UNPROCESSABLE_ENTITY)
-            }
-            ChatCompletionResponder::ModelError(msg, response) => {
-                JsonModelError::new(msg, response)
-                    .to_response(http::StatusCode::INTERNAL_SERVER_ERROR)
-            }
-        }
-    }
-}
-
-trait ErrorToResponse: Serialize {
-    fn to_response(&self, code: StatusCode) -> axum::response::Response {
-        let mut r = Json(self).into_response();
-        *r.status_mut() = code;
-        r
-    }
-}
-
-#[derive(Serialize)]
-struct JsonError {
-    message: String,
-}
-
-impl JsonError {
-    fn new(message: String) -> Self {
-        Self { message }
-    }
-}
-impl ErrorToResponse for JsonError {}
-
-#[derive(Serialize)]
-struct JsonModelError {
-    message: String,
-    partial_response: ChatCompletionResponse,
-}
-
-impl JsonModelError {
-    fn new(message: String, partial_response: ChatCompletionResponse) -> Self {
-        Self {
-            message,
-            partial_response,
-        }
-    }
-}
-
-impl ErrorToResponse for JsonModelError {}
-
-pub struct Streamer {
-    rx: Receiver<Response>,
-    is_done: bool,
-    state: Arc<MistralRs>,
-}
-
-impl futures::Stream for Streamer {
-    type Item = Result<Event, axum::Error>;
-
-    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.is_done {
-            return Poll::Ready(None);
-        }
-        match self.rx.try_recv() {
-            Ok(resp) => match resp {
-                Response::ModelError(msg, _) => {
-                    MistralRs::maybe_log_error(
-                        self.state.clone(),
-                        &ModelErrorMessage(msg.to_string()),
-                    );
-                    Poll::Ready(Some(Ok(Event::default().data(msg))))
-                }
-                Response::ValidationError(e) => {
-                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
-                }
-                Response::InternalError(e) => {
-                    MistralRs::maybe_log_error(self.state.clone(), &*e);
-                    Poll::Ready(Some(Ok(Event::default().data(e.to_string()))))
-                }
-                Response::Chunk(response) => {
-                    if response.choices.iter().all(|x| x.finish_reason.is_some()) {
-                        self.is_done = true;
-                    }
-                    MistralRs::maybe_log_response(self.state.clone(), &response);
-                    Poll::Ready(Some(Event::default().json_data(response)))
-                }
-                Response::Done(_) => unreachable!(),
-                Response::CompletionDone(_) => unreachable!(),
-                Response::CompletionModelError(_, _) => unreachable!(),
-                Response::CompletionChunk(_) => unreachable!(),
-            },
-            Err(_) => Poll::Pending,
-        }
-    }
-}
+fn get_router(state: Arc<AppState>, keys: Option<Arc<ApiKeys>>) -> Router {
+    let allow_origin = AllowOrigin::any();
+    let cors_layer = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            http::HeaderName::from_static(admission::PRIORITY_HEADER),
+            http::HeaderName::from_static(admission::TIMEOUT_HEADER),
+        ])
+        .allow_origin(allow_origin);
 
-#[derive(Debug)]
-struct ModelErrorMessage(String);
-impl std::fmt::Display for ModelErrorMessage {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
-    }
+    // Only generation requests wait for admission, so only they take a ticket. It is added
+    // inside auth, so the ticket can use the API key.
+    let generation = Router::new()
+        .route("/v1/chat/completions", post(chatcompletions))
+        .route("/v1/completions", post(completions))
+        .route_layer(middleware::from_fn_with_state(
+            state.admission.clone(),
+            admission::ticket,
+        ));
+    let api = Router::new()
+        .route("/v1/embeddings", post(embeddings))
+        .route("/v1/tokenize", post(tokenize))
+        .route("/v1/detokenize", post(detokenize))
+        .route("/v1/models", get(models))
+        // Taking adapters in and out of service affects every client, so it needs an admin key.
+        .route("/v1/adapters", get(adapters::list_adapters))
+        .route(
+            "/v1/adapters/:name/enable",
+            post(adapters::enable_adapter).route_layer(middleware::from_fn(auth::require_admin)),
+        )
+        .route(
+            "/v1/adapters/:name/disable",
+            post(adapters::disable_adapter).route_layer(middleware::from_fn(auth::require_admin)),
+        )
+        .merge(generation);
+    // `route_layer` only covers the routes above, so `/health` and `/metrics` stay open.
+    let api = match keys {
+        Some(keys) => api.route_layer(middleware::from_fn_with_state(keys, auth::require_api_key)),
+        None => api,
+    };
+    let api = api.route_layer(middleware::from_fn_with_state(
+        state.shutdown.clone(),
+        shutdown::reject_while_draining,
+    ));
+    // Outside of auth, so rejected requests are counted too.
+    let api = api.route_layer(middleware::from_fn_with_state(
+        state.metrics.clone(),
+        metrics::track,
+    ));
+
+    api.route("/health", get(health::ready))
+        .route("/health/live", get(health::live))
+        .route("/health/ready", get(health::ready))
+        .route("/metrics", get(metrics::metrics))
+        .layer(cors_layer)
+        .layer(DefaultBodyLimit::max(N_INPUT_SIZE * MB_TO_B))
+        .with_state(state)
 }
-impl std::error::Error for ModelErrorMessage {}
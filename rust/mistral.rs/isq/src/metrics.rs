@@ -0,0 +1,424 @@
+//! Prometheus metrics, served as text from `/metrics`.
+//!
+//! The `track` middleware gives every `/v1` request a [`RequestMetrics`], which handlers fill in
+//! with the model, usage and first-token time. It is finished when the response body is dropped,
+//! so streamed responses are timed until their last event.
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use mistralrs::Usage;
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::state::AppState;
+
+/// Latency buckets in seconds, from a fast first token to a long generation.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+const THROUGHPUT_BUCKETS: &[f64] = &[
+    1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0,
+];
+/// Model label for requests that never resolved to a loaded model.
+const NO_MODEL: &str = "";
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    requests_by_mode: IntCounterVec,
+    request_duration: HistogramVec,
+    time_to_first_token: HistogramVec,
+    prompt_tokens: IntCounterVec,
+    completion_tokens: IntCounterVec,
+    throughput: HistogramVec,
+    in_flight: IntGauge,
+    queue_depth: IntGaugeVec,
+    admission_queue: IntGaugeVec,
+    prefix_cache_enabled: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("isq".to_string()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "requests_total",
+                "Requests by endpoint, model and status code.",
+            ),
+            &["endpoint", "model", "status"],
+        )?;
+        let requests_by_mode = IntCounterVec::new(
+            Opts::new(
+                "requests_by_mode_total",
+                "Generation requests by endpoint, model and mode (`stream` or `non_stream`).",
+            ),
+            &["endpoint", "model", "mode"],
+        )?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Time from receiving a request to the end of its response body.",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["endpoint", "model", "mode"],
+        )?;
+        let time_to_first_token = HistogramVec::new(
+            HistogramOpts::new(
+                "time_to_first_token_seconds",
+                "Time from receiving a request to its first generated token. For non-streaming \
+                 requests this is the total time minus the engine's completion time.",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["endpoint", "model", "mode"],
+        )?;
+        let prompt_tokens = IntCounterVec::new(
+            Opts::new(
+                "prompt_tokens_total",
+                "Prompt tokens processed. Streamed responses count theirs once the stream ends.",
+            ),
+            &["model"],
+        )?;
+        let completion_tokens = IntCounterVec::new(
+            Opts::new("completion_tokens_total", "Tokens generated."),
+            &["model"],
+        )?;
+        let throughput = HistogramVec::new(
+            HistogramOpts::new(
+                "tokens_per_second",
+                "Engine throughput per non-streaming request, by phase (`prompt` or `completion`).",
+            )
+            .buckets(THROUGHPUT_BUCKETS.to_vec()),
+            &["model", "phase"],
+        )?;
+        let in_flight = IntGauge::new("requests_in_flight", "Requests being handled.")?;
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "engine_queue_depth",
+                "Requests waiting in the engine's queue, sampled at scrape time.",
+            ),
+            &["model"],
+        )?;
+        let admission_queue = IntGaugeVec::new(
+            Opts::new(
+                "admission_queue_length",
+                "Requests waiting for a running slot, sampled at scrape time.",
+            ),
+            &["model"],
+        )?;
+        let prefix_cache_enabled = IntGaugeVec::new(
+            Opts::new(
+                "prefix_cache_enabled",
+                "1 if the model reuses the KV cache of earlier prompts sharing a prefix, else 0.",
+            ),
+            &["model"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(requests_by_mode.clone()))?;
+        registry.register(Box::new(request_duration.clone()))?;
+        registry.register(Box::new(time_to_first_token.clone()))?;
+        registry.register(Box::new(prompt_tokens.clone()))?;
+        registry.register(Box::new(completion_tokens.clone()))?;
+        registry.register(Box::new(throughput.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(admission_queue.clone()))?;
+        registry.register(Box::new(prefix_cache_enabled.clone()))?;
+        Ok(Self {
+            registry,
+            requests,
+            requests_by_mode,
+            request_duration,
+            time_to_first_token,
+            prompt_tokens,
+            completion_tokens,
+            throughput,
+            in_flight,
+            queue_depth,
+            admission_queue,
+            prefix_cache_enabled,
+        })
+    }
+}
+
+/// What is known about one request so far.
+#[derive(Default)]
+struct RequestInfo {
+    model: Option<String>,
+    streaming: bool,
+    time_to_first_token: Option<Duration>,
+}
+
+/// Metrics of one request, shared by the middleware, the handler and the streamer.
+pub struct RequestMetrics {
+    metrics: Arc<Metrics>,
+    endpoint: String,
+    start: Instant,
+    info: Mutex<RequestInfo>,
+}
+
+impl RequestMetrics {
+    fn new(metrics: Arc<Metrics>, endpoint: String) -> Self {
+        metrics.in_flight.inc();
+        Self {
+            metrics,
+            endpoint,
+            start: Instant::now(),
+            info: Mutex::new(RequestInfo::default()),
+        }
+    }
+
+    /// Called by generation handlers once the model is resolved.
+    pub fn set_model(&self, model: &str, streaming: bool) {
+        let mut info = self.info.lock().unwrap();
+        info.model = Some(model.to_string());
+        info.streaming = streaming;
+        self.metrics
+            .requests_by_mode
+            .with_label_values(&[&self.endpoint, model, mode(streaming)])
+            .inc();
+    }
+
+    /// Records the time to the first streamed chunk. Later calls do nothing.
+    pub fn first_token(&self) {
+        let mut info = self.info.lock().unwrap();
+        if info.time_to_first_token.is_none() {
+            info.time_to_first_token = Some(self.start.elapsed());
+        }
+    }
+
+    /// Records the usage of a finished non-streaming response.
+    pub fn usage(&self, usage: &Usage) {
+        let mut info = self.info.lock().unwrap();
+        let model = info.model.clone().unwrap_or_default();
+        let completion_time = Duration::from_secs_f32(usage.total_completion_time_sec.max(0.0));
+        info.time_to_first_token = Some(self.start.elapsed().saturating_sub(completion_time));
+        self.metrics
+            .prompt_tokens
+            .with_label_values(&[&model])
+            .inc_by(usage.prompt_tokens as u64);
+        self.metrics
+            .completion_tokens
+            .with_label_values(&[&model])
+            .inc_by(usage.completion_tokens as u64);
+        self.metrics
+            .throughput
+            .with_label_values(&[&model, "prompt"])
+            .observe(usage.avg_prompt_tok_per_sec.into());
+        self.metrics
+            .throughput
+            .with_label_values(&[&model, "completion"])
+            .observe(usage.avg_compl_tok_per_sec.into());
+    }
+
+    /// Counts tokens of a streamed response, completion tokens being one per choice delta.
+    pub fn streamed_tokens(&self, prompt_tokens: usize, completion_tokens: usize) {
+        let info = self.info.lock().unwrap();
+        let model = info.model.as_deref().unwrap_or(NO_MODEL);
+        self.metrics
+            .prompt_tokens
+            .with_label_values(&[model])
+            .inc_by(prompt_tokens as u64);
+        self.metrics
+            .completion_tokens
+            .with_label_values(&[model])
+            .inc_by(completion_tokens as u64);
+    }
+
+    fn status(&self, status: StatusCode) {
+        let info = self.info.lock().unwrap();
+        let model = info.model.as_deref().unwrap_or(NO_MODEL);
+        self.metrics
+            .requests
+            .with_label_values(&[&self.endpoint, model, status.as_str()])
+            .inc();
+    }
+}
+
+impl Drop for RequestMetrics {
+    fn drop(&mut self) {
+        self.metrics.in_flight.dec();
+        let info = self.info.get_mut().unwrap();
+        // Only generation requests are timed.
+        let Some(model) = &info.model else {
+            return;
+        };
+        let labels = [self.endpoint.as_str(), model, mode(info.streaming)];
+        self.metrics
+            .request_duration
+            .with_label_values(&labels)
+            .observe(self.start.elapsed().as_secs_f64());
+        if let Some(ttft) = info.time_to_first_token {
+            self.metrics
+                .time_to_first_token
+                .with_label_values(&labels)
+                .observe(ttft.as_secs_f64());
+        }
+    }
+}
+
+fn mode(streaming: bool) -> &'static str {
+    if streaming {
+        "stream"
+    } else {
+        "non_stream"
+    }
+}
+
+/// Attaches a [`RequestMetrics`] to the request, counts the response status, and keeps the
+/// metrics alive until the response body is done.
+pub async fn track(
+    State(metrics): State<Arc<Metrics>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let tracker = Arc::new(RequestMetrics::new(metrics, endpoint));
+    request.extensions_mut().insert(tracker.clone());
+
+    let response = next.run(request).await;
+    tracker.status(response.status());
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _tracker = &tracker;
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+pub async fn metrics(State(app): State<Arc<AppState>>) -> Response {
+    for (name, model) in app.models.list() {
+        if let Some(depth) = model.engine.queue_depth() {
+            app.metrics
+                .queue_depth
+                .with_label_values(&[&name])
+                .set(depth as i64);
+        }
+        app.metrics
+            .prefix_cache_enabled
+            .with_label_values(&[&name])
+            .set(model.engine.prefix_cache_enabled().into());
+    }
+    for (name, waiting) in app.admission.waiting() {
+        app.metrics
+            .admission_queue
+            .with_label_values(&[&name])
+            .set(waiting as i64);
+    }
+    match TextEncoder::new().encode_to_string(&app.metrics.registry.gather()) {
+        Ok(text) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], text).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        engine::fake::{app, chat_chunk, chat_done, post, FakeEngine},
+        state::AppState,
+    };
+    use axum::{body::Body, http::Request as HttpRequest};
+    use mistralrs::Response;
+    use serde_json::json;
+    use std::{sync::Arc, time::Duration};
+    use tower::ServiceExt as _;
+
+    async fn scrape(app: &Arc<AppState>) -> String {
+        let request = HttpRequest::get("/metrics").body(Body::empty()).unwrap();
+        let response = crate::get_router(app.clone(), None)
+            .oneshot(request)
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    /// Value of the sample of `name` with all of `labels`, 0 if there is none.
+    fn value(text: &str, name: &str, labels: &[(&str, &str)]) -> f64 {
+        text.lines()
+            .filter(|line| line.starts_with(&format!("isq_{name}")))
+            .filter(|line| line[4 + name.len()..].starts_with(['{', ' ']))
+            .find(|line| {
+                labels
+                    .iter()
+                    .all(|(k, v)| line.contains(&format!("{k}=\"{v}\"")))
+            })
+            .map_or(0.0, |line| {
+                line.rsplit(' ').next().unwrap().parse().unwrap()
+            })
+    }
+
+    #[tokio::test]
+    async fn counts_requests_modes_and_tokens() {
+        let engine = FakeEngine::new([
+            vec![Response::Done(chat_done("hi there", "stop"))],
+            vec![chat_chunk("a", None), chat_chunk("b", Some("stop"))],
+        ]);
+        let app = app(engine);
+        let chat = "/v1/chat/completions";
+        let request = |stream| json!({ "messages": "hi", "stream": stream });
+
+        let (status, _) = post(app.clone(), chat, request(false)).await;
+        assert_eq!(status, 200);
+        let (status, _) = post(app.clone(), chat, request(true)).await;
+        assert_eq!(status, 200);
+        let (status, _) = post(app.clone(), chat, json!({ "messages": "hi", "n": 100 })).await;
+        assert_eq!(status, 400);
+        let (status, _) = post(app.clone(), chat, json!({ "model": "x", "messages": "hi" })).await;
+        assert_eq!(status, 404);
+
+        // A stream's prompt tokens are counted by a task once it ends. The fake engine's usage
+        // has 3 prompt and 2 completion tokens, the stream 24 prompt tokens and 2 deltas.
+        let mut text = scrape(&app).await;
+        for _ in 0..100 {
+            if value(&text, "prompt_tokens_total", &[("model", "fake")]) == 27.0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            text = scrape(&app).await;
+        }
+        let requests = |model, status| {
+            let labels = [("endpoint", chat), ("model", model), ("status", status)];
+            value(&text, "requests_total", &labels)
+        };
+        assert_eq!(requests("fake", "200"), 2.0);
+        assert_eq!(requests("fake", "400"), 1.0);
+        assert_eq!(requests("", "404"), 1.0);
+        let mode = |mode| value(&text, "requests_by_mode_total", &[("mode", mode)]);
+        assert_eq!(mode("stream"), 1.0);
+        assert_eq!(mode("non_stream"), 2.0);
+        assert_eq!(
+            value(&text, "prompt_tokens_total", &[("model", "fake")]),
+            27.0
+        );
+        assert_eq!(
+            value(&text, "completion_tokens_total", &[("model", "fake")]),
+            4.0
+        );
+        assert_eq!(value(&text, "requests_in_flight", &[]), 0.0);
+        let fake = [("model", "fake")];
+        assert_eq!(value(&text, "prefix_cache_enabled", &fake), 0.0);
+        let duration = [("model", "fake"), ("mode", "stream")];
+        assert_eq!(
+            value(&text, "request_duration_seconds_count", &duration),
+            1.0
+        );
+    }
+}
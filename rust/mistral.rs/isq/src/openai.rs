@@ -0,0 +1,304 @@
+use either::Either;
+use mistralrs::{Tool, ToolChoice};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+use crate::config::AdapterKind;
+
+/// `detail` of an `image_url` part. `low` downscales the image before it reaches the model.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetail {
+    #[default]
+    Auto,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default)]
+    pub detail: ImageDetail,
+}
+
+/// One item of an array-valued message `content`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+/// Message `content`: a plain string, or any number of text and image parts in any order.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl<'de> Deserialize<'de> for MessageContent {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Text(String),
+            Parts(Vec<serde_json::Value>),
+        }
+
+        // Parts are decoded one by one so an error names the part at fault, instead of
+        // serde's "did not match any variant" for the whole array.
+        match Raw::deserialize(deserializer)
+            .map_err(|_| D::Error::custom("expected a string or an array of content parts"))?
+        {
+            Raw::Text(text) => Ok(Self::Text(text)),
+            Raw::Parts(parts) => parts
+                .into_iter()
+                .enumerate()
+                .map(|(i, part)| {
+                    serde_json::from_value(part)
+                        .map_err(|e| D::Error::custom(format!("content part {i}: {e}")))
+                })
+                .collect::<Result<_, _>>()
+                .map(Self::Parts),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Message {
+    pub content: MessageContent,
+    pub role: String,
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum StopTokens {
+    Multi(Vec<String>),
+    Single(String),
+}
+
+fn default_false() -> bool {
+    false
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_1usize() -> usize {
+    1
+}
+
+fn default_model() -> String {
+    "default".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", content = "value")]
+pub enum Grammar {
+    #[serde(rename = "regex")]
+    Regex(String),
+    #[serde(rename = "yacc")]
+    Yacc(String),
+}
+
+/// `json_schema` of a `{"type": "json_schema"}` response format.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+    pub description: Option<String>,
+    /// Missing means any JSON object.
+    pub schema: Option<serde_json::Value>,
+    /// Accepted for compatibility. Schemas are always enforced.
+    pub strict: Option<bool>,
+}
+
+/// OpenAI's `response_format`. `json_object` and `json_schema` are enforced by the engine.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChatCompletionRequest {
+    #[serde(with = "either::serde_untagged")]
+    pub messages: Either<Vec<Message>, String>,
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default = "default_false")]
+    pub logprobs: bool,
+    pub top_logprobs: Option<usize>,
+    pub max_tokens: Option<usize>,
+    #[serde(rename = "n")]
+    #[serde(default = "default_1usize")]
+    pub n_choices: usize,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    #[serde(rename = "stop")]
+    pub stop_seqs: Option<StopTokens>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub stream: Option<bool>,
+    pub tools: Option<Vec<Tool>>,
+    pub tool_choice: Option<ToolChoice>,
+    pub response_format: Option<ResponseFormat>,
+
+    // mistral.rs additional
+    pub top_k: Option<usize>,
+    pub grammar: Option<Grammar>,
+    pub adapters: Option<Vec<String>>,
+    pub min_p: Option<f64>,
+    pub dry_multiplier: Option<f32>,
+    pub dry_base: Option<f32>,
+    pub dry_allowed_length: Option<usize>,
+    pub dry_sequence_breakers: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CompletionRequest {
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub prompt: String,
+    #[serde(default = "default_1usize")]
+    pub best_of: usize,
+    #[serde(rename = "echo")]
+    #[serde(default = "default_false")]
+    pub echo_prompt: bool,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    pub logprobs: Option<usize>,
+    pub max_tokens: Option<usize>,
+    #[serde(rename = "n")]
+    #[serde(default = "default_1usize")]
+    pub n_choices: usize,
+    #[serde(rename = "stop")]
+    pub stop_seqs: Option<StopTokens>,
+    pub stream: Option<bool>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    /// Text that comes after the completion, for fill-in-the-middle.
+    pub suffix: Option<String>,
+    #[serde(rename = "user")]
+    pub _user: Option<String>,
+    pub tools: Option<Vec<Tool>>,
+    pub tool_choice: Option<ToolChoice>,
+
+    // mistral.rs additional
+    pub top_k: Option<usize>,
+    pub grammar: Option<Grammar>,
+    pub adapters: Option<Vec<String>>,
+    pub min_p: Option<f64>,
+    pub dry_multiplier: Option<f32>,
+    pub dry_base: Option<f32>,
+    pub dry_allowed_length: Option<usize>,
+    pub dry_sequence_breakers: Option<Vec<String>>,
+}
+
+/// `input` of an embeddings request: one text or a batch of them.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Multi(Vec<String>),
+    Single(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EmbeddingRequest {
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub input: EmbeddingInput,
+    #[serde(rename = "user")]
+    pub _user: Option<String>,
+}
+
+/// `/v1/tokenize`: exactly one of `prompt` and `messages`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TokenizeRequest {
+    #[serde(default = "default_model")]
+    pub model: String,
+    /// Raw text, tokenized as a completion prompt.
+    pub prompt: Option<String>,
+    /// Chat messages, rendered through the chat template first.
+    pub messages: Option<Vec<Message>>,
+    /// Only for `prompt`: chat templates write their own special tokens.
+    #[serde(default = "default_true")]
+    pub add_special_tokens: bool,
+    /// Only for `messages`: end with the start of an assistant turn, as a chat request does.
+    #[serde(default = "default_true")]
+    pub add_generation_prompt: bool,
+    /// Include the text that was tokenized in the response.
+    #[serde(default = "default_false")]
+    pub return_prompt: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenizeResponse {
+    pub model: String,
+    pub tokens: Vec<u32>,
+    pub count: usize,
+    /// Context window of the model, prompt and completion together.
+    pub max_model_len: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DetokenizeRequest {
+    #[serde(default = "default_model")]
+    pub model: String,
+    pub tokens: Vec<u32>,
+    #[serde(default = "default_true")]
+    pub skip_special_tokens: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetokenizeResponse {
+    pub model: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterObject {
+    pub object: &'static str,
+    pub name: String,
+    pub model: String,
+    pub kind: AdapterKind,
+    /// HF model id or local directory the weights were loaded from.
+    pub source: String,
+    /// Used by requests that pick no adapters.
+    pub default: bool,
+    /// Requests may use it. Cleared by `POST /v1/adapters/{name}/disable`.
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AdapterList {
+    pub object: &'static str,
+    pub data: Vec<AdapterObject>,
+}
+
+/// OpenAI error object, `{"error": {"message", "type", "param", "code"}}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorResponse {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub tp: String,
+    pub param: Option<String>,
+    pub code: Option<String>,
+}
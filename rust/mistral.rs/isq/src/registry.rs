@@ -0,0 +1,98 @@
+//! Named engines served from one process, selected by the request's `model` field.
+//!
+//! Models are added as they finish loading, while the server is already answering probes.
+
+use axum::extract::{Json, State};
+use indexmap::IndexMap;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+
+use crate::{config::DEFAULT_MODEL_NAME, engine::Engine, error::ApiError, state::AppState};
+
+#[derive(Clone)]
+pub struct Model {
+    pub engine: Arc<dyn Engine>,
+    pub info: ModelInfo,
+}
+
+/// What a model is, as reported by the health probes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelInfo {
+    pub model_id: String,
+    pub device: String,
+    /// ISQ type, `gguf` for pre-quantized files, or `none`.
+    pub quantization: String,
+}
+
+pub struct ModelRegistry {
+    models: RwLock<IndexMap<String, Model>>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self {
+            models: RwLock::new(IndexMap::new()),
+        }
+    }
+
+    pub fn insert(&self, name: String, engine: Arc<dyn Engine>, info: ModelInfo) {
+        self.models
+            .write()
+            .unwrap()
+            .insert(name, Model { engine, info });
+    }
+
+    /// Looks up a model by name, returning its registered name. `default` resolves to the first
+    /// registered model.
+    pub fn get(&self, name: &str) -> Result<(String, Arc<dyn Engine>), ApiError> {
+        let models = self.models.read().unwrap();
+        let model = if name == DEFAULT_MODEL_NAME {
+            models.first()
+        } else {
+            models.get_key_value(name)
+        };
+        model
+            .map(|(name, model)| (name.clone(), model.engine.clone()))
+            .ok_or_else(|| ApiError::ModelNotFound(name.to_string()))
+    }
+
+    /// The models loaded so far, in order.
+    pub fn list(&self) -> Vec<(String, Model)> {
+        let models = self.models.read().unwrap();
+        models
+            .iter()
+            .map(|(name, model)| (name.clone(), model.clone()))
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ModelObject {
+    id: String,
+    object: &'static str,
+    created: u64,
+    owned_by: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct ModelObjects {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
+
+pub async fn models(State(app): State<Arc<AppState>>) -> Json<ModelObjects> {
+    Json(ModelObjects {
+        object: "list",
+        data: app
+            .models
+            .list()
+            .into_iter()
+            .map(|(name, model)| ModelObject {
+                id: name,
+                object: "model",
+                created: model.engine.creation_time(),
+                owned_by: "local",
+            })
+            .collect(),
+    })
+}
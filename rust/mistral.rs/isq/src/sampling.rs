@@ -0,0 +1,416 @@
+//! Range checks on sampling parameters, so a bad value is a 400 naming the parameter instead
+//! of an engine error, or a silently odd generation.
+
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use crate::{
+    config::LimitsConfig,
+    error::ApiError,
+    openai::{ChatCompletionRequest, CompletionRequest},
+};
+
+const TEMPERATURE: RangeInclusive<f64> = 0.0..=2.0;
+const PENALTY: RangeInclusive<f32> = -2.0..=2.0;
+const LOGIT_BIAS: RangeInclusive<f32> = -100.0..=100.0;
+/// Alternatives OpenAI returns at most per token.
+const MAX_TOP_LOGPROBS: usize = 20;
+
+/// The sampling fields chat and text completions share.
+#[derive(Debug, Clone, Default)]
+pub struct Sampling<'a> {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f64>,
+    pub n_choices: usize,
+    pub max_tokens: Option<usize>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub logit_bias: Option<&'a HashMap<u32, f32>>,
+    pub logprobs: bool,
+    pub top_logprobs: Option<usize>,
+    pub dry_multiplier: Option<f32>,
+    pub dry_base: Option<f32>,
+    pub dry_allowed_length: Option<usize>,
+    pub dry_sequence_breakers: Option<&'a [String]>,
+}
+
+impl<'a> From<&'a ChatCompletionRequest> for Sampling<'a> {
+    fn from(request: &'a ChatCompletionRequest) -> Self {
+        Self {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            min_p: request.min_p,
+            n_choices: request.n_choices,
+            max_tokens: request.max_tokens,
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            logit_bias: request.logit_bias.as_ref(),
+            logprobs: request.logprobs,
+            top_logprobs: request.top_logprobs,
+            dry_multiplier: request.dry_multiplier,
+            dry_base: request.dry_base,
+            dry_allowed_length: request.dry_allowed_length,
+            dry_sequence_breakers: request.dry_sequence_breakers.as_deref(),
+        }
+    }
+}
+
+impl<'a> From<&'a CompletionRequest> for Sampling<'a> {
+    fn from(request: &'a CompletionRequest) -> Self {
+        Self {
+            temperature: request.temperature,
+            top_p: request.top_p,
+            top_k: request.top_k,
+            min_p: request.min_p,
+            n_choices: request.n_choices,
+            max_tokens: request.max_tokens,
+            presence_penalty: request.presence_penalty,
+            frequency_penalty: request.frequency_penalty,
+            logit_bias: request.logit_bias.as_ref(),
+            logprobs: false,
+            top_logprobs: None,
+            dry_multiplier: request.dry_multiplier,
+            dry_base: request.dry_base,
+            dry_allowed_length: request.dry_allowed_length,
+            dry_sequence_breakers: request.dry_sequence_breakers.as_deref(),
+        }
+    }
+}
+
+impl Sampling<'_> {
+    /// Checks every field against its range and `limits`, and token ids against `vocab_size`
+    /// when the model has a tokenizer. Fields are checked in declaration order.
+    pub fn validate(
+        &self,
+        limits: &LimitsConfig,
+        vocab_size: Option<usize>,
+    ) -> Result<(), ApiError> {
+        check_range("temperature", self.temperature, &TEMPERATURE)?;
+        if let Some(top_p) = self.top_p {
+            if !(top_p > 0.0 && top_p <= 1.0) {
+                return Err(invalid("top_p", format!("must be in (0, 1], got {top_p}.")));
+            }
+        }
+        if self.top_k == Some(0) {
+            return Err(invalid("top_k", "must be at least 1.".to_string()));
+        }
+        check_range("min_p", self.min_p, &(0.0..=1.0))?;
+        if !(1..=limits.max_n).contains(&self.n_choices) {
+            return Err(invalid(
+                "n",
+                format!(
+                    "must be between 1 and {}, got {}.",
+                    limits.max_n, self.n_choices
+                ),
+            ));
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            let cap = limits.max_tokens.unwrap_or(usize::MAX);
+            if !(1..=cap).contains(&max_tokens) {
+                let message = match limits.max_tokens {
+                    Some(cap) => format!("must be between 1 and {cap}, got {max_tokens}."),
+                    None => "must be at least 1.".to_string(),
+                };
+                return Err(invalid("max_tokens", message));
+            }
+        }
+        check_range("presence_penalty", self.presence_penalty, &PENALTY)?;
+        check_range("frequency_penalty", self.frequency_penalty, &PENALTY)?;
+        for (&token, &bias) in self.logit_bias.into_iter().flatten() {
+            if vocab_size.is_some_and(|size| token as usize >= size) {
+                return Err(invalid(
+                    "logit_bias",
+                    format!(
+                        "token {token} is not in the vocabulary of {} tokens.",
+                        vocab_size.unwrap()
+                    ),
+                ));
+            }
+            if !LOGIT_BIAS.contains(&bias) {
+                return Err(invalid(
+                    "logit_bias",
+                    format!("bias of token {token} must be in [-100, 100], got {bias}."),
+                ));
+            }
+        }
+        if let Some(top_logprobs) = self.top_logprobs {
+            if !self.logprobs {
+                return Err(invalid(
+                    "top_logprobs",
+                    "needs `logprobs` set to true.".to_string(),
+                ));
+            }
+            if top_logprobs > MAX_TOP_LOGPROBS {
+                return Err(invalid(
+                    "top_logprobs",
+                    format!("must be at most {MAX_TOP_LOGPROBS}, got {top_logprobs}."),
+                ));
+            }
+        }
+        self.validate_dry()
+    }
+
+    /// DRY's other settings only mean something with a multiplier.
+    fn validate_dry(&self) -> Result<(), ApiError> {
+        let Some(multiplier) = self.dry_multiplier else {
+            let set = [
+                ("dry_base", self.dry_base.is_some()),
+                ("dry_allowed_length", self.dry_allowed_length.is_some()),
+                (
+                    "dry_sequence_breakers",
+                    self.dry_sequence_breakers.is_some(),
+                ),
+            ];
+            return match set.into_iter().find(|(_, set)| *set) {
+                Some((param, _)) => Err(invalid(param, "needs `dry_multiplier`.".to_string())),
+                None => Ok(()),
+            };
+        };
+        if !(multiplier >= 0.0 && multiplier.is_finite()) {
+            return Err(invalid(
+                "dry_multiplier",
+                format!("must be 0 or more, got {multiplier}."),
+            ));
+        }
+        if let Some(base) = self.dry_base {
+            if !(base >= 1.0 && base.is_finite()) {
+                return Err(invalid(
+                    "dry_base",
+                    format!("must be at least 1, got {base}."),
+                ));
+            }
+        }
+        if self
+            .dry_sequence_breakers
+            .is_some_and(|breakers| breakers.iter().any(String::is_empty))
+        {
+            return Err(invalid(
+                "dry_sequence_breakers",
+                "must not contain empty strings.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(
+    param: &str,
+    value: Option<T>,
+    range: &RangeInclusive<T>,
+) -> Result<(), ApiError> {
+    match value {
+        Some(value) if !range.contains(&value) => Err(invalid(
+            param,
+            format!(
+                "must be in [{}, {}], got {value}.",
+                range.start(),
+                range.end()
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn invalid(param: &str, message: String) -> ApiError {
+    ApiError::invalid_request(format!("`{param}` {message}")).with_param(param)
+}
+
+#[cfg(test)]
+mod tests {
+    //! Property tests: random requests drawn around the edges of every range.
+
+    use super::*;
+
+    const CASES: usize = 2000;
+    const VOCAB: usize = 1000;
+    const LIMITS: LimitsConfig = LimitsConfig {
+        max_tokens: Some(512),
+        max_n: 4,
+    };
+
+    /// xorshift64, enough to spread cases without a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn chance(&mut self) -> bool {
+            self.next() & 1 == 0
+        }
+
+        fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+            items[self.below(items.len())]
+        }
+    }
+
+    /// A request whose every field is either unset or in range.
+    fn valid(rng: &mut Rng, bias: &mut HashMap<u32, f32>) -> Sampling<'static> {
+        *bias = (0..rng.below(3))
+            .map(|_| {
+                let bias = rng.pick(&[-100.0, -1.5, 0.0, 3.0, 100.0]);
+                (rng.below(VOCAB) as u32, bias)
+            })
+            .collect();
+        let logprobs = rng.chance();
+        let dry = rng.chance();
+        Sampling {
+            temperature: rng.chance().then(|| rng.pick(&[0.0, 0.7, 1.0, 2.0])),
+            top_p: rng.chance().then(|| rng.pick(&[1e-6, 0.5, 1.0])),
+            top_k: rng.chance().then(|| 1 + rng.below(100)),
+            min_p: rng.chance().then(|| rng.pick(&[0.0, 0.05, 1.0])),
+            n_choices: 1 + rng.below(LIMITS.max_n),
+            max_tokens: rng.chance().then(|| 1 + rng.below(512)),
+            presence_penalty: rng.chance().then(|| rng.pick(&[-2.0, 0.0, 2.0])),
+            frequency_penalty: rng.chance().then(|| rng.pick(&[-2.0, 0.5, 2.0])),
+            logit_bias: None,
+            logprobs,
+            top_logprobs: (logprobs && rng.chance()).then(|| rng.below(MAX_TOP_LOGPROBS + 1)),
+            dry_multiplier: dry.then(|| rng.pick(&[0.0, 0.8, 4.0])),
+            dry_base: (dry && rng.chance()).then(|| rng.pick(&[1.0, 1.75, 4.0])),
+            dry_allowed_length: (dry && rng.chance()).then(|| rng.below(10)),
+            dry_sequence_breakers: None,
+        }
+    }
+
+    /// Breaks one field of `sampling`, returning the parameter the error must name.
+    fn break_one(
+        rng: &mut Rng,
+        sampling: &mut Sampling<'_>,
+        bias: &mut HashMap<u32, f32>,
+    ) -> &'static str {
+        match rng.below(12) {
+            0 => {
+                sampling.temperature = Some(rng.pick(&[-0.1, 2.01, f64::INFINITY, f64::NAN]));
+                "temperature"
+            }
+            1 => {
+                sampling.top_p = Some(rng.pick(&[0.0, -1.0, 1.01, f64::NAN]));
+                "top_p"
+            }
+            2 => {
+                sampling.top_k = Some(0);
+                "top_k"
+            }
+            3 => {
+                sampling.min_p = Some(rng.pick(&[-0.01, 1.5]));
+                "min_p"
+            }
+            4 => {
+                sampling.n_choices = rng.pick(&[0, LIMITS.max_n + 1, usize::MAX]);
+                "n"
+            }
+            5 => {
+                sampling.max_tokens = Some(rng.pick(&[0, 513, usize::MAX]));
+                "max_tokens"
+            }
+            6 => {
+                sampling.presence_penalty = Some(rng.pick(&[-2.5, 2.5]));
+                "presence_penalty"
+            }
+            7 => {
+                sampling.frequency_penalty = Some(rng.pick(&[-2.5, 2.5]));
+                "frequency_penalty"
+            }
+            8 => {
+                let token = (VOCAB + rng.below(10)) as u32;
+                bias.insert(token, 1.0);
+                "logit_bias"
+            }
+            9 => {
+                bias.insert(
+                    rng.below(VOCAB) as u32,
+                    rng.pick(&[-100.5, 101.0, f32::NAN]),
+                );
+                "logit_bias"
+            }
+            10 => {
+                if sampling.logprobs {
+                    sampling.top_logprobs = Some(MAX_TOP_LOGPROBS + 1 + rng.below(5));
+                } else {
+                    sampling.top_logprobs = Some(rng.below(MAX_TOP_LOGPROBS + 1));
+                }
+                "top_logprobs"
+            }
+            _ => match sampling.dry_multiplier {
+                Some(_) => {
+                    sampling.dry_base = Some(rng.pick(&[0.5, -1.0, f32::INFINITY]));
+                    "dry_base"
+                }
+                None => {
+                    sampling.dry_base = Some(1.75);
+                    "dry_base"
+                }
+            },
+        }
+    }
+
+    #[test]
+    fn in_range_requests_pass() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for case in 0..CASES {
+            let mut bias = HashMap::new();
+            let mut sampling = valid(&mut rng, &mut bias);
+            sampling.logit_bias = Some(&bias);
+            if let Err(e) = sampling.validate(&LIMITS, Some(VOCAB)) {
+                panic!("case {case}: {sampling:?} rejected: {e}");
+            }
+        }
+    }
+
+    #[test]
+    fn one_bad_field_is_named() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for case in 0..CASES {
+            let mut bias = HashMap::new();
+            let mut sampling = valid(&mut rng, &mut bias);
+            let param = break_one(&mut rng, &mut sampling, &mut bias);
+            sampling.logit_bias = Some(&bias);
+            match sampling.validate(&LIMITS, Some(VOCAB)) {
+                Ok(()) => panic!("case {case}: {sampling:?} accepted, expected {param} rejected"),
+                Err(e) => assert_eq!(
+                    e.detail().param.as_deref(),
+                    Some(param),
+                    "case {case}: {sampling:?}: {e}"
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_vocabulary_skips_the_token_check() {
+        let bias = HashMap::from([(u32::MAX, 1.0)]);
+        let sampling = Sampling {
+            n_choices: 1,
+            logit_bias: Some(&bias),
+            ..Sampling::default()
+        };
+        assert!(sampling.validate(&LIMITS, None).is_ok());
+        assert!(sampling.validate(&LIMITS, Some(VOCAB)).is_err());
+    }
+
+    #[test]
+    fn no_token_cap_still_needs_one_token() {
+        let limits = LimitsConfig {
+            max_tokens: None,
+            ..LIMITS
+        };
+        let sampling = |max_tokens| Sampling {
+            n_choices: 1,
+            max_tokens: Some(max_tokens),
+            ..Sampling::default()
+        };
+        assert!(sampling(usize::MAX).validate(&limits, None).is_ok());
+        assert!(sampling(0).validate(&limits, None).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! Graceful shutdown on SIGTERM or SIGINT.
+//!
+//! On the signal the server stops accepting connections and starts draining: `/health` answers
+//! 503 so load balancers move traffic away, and new `/v1` requests on open connections are
+//! refused. Requests already running get the grace period to finish. When it expires, open
+//! streams end with an error event and requests still waiting for the engine get a 503.
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{future::Future, sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Phase {
+    Running,
+    Draining,
+    /// The grace period is over.
+    Expired,
+}
+
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            phase: watch::Sender::new(Phase::Running),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.phase.borrow() >= Phase::Draining
+    }
+
+    /// Starts draining and expires the grace period after `grace`.
+    pub fn drain(self: &Arc<Self>, grace: Duration) {
+        self.phase.send_replace(Phase::Draining);
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            shutdown.expire();
+        });
+    }
+
+    pub fn expire(&self) {
+        self.phase.send_replace(Phase::Expired);
+    }
+
+    /// Resolves when the grace period is over.
+    pub fn expired(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut phase = self.phase.subscribe();
+        async move {
+            // An error means `Shutdown` is gone, which does not end the grace period.
+            if phase
+                .wait_for(|phase| *phase == Phase::Expired)
+                .await
+                .is_err()
+            {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// The error sent to requests cut off at the end of the grace period.
+    pub fn error() -> ApiError {
+        ApiError::Unavailable("The server is shutting down.".to_string())
+    }
+}
+
+/// Resolves on SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Warning: could not listen for Ctrl-C: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Warning: could not listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
+
+/// Refuses requests while draining. They can only come in on connections opened before the
+/// signal.
+pub async fn reject_while_draining(
+    State(shutdown): State<Arc<Shutdown>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if shutdown.is_draining() {
+        return Shutdown::error().into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::{app, chat_chunk, post, sse_data, FakeEngine};
+    use axum::{
+        body::Body,
+        http::{Request as HttpRequest, StatusCode},
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt as _;
+
+    /// Waits until the engine has the request, so draining cannot refuse it.
+    async fn submitted(engine: &FakeEngine) {
+        while engine.requests().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn health_fails_while_draining() {
+        let app = app(FakeEngine::new([]));
+        let health = || async {
+            crate::get_router(app.clone(), None)
+                .oneshot(HttpRequest::get("/health").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+                .status()
+        };
+        assert_eq!(health().await, StatusCode::OK);
+        app.shutdown.drain(Duration::from_secs(60));
+        assert_eq!(health().await, StatusCode::SERVICE_UNAVAILABLE);
+
+        let request = json!({ "messages": "hi" });
+        let (status, _) = post(app.clone(), "/v1/chat/completions", request).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn expiry_closes_open_streams() {
+        let engine = FakeEngine::hanging([vec![chat_chunk("hel", None)]]);
+        let app = app(engine.clone());
+        let request = json!({ "messages": "hi", "stream": true });
+        let stream = tokio::spawn(post(app.clone(), "/v1/chat/completions", request));
+        submitted(&engine).await;
+        app.shutdown.drain(Duration::from_millis(50));
+
+        let (status, body) = stream.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        let events = sse_data(&body);
+        assert_eq!(events.len(), 3, "{events:?}");
+        let error = serde_json::from_str::<Value>(&events[1]).unwrap();
+        assert_eq!(error["error"]["message"], "The server is shutting down.");
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn expiry_answers_waiting_requests() {
+        let engine = FakeEngine::hanging([vec![]]);
+        let app = app(engine.clone());
+        let request = json!({ "prompt": "hello" });
+        let waiting = tokio::spawn(post(app.clone(), "/v1/completions", request));
+        submitted(&engine).await;
+        app.shutdown.drain(Duration::from_millis(50));
+        let (status, _) = waiting.await.unwrap();
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,19 @@
+//! State shared by all handlers.
+
+use std::sync::Arc;
+
+use crate::{
+    admission::Admission, config::LimitsConfig, health::Health, journal::Journal, metrics::Metrics,
+    registry::ModelRegistry, shutdown::Shutdown, util::ImageFetcher,
+};
+
+pub struct AppState {
+    pub models: ModelRegistry,
+    pub admission: Arc<Admission>,
+    pub limits: LimitsConfig,
+    pub images: ImageFetcher,
+    pub metrics: Arc<Metrics>,
+    pub journal: Option<Journal>,
+    pub shutdown: Arc<Shutdown>,
+    pub health: Health,
+}
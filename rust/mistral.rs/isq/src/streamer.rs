@@ -0,0 +1,337 @@
+//! SSE stream shared by `/v1/chat/completions` and `/v1/completions`.
+
+use axum::response::sse::{Event, KeepAlive};
+use mistralrs::{Request, RequestMessage, Response};
+use std::{
+    collections::BTreeMap,
+    env,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{sync::mpsc::Receiver, task::JoinHandle};
+
+use crate::{
+    admission::Permit,
+    auth::KeyBudget,
+    engine::{Engine, LoggedResponse, TokenizeInput},
+    error::{ApiError, ModelErrorMessage},
+    journal::{JournalRecord, StreamAssembler},
+    json_schema::OutputCheck,
+    metrics::RequestMetrics,
+    shutdown::Shutdown,
+};
+
+/// Keep-alive for SSE responses, interval configurable through `KEEP_ALIVE_INTERVAL` (ms).
+pub(crate) fn get_keep_alive() -> KeepAlive {
+    KeepAlive::new()
+        .interval(Duration::from_millis(
+            env::var("KEEP_ALIVE_INTERVAL")
+                .map(|val| val.parse::<u64>().unwrap_or(1000))
+                .unwrap_or(1000),
+        ))
+        .text("keep-alive-text")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    /// Forwarding chunks from the engine.
+    Running,
+    /// The last chunk or an error went out, `data: [DONE]` is next.
+    SendingDone,
+    /// `[DONE]` was sent, the stream is finished.
+    Done,
+}
+
+/// Forwards the engine's streamed chunks as SSE events.
+///
+/// Errors are sent as `{"error": {...}}` events and the stream always ends with `data: [DONE]`,
+/// like OpenAI. If the client goes away first, axum drops the stream and `Drop` closes the
+/// response channel, so the engine's next send for this sequence fails and it stops generating.
+///
+/// Chunks carry no usage, so when the stream ends the API key's budget, the token metrics and
+/// the journal get the [`PromptTokens`] counted alongside, plus one token per streamed choice
+/// delta.
+///
+/// Chat chunks are checked against `response_format` and the declared tools as they come. A
+/// chunk that fails is replaced by an error event, which ends the stream, and the stream is not
+/// charged to the API key.
+///
+/// When the shutdown grace period runs out, the stream ends with an error event as well.
+///
+/// The request's admission permit is held until the stream is dropped.
+pub struct Streamer {
+    rx: Receiver<Response>,
+    stream_state: StreamState,
+    state: Arc<dyn Engine>,
+    budget: Option<Arc<KeyBudget>>,
+    metrics: Option<Arc<RequestMetrics>>,
+    /// Boxed like `output_check`.
+    journal: Option<Box<(JournalRecord, StreamAssembler)>>,
+    /// The check and each choice's content so far. Boxed to keep the responders small.
+    output_check: Option<Box<(OutputCheck, BTreeMap<usize, String>)>>,
+    shutdown: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+    permit: Option<Permit>,
+    prompt_tokens: Option<PromptTokens>,
+    streamed_tokens: usize,
+}
+
+impl Streamer {
+    pub fn new(rx: Receiver<Response>, state: Arc<dyn Engine>) -> Self {
+        Self {
+            rx,
+            stream_state: StreamState::Running,
+            state,
+            budget: None,
+            metrics: None,
+            journal: None,
+            output_check: None,
+            shutdown: None,
+            permit: None,
+            prompt_tokens: None,
+            streamed_tokens: 0,
+        }
+    }
+
+    pub fn with_budget(mut self, budget: Option<Arc<KeyBudget>>) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Option<Arc<RequestMetrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Journals the response assembled from the streamed chunks when the stream ends.
+    pub fn with_journal(mut self, record: Option<JournalRecord>) -> Self {
+        self.journal = record.map(|record| Box::new((record, StreamAssembler::default())));
+        self
+    }
+
+    pub fn with_output_check(mut self, check: OutputCheck) -> Self {
+        if !check.is_empty() {
+            self.output_check = Some(Box::new((check, BTreeMap::new())));
+        }
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: &Shutdown) -> Self {
+        self.shutdown = Some(Box::pin(shutdown.expired()));
+        self
+    }
+
+    pub fn with_permit(mut self, permit: Permit) -> Self {
+        self.permit = Some(permit);
+        self
+    }
+
+    pub fn with_prompt_tokens(mut self, prompt_tokens: Option<PromptTokens>) -> Self {
+        self.prompt_tokens = prompt_tokens;
+        self
+    }
+
+    fn count_tokens(&mut self, choices: usize) {
+        if self.streamed_tokens == 0 {
+            if let Some(metrics) = &self.metrics {
+                metrics.first_token();
+            }
+        }
+        self.streamed_tokens += choices;
+    }
+
+    /// Errors use the same `{"error": {...}}` body as non-streaming responses.
+    fn error_event(&mut self, error: ApiError) -> Event {
+        self.stream_state = StreamState::SendingDone;
+        if let Some((_, assembler)) = self.journal.as_deref_mut() {
+            assembler.error(&error.body());
+        }
+        Event::default()
+            .json_data(error.body())
+            .expect("Serialization of error failed.")
+    }
+}
+
+impl futures::Stream for Streamer {
+    type Item = Result<Event, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.stream_state {
+            StreamState::Running => {}
+            StreamState::SendingDone => {
+                self.stream_state = StreamState::Done;
+                return Poll::Ready(Some(Ok(Event::default().data("[DONE]"))));
+            }
+            StreamState::Done => return Poll::Ready(None),
+        }
+        if let Some(expired) = &mut self.shutdown {
+            if expired.as_mut().poll(cx).is_ready() {
+                // Stop the generation like a client disconnect would.
+                self.rx.close();
+                let event = self.error_event(Shutdown::error());
+                return Poll::Ready(Some(Ok(event)));
+            }
+        }
+        let resp = match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(resp)) => resp,
+            Poll::Ready(None) => {
+                // The engine dropped the sequence without a final chunk.
+                let e =
+                    ModelErrorMessage("The model stopped without finishing the response.".into());
+                self.state.log_error(&e);
+                let event = self.error_event(ApiError::Internal(e.0));
+                return Poll::Ready(Some(Ok(event)));
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        match resp {
+            Response::ModelError(msg, _) | Response::CompletionModelError(msg, _) => {
+                self.state.log_error(&ModelErrorMessage(msg.to_string()));
+                let event = self.error_event(ApiError::Internal(msg));
+                Poll::Ready(Some(Ok(event)))
+            }
+            Response::ValidationError(e) => {
+                let event = self.error_event(ApiError::unprocessable(e.to_string()));
+                Poll::Ready(Some(Ok(event)))
+            }
+            Response::InternalError(e) => {
+                self.state.log_error(&*e);
+                let event = self.error_event(ApiError::internal(e.to_string()));
+                Poll::Ready(Some(Ok(event)))
+            }
+            Response::Chunk(response) => {
+                self.count_tokens(response.choices.len());
+                if let Some((check, contents)) = self.output_check.as_deref_mut() {
+                    if let Err(e) = check.check_chunk(contents, &response) {
+                        self.state.log_error(&e);
+                        self.budget = None;
+                        let event = self.error_event(e);
+                        return Poll::Ready(Some(Ok(event)));
+                    }
+                }
+                if let Some((_, assembler)) = self.journal.as_deref_mut() {
+                    assembler.chat_chunk(&response);
+                }
+                if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    self.stream_state = StreamState::SendingDone;
+                }
+                self.state
+                    .log_response(LoggedResponse::ChatChunk(&response));
+                Poll::Ready(Some(Event::default().json_data(response)))
+            }
+            Response::CompletionChunk(response) => {
+                self.count_tokens(response.choices.len());
+                if let Some((_, assembler)) = self.journal.as_deref_mut() {
+                    assembler.completion_chunk(&response);
+                }
+                if response.choices.iter().all(|x| x.finish_reason.is_some()) {
+                    self.stream_state = StreamState::SendingDone;
+                }
+                self.state
+                    .log_response(LoggedResponse::CompletionChunk(&response));
+                Poll::Ready(Some(Event::default().json_data(response)))
+            }
+            Response::Done(_) | Response::CompletionDone(_) => {
+                let e = ApiError::internal("Unexpected final response in a stream.");
+                self.state.log_error(&e);
+                let event = self.error_event(e);
+                Poll::Ready(Some(Ok(event)))
+            }
+        }
+    }
+}
+
+impl Drop for Streamer {
+    fn drop(&mut self) {
+        let usage = StreamUsage {
+            budget: self.budget.take(),
+            metrics: self.metrics.take(),
+            journal: self.journal.take(),
+            aborted: self.stream_state == StreamState::Running,
+            completion_tokens: self.streamed_tokens,
+        };
+        match self.prompt_tokens.take() {
+            // The count is normally done long before the stream, but must not be waited for here.
+            Some(PromptTokens(count)) => {
+                tokio::spawn(async move { usage.finish(count.await.unwrap_or(0)) });
+            }
+            None => usage.finish(0),
+        }
+        if self.stream_state == StreamState::Running {
+            // The client disconnected mid-generation. Closing the channel makes the engine's
+            // next send for this sequence fail, which ends the sequence.
+            self.rx.close();
+            self.state.log_error(&ModelErrorMessage(
+                "Client disconnected, aborting the stream.".into(),
+            ));
+        }
+    }
+}
+
+/// Prompt length of a streamed request, counted on a blocking thread while it streams.
+pub struct PromptTokens(JoinHandle<usize>);
+
+impl PromptTokens {
+    /// Tokenizes `request`'s prompt the way the engine will. Chat prompts are counted without
+    /// their tools and images. Failures count as 0.
+    pub fn count(state: &Arc<dyn Engine>, request: &Request) -> Self {
+        let input = match request {
+            Request::Normal(request) => match &request.messages {
+                RequestMessage::Chat(messages) | RequestMessage::VisionChat { messages, .. } => {
+                    Ok(TokenizeInput::Chat {
+                        messages: messages.clone(),
+                        add_generation_prompt: true,
+                    })
+                }
+                RequestMessage::Completion { text, .. } => Ok(TokenizeInput::Text {
+                    text: text.clone(),
+                    add_special_tokens: true,
+                }),
+                RequestMessage::CompletionTokens(tokens) => Err(tokens.len()),
+            },
+            _ => Err(0),
+        };
+        let state = state.clone();
+        Self(tokio::task::spawn_blocking(move || match input {
+            Ok(input) => state
+                .tokenize(input)
+                .map_or(0, |tokenized| tokenized.tokens.len()),
+            Err(tokens) => tokens,
+        }))
+    }
+}
+
+/// What a finished stream is accounted to.
+struct StreamUsage {
+    budget: Option<Arc<KeyBudget>>,
+    metrics: Option<Arc<RequestMetrics>>,
+    journal: Option<Box<(JournalRecord, StreamAssembler)>>,
+    aborted: bool,
+    completion_tokens: usize,
+}
+
+impl StreamUsage {
+    fn finish(self, prompt_tokens: usize) {
+        let total_tokens = prompt_tokens + self.completion_tokens;
+        if let Some(budget) = &self.budget {
+            budget.charge(total_tokens);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.streamed_tokens(prompt_tokens, self.completion_tokens);
+        }
+        if let Some((record, assembler)) = self.journal.map(|journal| *journal) {
+            let mut response = assembler.into_value();
+            if self.aborted {
+                response["aborted"] = true.into();
+            }
+            let usage = serde_json::json!({
+                "prompt_tokens": prompt_tokens,
+                "completion_tokens": self.completion_tokens,
+                "total_tokens": total_tokens,
+            });
+            record.finish(200, response, Some(usage));
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! `/v1/tokenize` and `/v1/detokenize`, with the model's own tokenizer and chat template.
+
+use axum::extract::{Extension, Json, State};
+use either::Either;
+use mistralrs::RequestMessage;
+use std::sync::Arc;
+
+use crate::{
+    chat_completion::parse_messages,
+    engine::TokenizeInput,
+    error::{ApiError, OaiJson},
+    metrics::RequestMetrics,
+    openai::{
+        ContentPart, DetokenizeRequest, DetokenizeResponse, MessageContent, TokenizeRequest,
+        TokenizeResponse,
+    },
+    state::AppState,
+};
+
+pub async fn tokenize(
+    State(app): State<Arc<AppState>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    OaiJson(oairequest): OaiJson<TokenizeRequest>,
+) -> Result<Json<TokenizeResponse>, ApiError> {
+    let (name, state) = app.models.get(&oairequest.model)?;
+    if let Some(Extension(metrics)) = &metrics {
+        metrics.set_model(&name, false);
+    }
+
+    let input = match (oairequest.prompt, oairequest.messages) {
+        (Some(text), None) => TokenizeInput::Text {
+            text,
+            add_special_tokens: oairequest.add_special_tokens,
+        },
+        (None, Some(messages)) => {
+            // Images expand to a number of tokens only known once they are preprocessed.
+            if let Some(i) = messages.iter().position(|message| {
+                matches!(&message.content, MessageContent::Parts(parts)
+                    if parts.iter().any(|part| matches!(part, ContentPart::ImageUrl { .. })))
+            }) {
+                return Err(ApiError::invalid_request(format!(
+                    "Message {i} has an image, only text can be tokenized."
+                ))
+                .with_param(format!("messages[{i}].content")));
+            }
+            let RequestMessage::Chat(messages) =
+                parse_messages(Either::Left(messages), &app.images).await?
+            else {
+                unreachable!("Messages without images make a text chat.");
+            };
+            TokenizeInput::Chat {
+                messages,
+                add_generation_prompt: oairequest.add_generation_prompt,
+            }
+        }
+        _ => {
+            return Err(
+                ApiError::invalid_request("Give exactly one of `prompt` and `messages`.")
+                    .with_param("prompt"),
+            )
+        }
+    };
+
+    let engine = state.clone();
+    let tokenized = tokio::task::spawn_blocking(move || engine.tokenize(input))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?
+        .inspect_err(|e| state.log_error(e))?;
+    Ok(Json(TokenizeResponse {
+        model: name,
+        count: tokenized.tokens.len(),
+        tokens: tokenized.tokens,
+        max_model_len: tokenized.max_model_len,
+        prompt: oairequest.return_prompt.then_some(tokenized.prompt),
+    }))
+}
+
+pub async fn detokenize(
+    State(app): State<Arc<AppState>>,
+    metrics: Option<Extension<Arc<RequestMetrics>>>,
+    OaiJson(oairequest): OaiJson<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>, ApiError> {
+    let (name, state) = app.models.get(&oairequest.model)?;
+    if let Some(Extension(metrics)) = &metrics {
+        metrics.set_model(&name, false);
+    }
+
+    let engine = state.clone();
+    let text = tokio::task::spawn_blocking(move || {
+        engine.detokenize(&oairequest.tokens, oairequest.skip_special_tokens)
+    })
+    .await
+    .map_err(|e| ApiError::internal(e.to_string()))?
+    .inspect_err(|e| state.log_error(e))?;
+    Ok(Json(DetokenizeResponse { model: name, text }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::fake::{app, post, FakeEngine};
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+
+    async fn call(uri: &str, request: Value) -> (StatusCode, Value) {
+        let (status, body) = post(app(FakeEngine::new([])), uri, request).await;
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    fn ids(text: &str) -> Vec<u32> {
+        text.chars().map(u32::from).collect()
+    }
+
+    #[tokio::test]
+    async fn tokenizes_a_prompt() {
+        let (status, body) = call("/v1/tokenize", json!({ "prompt": "hi" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["model"], "fake");
+        assert_eq!(body["tokens"], json!([1, 'h' as u32, 'i' as u32]));
+        assert_eq!(body["count"], 3);
+        assert_eq!(body["max_model_len"], 32);
+        assert!(body.get("prompt").is_none());
+
+        let request = json!({ "prompt": "hi", "add_special_tokens": false });
+        let (_, body) = call("/v1/tokenize", request).await;
+        assert_eq!(body["tokens"], json!(ids("hi")));
+    }
+
+    #[tokio::test]
+    async fn renders_messages_through_the_template() {
+        let request = json!({
+            "messages": [
+                { "role": "system", "content": "be brief" },
+                { "role": "user", "content": [{ "type": "text", "text": "a" }, { "type": "text", "text": "b" }] },
+            ],
+            "return_prompt": true,
+        });
+        let (status, body) = call("/v1/tokenize", request).await;
+        assert_eq!(status, StatusCode::OK);
+        // Text parts are joined as in a chat request.
+        let prompt = "<|system|>be brief\n<|user|>a\nb\n<|assistant|>";
+        assert_eq!(body["prompt"], prompt);
+        assert_eq!(body["tokens"], json!(ids(prompt)));
+        assert_eq!(body["count"], prompt.chars().count());
+
+        let request = json!({
+            "messages": [{ "role": "user", "content": "a" }],
+            "add_generation_prompt": false,
+            "return_prompt": true,
+        });
+        let (_, body) = call("/v1/tokenize", request).await;
+        assert_eq!(body["prompt"], "<|user|>a\n");
+    }
+
+    #[tokio::test]
+    async fn needs_exactly_one_input() {
+        for request in [
+            json!({}),
+            json!({ "prompt": "a", "messages": [{ "role": "user", "content": "a" }] }),
+        ] {
+            let (status, body) = call("/v1/tokenize", request.clone()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{request}");
+            assert_eq!(body["error"]["param"], "prompt");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_images() {
+        let request = json!({
+            "messages": [{ "role": "user", "content": [
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+            ] }],
+        });
+        let (status, body) = call("/v1/tokenize", request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["param"], "messages[0].content");
+    }
+
+    #[tokio::test]
+    async fn detokenizes() {
+        let mut tokens = vec![1];
+        tokens.extend(ids("hi"));
+        let (status, body) = call("/v1/detokenize", json!({ "tokens": tokens })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "model": "fake", "text": "hi" }));
+
+        let request = json!({ "tokens": tokens, "skip_special_tokens": false });
+        let (_, body) = call("/v1/detokenize", request).await;
+        assert_eq!(body["text"], "<s>hi");
+
+        let (status, body) = call("/v1/detokenize", json!({ "tokens": [0xD800] })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["param"], "tokens");
+    }
+}
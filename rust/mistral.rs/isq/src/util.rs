@@ -0,0 +1,660 @@
+//! Fetching and decoding the images chat requests refer to, under the server's [`ImagePolicy`].
+
+use anyhow::Context as _;
+use image::{DynamicImage, ImageReader};
+use indexmap::IndexMap;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    redirect,
+};
+use std::{
+    hash::{BuildHasher, RandomState},
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
+};
+
+use crate::config::ImagePolicy;
+
+const MAX_REDIRECTS: usize = 5;
+
+pub struct ImageFetcher {
+    policy: ImagePolicy,
+    /// `policy.local_root`, canonicalized once at startup.
+    local_root: Option<PathBuf>,
+    client: reqwest::Client,
+    cache: Mutex<ImageCache>,
+    /// Hashes data URLs for their cache key, seeded per process.
+    hasher: RandomState,
+}
+
+impl ImageFetcher {
+    pub fn new(policy: ImagePolicy) -> anyhow::Result<Self> {
+        let local_root =
+            match &policy.local_root {
+                Some(root) => Some(std::fs::canonicalize(root).with_context(|| {
+                    format!("Image root `{}` is not accessible.", root.display())
+                })?),
+                None => None,
+            };
+        let allowed_hosts = policy.allowed_hosts.clone();
+        let client = reqwest::Client::builder()
+            .timeout(policy.timeout)
+            // A redirect must not lead outside the allow-list.
+            .redirect(redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if let Err(e) = check_host(&allowed_hosts, attempt.url()) {
+                    attempt.error(format!("redirect refused: {e}"))
+                } else {
+                    attempt.follow()
+                }
+            }))
+            // Names are checked again once resolved, so DNS cannot point them inside.
+            .dns_resolver(Arc::new(PublicResolver {
+                allowed_hosts: policy.allowed_hosts.clone(),
+            }))
+            .build()?;
+        Ok(Self {
+            cache: Mutex::new(ImageCache::new(policy.cache_size)),
+            policy,
+            local_root,
+            client,
+            hasher: RandomState::new(),
+        })
+    }
+
+    /// Loads an image from an http(s) URL, a data URL, bare base64 or a local path, whichever
+    /// the policy allows.
+    pub async fn parse_image_url(&self, url_unparsed: &str) -> anyhow::Result<DynamicImage> {
+        let url = if let Ok(url) = url::Url::parse(url_unparsed) {
+            url
+        } else if let Some(path) = self.local_path(url_unparsed).await {
+            url::Url::from_file_path(path)
+                .map_err(|_| anyhow::anyhow!("Could not parse file path: {}", url_unparsed))?
+        } else {
+            url::Url::parse(&format!("data:image/png;base64,{}", url_unparsed))
+                .map_err(|_| anyhow::anyhow!("Could not parse as base64 data: {}", url_unparsed))?
+        };
+
+        // Local files may change on disk, so only remote and inline images are cached.
+        let key = match url.scheme() {
+            "http" | "https" => Some(url.to_string()),
+            "data" => Some(format!("data:{:016x}", self.hasher.hash_one(url.as_str()))),
+            _ => None,
+        };
+        if let Some(image) = key
+            .as_ref()
+            .and_then(|key| self.cache.lock().unwrap().get(key))
+        {
+            return Ok(image);
+        }
+
+        let bytes = match url.scheme() {
+            scheme @ ("http" | "https") => {
+                self.check_scheme(scheme)?;
+                check_host(&self.policy.allowed_hosts, &url).map_err(anyhow::Error::msg)?;
+                self.fetch(&url).await?
+            }
+            "file" => {
+                let path = url
+                    .to_file_path()
+                    .map_err(|_| anyhow::anyhow!("Could not parse file path: {}", url))?;
+                self.read_file(&path).await?
+            }
+            "data" => {
+                self.check_scheme("data")?;
+                let data_url = data_url::DataUrl::process(url.as_str())?;
+                let bytes = data_url.decode_to_vec()?.0;
+                self.check_len(bytes.len())?;
+                bytes
+            }
+            other => anyhow::bail!("Unsupported URL scheme: {}", other),
+        };
+
+        let image = self.decode(&bytes)?;
+        if let Some(key) = key {
+            self.cache.lock().unwrap().insert(key, image.clone());
+        }
+        Ok(image)
+    }
+
+    /// Relative paths are relative to the image root, absolute ones must be inside it. Paths
+    /// that are missing and paths outside the root both give `None`, so neither can be told
+    /// apart from the other.
+    async fn local_path(&self, path: &str) -> Option<PathBuf> {
+        let root = self.local_root.as_ref()?;
+        let path = fs::canonicalize(root.join(path)).await.ok()?;
+        path.starts_with(root).then_some(path)
+    }
+
+    fn check_scheme(&self, scheme: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.policy.schemes.iter().any(|s| s == scheme),
+            "Image URL scheme `{scheme}` is not allowed."
+        );
+        Ok(())
+    }
+
+    fn check_len(&self, len: usize) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            len <= self.policy.max_bytes,
+            "Image is larger than the {} byte limit.",
+            self.policy.max_bytes
+        );
+        Ok(())
+    }
+
+    async fn fetch(&self, url: &url::Url) -> anyhow::Result<Vec<u8>> {
+        let timed_out = |e: reqwest::Error| {
+            if e.is_timeout() {
+                anyhow::anyhow!("Timed out after {:?} fetching {url}.", self.policy.timeout)
+            } else {
+                e.into()
+            }
+        };
+        let mut resp = self
+            .client
+            .get(url.clone())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(timed_out)?;
+        if let Some(len) = resp.content_length() {
+            self.check_len(usize::try_from(len).unwrap_or(usize::MAX))?;
+        }
+        // The declared length may be missing or wrong, so count while reading.
+        let mut bytes = Vec::new();
+        while let Some(chunk) = resp.chunk().await.map_err(timed_out)? {
+            self.check_len(bytes.len() + chunk.len())?;
+            bytes.extend_from_slice(&chunk);
+        }
+        Ok(bytes)
+    }
+
+    async fn read_file(&self, path: &Path) -> anyhow::Result<Vec<u8>> {
+        let Some(root) = &self.local_root else {
+            anyhow::bail!("Local image files are disabled.");
+        };
+        // Missing files and files outside the root get the same error, so clients cannot probe
+        // which paths exist.
+        let unavailable = || anyhow::anyhow!("Image file `{}` is not available.", path.display());
+        // Canonicalize to resolve `..` and symlinks before checking containment.
+        let path = fs::canonicalize(path).await.map_err(|_| unavailable())?;
+        anyhow::ensure!(path.starts_with(root), unavailable());
+        let mut f = File::open(&path).await.map_err(|_| unavailable())?;
+        let len = f.metadata().await?.len();
+        self.check_len(usize::try_from(len).unwrap_or(usize::MAX))?;
+        let mut buffer = Vec::new();
+        f.read_to_end(&mut buffer).await?;
+        Ok(buffer)
+    }
+
+    /// Checks the dimensions from the image header before decoding the pixels.
+    fn decode(&self, bytes: &[u8]) -> anyhow::Result<DynamicImage> {
+        let (width, height) = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        let (max_width, max_height) = (self.policy.max_width, self.policy.max_height);
+        anyhow::ensure!(
+            width <= max_width && height <= max_height,
+            "Image is {width}x{height} pixels, the limit is {max_width}x{max_height}."
+        );
+        Ok(image::load_from_memory(bytes)?)
+    }
+}
+
+/// Checks `url`'s host against the allow-list. An empty list allows any host with a public
+/// address; loopback, private and link-local ones have to be listed explicitly.
+fn check_host(allowed_hosts: &[String], url: &url::Url) -> Result<(), String> {
+    let host = url.host_str().unwrap_or_default();
+    if host_listed(allowed_hosts, host) {
+        return Ok(());
+    }
+    if !allowed_hosts.is_empty() {
+        return Err(format!("Image host `{host}` is not allowed."));
+    }
+    let ip = match url.host() {
+        Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        Some(url::Host::Domain(_)) => None,
+        None => return Err("Image URL has no host.".to_string()),
+    };
+    match ip {
+        Some(ip) if !is_public(ip) => Err(format!(
+            "Image host `{host}` is a private address and is not allowed."
+        )),
+        // Names are checked by `PublicResolver` once they are resolved.
+        _ => Ok(()),
+    }
+}
+
+/// Matches `host` against the allow-list, `*.example.com` also matching subdomains.
+fn host_listed(allowed_hosts: &[String], host: &str) -> bool {
+    let host = host.to_lowercase();
+    allowed_hosts
+        .iter()
+        .any(|allowed| match allowed.strip_prefix("*.") {
+            Some(domain) => host == domain || host.ends_with(&format!(".{domain}")),
+            None => host == *allowed,
+        })
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to loopback, private,
+/// link-local (cloud metadata services live at 169.254.169.254), shared or reserved ranges.
+fn is_public(ip: IpAddr) -> bool {
+    fn public_v4(ip: Ipv4Addr) -> bool {
+        let [a, b, ..] = ip.octets();
+        !(ip.is_loopback()
+            || ip.is_private()
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_documentation()
+            || a == 0
+            // 100.64.0.0/10, carrier-grade NAT.
+            || (a == 100 && (b & 0xc0) == 64))
+    }
+    fn public_v6(ip: Ipv6Addr) -> bool {
+        if let Some(v4) = ip.to_ipv4_mapped() {
+            return public_v4(v4);
+        }
+        let first = ip.segments()[0];
+        !(ip.is_loopback()
+            || ip.is_unspecified()
+            || ip.is_multicast()
+            // fc00::/7, unique local.
+            || (first & 0xfe00) == 0xfc00
+            // fe80::/10, link-local.
+            || (first & 0xffc0) == 0xfe80)
+    }
+    match ip {
+        IpAddr::V4(ip) => public_v4(ip),
+        IpAddr::V6(ip) => public_v6(ip),
+    }
+}
+
+/// Resolves names with the system resolver, dropping non-public addresses unless the name is
+/// on the allow-list.
+struct PublicResolver {
+    allowed_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let listed = host_listed(&self.allowed_hosts, name.as_str());
+        Box::pin(async move {
+            let host = name.as_str();
+            let addrs: Vec<_> = tokio::net::lookup_host((host, 0))
+                .await?
+                .filter(|addr| listed || is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!(
+                    "Image host `{host}` has no public address and is not allowed."
+                )
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Least recently used decoded images, most recent last.
+struct ImageCache {
+    capacity: usize,
+    entries: IndexMap<String, DynamicImage>,
+}
+
+impl ImageCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: IndexMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<DynamicImage> {
+        let index = self.entries.get_index_of(key)?;
+        let last = self.entries.len() - 1;
+        self.entries.move_index(index, last);
+        self.entries.get_index(last).map(|(_, image)| image.clone())
+    }
+
+    fn insert(&mut self, key: String, image: DynamicImage) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.entries.shift_remove(&key);
+        self.entries.insert(key, image);
+        while self.entries.len() > self.capacity {
+            self.entries.shift_remove_index(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::fake::TempDir;
+    use axum::{extract::State, response::Redirect, routing::get, Router};
+    use image::{GenericImageView, ImageFormat};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    fn policy() -> ImagePolicy {
+        ImagePolicy {
+            schemes: vec!["http".into(), "https".into(), "data".into()],
+            allowed_hosts: vec!["127.0.0.1".into()],
+            local_root: None,
+            max_bytes: 1024 * 1024,
+            max_width: 16,
+            max_height: 16,
+            timeout: Duration::from_millis(500),
+            cache_size: 4,
+        }
+    }
+
+    /// Local stand-in for an image host. Returns its base URL and a counter of requests to
+    /// `/small.png`.
+    async fn serve() -> (String, Arc<AtomicUsize>) {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new()
+            .route(
+                "/small.png",
+                get(|State(hits): State<Arc<AtomicUsize>>| async move {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    png(8, 8)
+                }),
+            )
+            .route("/wide.png", get(|| async { png(32, 8) }))
+            .route("/huge.png", get(|| async { vec![0u8; 2 * 1024 * 1024] }))
+            .route(
+                "/slow.png",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    png(8, 8)
+                }),
+            )
+            .route(
+                "/elsewhere",
+                get(move || async move {
+                    Redirect::temporary(&format!("http://localhost:{port}/small.png"))
+                }),
+            )
+            .with_state(hits.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (format!("http://127.0.0.1:{port}"), hits)
+    }
+
+    fn data_url(bytes: &[u8]) -> String {
+        let encoded: String = bytes.iter().map(|b| format!("%{b:02X}")).collect();
+        format!("data:image/png,{encoded}")
+    }
+
+    #[tokio::test]
+    async fn fetches_http_images_once_then_serves_from_cache() {
+        let (base, hits) = serve().await;
+        let fetcher = ImageFetcher::new(policy()).unwrap();
+        let url = format!("{base}/small.png");
+        for _ in 0..2 {
+            let image = fetcher.parse_image_url(&url).await.unwrap();
+            assert_eq!(image.dimensions(), (8, 8));
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_evicts_least_recently_used() {
+        let (base, hits) = serve().await;
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            cache_size: 1,
+            ..policy()
+        })
+        .unwrap();
+        let small = format!("{base}/small.png");
+        fetcher.parse_image_url(&small).await.unwrap();
+        fetcher
+            .parse_image_url(&data_url(&png(2, 2)))
+            .await
+            .unwrap();
+        fetcher.parse_image_url(&small).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_hosts_outside_the_allow_list() {
+        let (base, hits) = serve().await;
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            allowed_hosts: vec!["*.example.com".into()],
+            ..policy()
+        })
+        .unwrap();
+        let e = fetcher
+            .parse_image_url(&format!("{base}/small.png"))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("is not allowed"), "{e}");
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_redirects_outside_the_allow_list() {
+        let (base, hits) = serve().await;
+        let fetcher = ImageFetcher::new(policy()).unwrap();
+        let e = fetcher
+            .parse_image_url(&format!("{base}/elsewhere"))
+            .await
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("not allowed"), "{e:#}");
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_schemes_not_allowed() {
+        let (base, _) = serve().await;
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            schemes: vec!["https".into()],
+            ..policy()
+        })
+        .unwrap();
+        let e = fetcher
+            .parse_image_url(&format!("{base}/small.png"))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("scheme `http`"), "{e}");
+        let e = fetcher
+            .parse_image_url(&data_url(&png(2, 2)))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("scheme `data`"), "{e}");
+        let e = fetcher
+            .parse_image_url("ftp://127.0.0.1/a.png")
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("Unsupported URL scheme"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn enforces_max_bytes() {
+        let (base, _) = serve().await;
+        let fetcher = ImageFetcher::new(policy()).unwrap();
+        let e = fetcher
+            .parse_image_url(&format!("{base}/huge.png"))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("byte limit"), "{e}");
+
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            max_bytes: 16,
+            ..policy()
+        })
+        .unwrap();
+        let e = fetcher
+            .parse_image_url(&data_url(&png(2, 2)))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("byte limit"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn enforces_max_dimensions() {
+        let (base, _) = serve().await;
+        let fetcher = ImageFetcher::new(policy()).unwrap();
+        let e = fetcher
+            .parse_image_url(&format!("{base}/wide.png"))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("32x8 pixels"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_hosts() {
+        let (base, _) = serve().await;
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            timeout: Duration::from_millis(100),
+            ..policy()
+        })
+        .unwrap();
+        let e = fetcher
+            .parse_image_url(&format!("{base}/slow.png"))
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("Timed out"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn local_files_are_disabled_without_a_root() {
+        let dir = TempDir::new("no-root");
+        let path = dir.0.join("a.png");
+        std::fs::write(&path, png(2, 2)).unwrap();
+        let fetcher = ImageFetcher::new(policy()).unwrap();
+        let url = url::Url::from_file_path(&path).unwrap();
+        let e = fetcher.parse_image_url(url.as_str()).await.unwrap_err();
+        assert!(e.to_string().contains("disabled"), "{e}");
+        // A bare path is not a URL and is not read either.
+        assert!(fetcher
+            .parse_image_url(path.to_str().unwrap())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn local_files_must_stay_inside_the_root() {
+        let dir = TempDir::new("root");
+        let root = dir.0.join("images");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.png"), png(2, 2)).unwrap();
+        std::fs::write(dir.0.join("secret.png"), png(2, 2)).unwrap();
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            local_root: Some(root.clone()),
+            ..policy()
+        })
+        .unwrap();
+
+        let image = fetcher.parse_image_url("a.png").await.unwrap();
+        assert_eq!(image.dimensions(), (2, 2));
+        // A file outside the root fails exactly like a file that does not exist.
+        let outside = fetcher.parse_image_url("../secret.png").await.unwrap_err();
+        let missing = fetcher.parse_image_url("../missing.png").await.unwrap_err();
+        assert_eq!(
+            outside.to_string(),
+            missing.to_string().replace("missing", "secret")
+        );
+        let url = |name: &str| url::Url::from_file_path(dir.0.join(name)).unwrap();
+        let outside = fetcher
+            .parse_image_url(url("secret.png").as_str())
+            .await
+            .unwrap_err();
+        let missing = fetcher
+            .parse_image_url(url("missing.png").as_str())
+            .await
+            .unwrap_err();
+        assert!(
+            outside.to_string().contains("is not available"),
+            "{outside}"
+        );
+        assert_eq!(
+            outside.to_string(),
+            missing.to_string().replace("missing", "secret")
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_allow_list_refuses_private_addresses() {
+        let (base, hits) = serve().await;
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            allowed_hosts: Vec::new(),
+            ..policy()
+        })
+        .unwrap();
+        let port = base.rsplit(':').next().unwrap();
+        for url in [
+            format!("{base}/small.png"),
+            format!("http://[::1]:{port}/small.png"),
+            format!("http://[::ffff:127.0.0.1]:{port}/small.png"),
+            "http://169.254.169.254/latest/meta-data/".to_string(),
+            "http://10.0.0.1/a.png".to_string(),
+        ] {
+            let e = fetcher.parse_image_url(&url).await.unwrap_err();
+            assert!(e.to_string().contains("private address"), "{url}: {e}");
+        }
+        // A name resolving to loopback is refused once resolved.
+        let e = fetcher
+            .parse_image_url(&format!("http://localhost:{port}/small.png"))
+            .await
+            .unwrap_err();
+        assert!(format!("{e:#}").contains("no public address"), "{e:#}");
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:2800:220:1::".parse().unwrap()));
+        for ip in [
+            "192.168.1.1",
+            "172.16.0.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn listed_hosts_may_be_private() {
+        let (base, _) = serve().await;
+        let port = base.rsplit(':').next().unwrap();
+        let fetcher = ImageFetcher::new(ImagePolicy {
+            allowed_hosts: vec!["localhost".into()],
+            ..policy()
+        })
+        .unwrap();
+        let image = fetcher
+            .parse_image_url(&format!("http://localhost:{port}/small.png"))
+            .await
+            .unwrap();
+        assert_eq!(image.dimensions(), (8, 8));
+    }
+}